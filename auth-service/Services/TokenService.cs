@@ -27,6 +27,8 @@ public class TokenService
                 new Claim(ClaimTypes.SerialNumber, user.Id.ToString())
             ]),
             Expires = DateTime.UtcNow.AddHours(1),
+            // core-app rejects tokens from other issuers, both read the same JWT_ISSUER
+            Issuer = Environment.GetEnvironmentVariable("JWT_ISSUER"),
             SigningCredentials = new SigningCredentials(new SymmetricSecurityKey(key), SecurityAlgorithms.HmacSha256Signature),
         };
 
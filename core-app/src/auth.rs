@@ -0,0 +1,339 @@
+use std::{
+    error::Error,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+// auth-service puts the user id into ClaimTypes.SerialNumber, which keeps its full name in the token
+const USER_ID_CLAIMS: [&str; 2] = [
+    "http://schemas.microsoft.com/ws/2008/06/identity/claims/serialnumber",
+    "sub",
+];
+const USER_NAME_CLAIM: &str = "unique_name";
+// tolerated clock difference between auth-service and this server
+const LEEWAY_SECONDS: u64 = 30;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    MissingToken,
+    MalformedToken,
+    UnsupportedAlgorithm(String),
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    WrongIssuer(Option<String>),
+    MissingUserId,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "no token provided"),
+            AuthError::MalformedToken => write!(f, "token is not a valid JWT"),
+            AuthError::UnsupportedAlgorithm(alg) => {
+                write!(f, "token is signed with {}, only HS256 is accepted", alg)
+            }
+            AuthError::InvalidSignature => write!(f, "token signature doesn't match"),
+            AuthError::Expired => write!(f, "token has expired"),
+            AuthError::NotYetValid => write!(f, "token is not valid yet"),
+            AuthError::WrongIssuer(issuer) => write!(f, "token issued by {:?}", issuer),
+            AuthError::MissingUserId => write!(f, "token has no user id claim"),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+#[derive(Debug, Clone)]
+pub struct Claims {
+    pub user_id: i32,
+    pub user_name: Option<String>,
+    pub expires_at: u64,
+}
+
+// Verifies HS256 tokens issued by auth-service with the shared JWT_SECRET
+pub struct JwtVerifier {
+    secret: Vec<u8>,
+    // tokens without this issuer are rejected
+    issuer: String,
+}
+
+impl JwtVerifier {
+    pub fn new(secret: &str, issuer: &str) -> Self {
+        JwtVerifier {
+            secret: secret.as_bytes().to_vec(),
+            issuer: issuer.to_string(),
+        }
+    }
+
+    // both are required, the error is the name of the missing variable
+    pub fn from_env() -> Result<Self, &'static str> {
+        let read = |name: &'static str| std::env::var(name).ok().filter(|s| !s.is_empty());
+        let secret = read("JWT_SECRET").ok_or("JWT_SECRET")?;
+        let issuer = read("JWT_ISSUER").ok_or("JWT_ISSUER")?;
+        Ok(Self::new(&secret, &issuer))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let mut parts = token.split('.');
+        let (encoded_header, encoded_claims, signature) =
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(h), Some(c), Some(s)) if parts.next().is_none() => (h, c, s),
+                _ => return Err(AuthError::MalformedToken),
+            };
+
+        let header = decode_json(encoded_header)?;
+        let alg = header
+            .get("alg")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if alg != "HS256" {
+            return Err(AuthError::UnsupportedAlgorithm(alg.to_string()));
+        }
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| AuthError::MalformedToken)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .map_err(|_| AuthError::InvalidSignature)?;
+        mac.update(encoded_header.as_bytes());
+        mac.update(b".");
+        mac.update(encoded_claims.as_bytes());
+        // constant time comparison
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidSignature)?;
+
+        let claims = decode_json(encoded_claims)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let expires_at = claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .ok_or(AuthError::Expired)?;
+        if expires_at + LEEWAY_SECONDS < now {
+            return Err(AuthError::Expired);
+        }
+        if let Some(not_before) = claims.get("nbf").and_then(Value::as_u64) {
+            if not_before > now + LEEWAY_SECONDS {
+                return Err(AuthError::NotYetValid);
+            }
+        }
+
+        let issuer = claims.get("iss").and_then(Value::as_str);
+        if issuer != Some(self.issuer.as_str()) {
+            return Err(AuthError::WrongIssuer(issuer.map(String::from)));
+        }
+
+        let user_id = USER_ID_CLAIMS
+            .iter()
+            .find_map(|name| match claims.get(*name)? {
+                Value::String(s) => s.parse::<i32>().ok(),
+                Value::Number(n) => n.as_i64().and_then(|n| i32::try_from(n).ok()),
+                _ => None,
+            })
+            .ok_or(AuthError::MissingUserId)?;
+
+        Ok(Claims {
+            user_id,
+            user_name: claims
+                .get(USER_NAME_CLAIM)
+                .and_then(Value::as_str)
+                .map(String::from),
+            expires_at,
+        })
+    }
+}
+
+fn decode_json(part: &str) -> Result<Value, AuthError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AuthError::MalformedToken)?;
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(value) if value.is_object() => Ok(value),
+        _ => Err(AuthError::MalformedToken),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET: &str = "secret of both services";
+    const ISSUER: &str = "auth-service";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    fn sign(header: &Value, claims: &Value, secret: &str) -> String {
+        let signed = format!("{}.{}", encode(header), encode(claims));
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", signed, signature)
+    }
+
+    fn header() -> Value {
+        json!({ "alg": "HS256", "typ": "JWT" })
+    }
+
+    fn claims() -> Value {
+        json!({
+            USER_ID_CLAIMS[0]: "42",
+            "unique_name": "rudolf",
+            "iss": ISSUER,
+            "exp": now() + 3600,
+        })
+    }
+
+    fn verify(token: &str) -> Result<Claims, AuthError> {
+        JwtVerifier::new(SECRET, ISSUER).verify(token)
+    }
+
+    #[test]
+    fn token_of_auth_service_is_accepted() {
+        let claims = verify(&sign(&header(), &claims(), SECRET)).unwrap();
+        assert_eq!(claims.user_id, 42);
+        assert_eq!(claims.user_name.as_deref(), Some("rudolf"));
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let with = |name: &str, value: Value| {
+            let mut claims = claims();
+            claims[name] = value;
+            claims
+        };
+        let without = |name: &str| {
+            let mut claims = claims();
+            claims.as_object_mut().unwrap().remove(name);
+            claims
+        };
+        let valid = sign(&header(), &claims(), SECRET);
+        let (signed, _) = valid.rsplit_once('.').unwrap();
+
+        let cases: Vec<(&str, String, AuthError)> = vec![
+            (
+                "other secret",
+                sign(&header(), &claims(), "other secret"),
+                AuthError::InvalidSignature,
+            ),
+            (
+                "claims changed after signing",
+                format!(
+                    "{}.{}.{}",
+                    encode(&header()),
+                    encode(&with(USER_ID_CLAIMS[0], json!("1"))),
+                    valid.rsplit('.').next().unwrap()
+                ),
+                AuthError::InvalidSignature,
+            ),
+            (
+                "expired",
+                sign(
+                    &header(),
+                    &with("exp", json!(now() - LEEWAY_SECONDS - 60)),
+                    SECRET,
+                ),
+                AuthError::Expired,
+            ),
+            (
+                "no expiry",
+                sign(&header(), &without("exp"), SECRET),
+                AuthError::Expired,
+            ),
+            (
+                "not valid yet",
+                sign(
+                    &header(),
+                    &with("nbf", json!(now() + LEEWAY_SECONDS + 60)),
+                    SECRET,
+                ),
+                AuthError::NotYetValid,
+            ),
+            (
+                "wrong issuer",
+                sign(&header(), &with("iss", json!("someone else")), SECRET),
+                AuthError::WrongIssuer(Some(String::from("someone else"))),
+            ),
+            (
+                "no issuer",
+                sign(&header(), &without("iss"), SECRET),
+                AuthError::WrongIssuer(None),
+            ),
+            (
+                "alg none without signature",
+                format!(
+                    "{}.{}.",
+                    encode(&json!({ "alg": "none" })),
+                    encode(&claims())
+                ),
+                AuthError::UnsupportedAlgorithm(String::from("none")),
+            ),
+            (
+                "alg none signed anyway",
+                sign(&json!({ "alg": "none" }), &claims(), SECRET),
+                AuthError::UnsupportedAlgorithm(String::from("none")),
+            ),
+            (
+                "HS512",
+                sign(&json!({ "alg": "HS512" }), &claims(), SECRET),
+                AuthError::UnsupportedAlgorithm(String::from("HS512")),
+            ),
+            (
+                "no user id",
+                sign(&header(), &without(USER_ID_CLAIMS[0]), SECRET),
+                AuthError::MissingUserId,
+            ),
+            ("empty", String::new(), AuthError::MalformedToken),
+            ("two parts", String::from(signed), AuthError::MalformedToken),
+            (
+                "four parts",
+                format!("{}.extra", valid),
+                AuthError::MalformedToken,
+            ),
+            (
+                "header is not base64",
+                format!("%%%.{}", valid.split_once('.').unwrap().1),
+                AuthError::MalformedToken,
+            ),
+            (
+                "header is not an object",
+                format!(
+                    "{}.{}",
+                    URL_SAFE_NO_PAD.encode("[]"),
+                    valid.split_once('.').unwrap().1
+                ),
+                AuthError::MalformedToken,
+            ),
+            (
+                "signature is not base64",
+                format!("{}.%%%", signed),
+                AuthError::MalformedToken,
+            ),
+        ];
+
+        for (name, token, expected) in cases {
+            match verify(&token) {
+                Ok(claims) => panic!("{}: accepted for user {}", name, claims.user_id),
+                Err(e) => assert_eq!(e, expected, "{}", name),
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::{env, process};
+
+use fun_poker::simulation::{run_simulation, SimulationConfig, Strategy};
+
+const USAGE: &str = "Usage: simulate [--players N] [--hands N] [--seed N] [--blind N] [--bank N] [--strategy random|passive|aggressive]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut players_amount = 6;
+    let mut strategy = Strategy::Random;
+    let mut hands = 1000;
+    let mut seed = 0;
+    let mut blind_size = 10;
+    let mut starting_bank = 1000;
+
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        let value = match iter.next() {
+            Some(v) => v,
+            None => exit_with_usage(),
+        };
+
+        let parsed = match flag.as_str() {
+            "--players" => value.parse().map(|v| players_amount = v).is_ok(),
+            "--hands" => value.parse().map(|v| hands = v).is_ok(),
+            "--seed" => value.parse().map(|v| seed = v).is_ok(),
+            "--blind" => value.parse().map(|v| blind_size = v).is_ok(),
+            "--bank" => value.parse().map(|v| starting_bank = v).is_ok(),
+            "--strategy" => Strategy::parse(value).map(|v| strategy = v).is_some(),
+            _ => false,
+        };
+
+        if !parsed {
+            exit_with_usage();
+        }
+    }
+
+    if players_amount < 2 {
+        eprintln!("At least 2 players are required");
+        process::exit(1);
+    }
+
+    let mut config = SimulationConfig::new(players_amount, strategy);
+    config.hands = hands;
+    config.seed = seed;
+    config.blind_size = blind_size;
+    config.starting_bank = starting_bank;
+
+    let report = run_simulation(config);
+    println!("{}", report);
+
+    if !report.is_ok() {
+        process::exit(2);
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
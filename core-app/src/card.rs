@@ -1,5 +1,5 @@
-use rand::seq::SliceRandom;
-use std::collections::VecDeque;
+use rand::{seq::SliceRandom, Rng};
+use std::{collections::VecDeque, fmt};
 
 use crate::protos::card::{Card, CardSuit, CardValue};
 
@@ -38,11 +38,14 @@ impl CardSuit {
     }
 }
 
-impl Card {
-    pub fn to_string(&self) -> String {
-        let mut result = CardValue::from_int_to_str(self.value).to_owned();
-        result.push_str(CardSuit::from_int_to_str(self.suit));
-        result
+impl fmt::Display for Card {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            CardValue::from_int_to_str(self.value),
+            CardSuit::from_int_to_str(self.suit)
+        )
     }
 }
 
@@ -131,6 +134,12 @@ impl Iterator for CardSuit {
         }
     }
 }
+impl Default for CardDeck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CardDeck {
     pub fn new() -> Self {
         CardDeck {
@@ -139,15 +148,25 @@ impl CardDeck {
     }
 
     pub fn new_random() -> CardDeck {
+        CardDeck::new_shuffled(&mut rand::thread_rng())
+    }
+
+    pub fn new_shuffled<R: Rng>(rng: &mut R) -> CardDeck {
+        let mut new_deck = CardDeck::new_ordered().cards;
+
+        new_deck.make_contiguous().shuffle(rng);
+
+        CardDeck { cards: new_deck }
+    }
+
+    // all 52 cards, clubs first; CardSuit/CardValue iterators stop before their last variant
+    pub fn new_ordered() -> CardDeck {
         let mut new_deck = VecDeque::new();
-        for suit in CardSuit::Clubs {
-            for value in CardValue::Two {
-                new_deck.push_back(Card::new(suit, value));
+        for suit in 0..4 {
+            for value in 0..13 {
+                new_deck.push_back(Card { suit, value });
             }
         }
-        
-        let mut rng = rand::thread_rng();
-        new_deck.make_contiguous().shuffle(&mut rng);
 
         CardDeck { cards: new_deck }
     }
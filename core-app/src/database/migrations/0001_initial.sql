@@ -0,0 +1,53 @@
+-- Tables the server reads and writes. Idempotent, so databases created by the old
+-- PostgresDatabase::init are adopted; what differs from here is reported as drift.
+DO $$ BEGIN
+IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'priveleges_enum') THEN
+    CREATE TYPE priveleges_enum AS ENUM ('admin', 'user', 'staruser', 'moderator');
+END IF;
+-- labels are the names of the GameName and GameType protobuf enums
+IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'game_name_enum') THEN
+    CREATE TYPE game_name_enum AS ENUM ('Holdem');
+END IF;
+IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'game_type_enum') THEN
+    CREATE TYPE game_type_enum AS ENUM ('Tournament', 'Cash');
+END IF;
+END $$;
+
+CREATE OR REPLACE FUNCTION is_valid_email (email_address TEXT) RETURNS BOOLEAN AS $$
+BEGIN
+    RETURN email_address ~ '^[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}$';
+END;
+$$ LANGUAGE plpgsql;
+
+-- credentials live in auth-service, tokens carry the id of this table
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(32) NOT NULL,
+    country VARCHAR(32) NOT NULL,
+    email VARCHAR(100) CHECK (is_valid_email (email)),
+    balance NUMERIC(12, 2) NOT NULL DEFAULT 0 CHECK (balance >= 0),
+    type priveleges_enum NOT NULL DEFAULT 'user'
+);
+
+CREATE TABLE IF NOT EXISTS lobbies (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    author_id INTEGER NOT NULL REFERENCES users (id),
+    players_registered INTEGER NOT NULL,
+    game_name game_name_enum NOT NULL,
+    game_type game_type_enum NOT NULL
+);
+
+-- a player comes back to the same lobby on every reconnect
+CREATE TABLE IF NOT EXISTS players_lobbies (
+    player_lobby_id SERIAL PRIMARY KEY,
+    player_id INTEGER NOT NULL REFERENCES users (id),
+    lobby_id INTEGER NOT NULL REFERENCES lobbies (id),
+    UNIQUE (player_id, lobby_id)
+);
+
+CREATE TABLE IF NOT EXISTS game_snapshots (
+    lobby_id INTEGER PRIMARY KEY REFERENCES lobbies (id),
+    payload BYTEA NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,15 @@
+-- payload is an encoded HandHistory, hand_players finds the hands of a user
+CREATE TABLE IF NOT EXISTS hand_histories (
+    id SERIAL PRIMARY KEY,
+    lobby_id INTEGER NOT NULL REFERENCES lobbies (id),
+    payload BYTEA NOT NULL,
+    finished_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS hand_players (
+    hand_id INTEGER NOT NULL REFERENCES hand_histories (id),
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    PRIMARY KEY (hand_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS hand_players_user_id ON hand_players (user_id);
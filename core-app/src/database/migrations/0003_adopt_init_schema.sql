@@ -0,0 +1,29 @@
+-- Databases created by the old PostgresDatabase::init were adopted by 0001 as they were:
+-- users has no type, which is_admin reads, and players_lobbies has no unique key,
+-- which ON CONFLICT of add_user_to_lobby needs. Both are a no-op on databases created by 0001.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS balance NUMERIC(12, 2) NOT NULL DEFAULT 0 CHECK (balance >= 0),
+    ADD COLUMN IF NOT EXISTS type priveleges_enum NOT NULL DEFAULT 'user';
+
+-- the first row of every player and lobby pair is kept, later duplicates are removed
+DELETE FROM players_lobbies duplicate
+    USING players_lobbies kept
+    WHERE duplicate.player_id = kept.player_id
+        AND duplicate.lobby_id = kept.lobby_id
+        AND duplicate.player_lobby_id > kept.player_lobby_id;
+
+DO $$ BEGIN
+IF NOT EXISTS (
+    SELECT 1 FROM pg_index i
+    WHERE i.indrelid = 'players_lobbies'::regclass
+        AND i.indisunique
+        AND i.indpred IS NULL
+        AND i.indexprs IS NULL
+        AND (SELECT array_agg(a.attname::text ORDER BY a.attname) FROM pg_attribute a
+            WHERE a.attrelid = i.indrelid AND a.attnum = ANY (i.indkey))
+            = ARRAY['lobby_id', 'player_id']
+) THEN
+    ALTER TABLE players_lobbies
+        ADD CONSTRAINT players_lobbies_player_id_lobby_id_key UNIQUE (player_id, lobby_id);
+END IF;
+END $$;
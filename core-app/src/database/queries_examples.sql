@@ -1,3 +1,4 @@
+-- Examples against schema.sql, see its header. The server's own queries are in postgres_database.rs.
 -- Adding new users
 PREPARE insert_user (
     VARCHAR,
@@ -1,3 +1,10 @@
+-- Design of the full product schema, kept for reference. The server doesn't apply it:
+-- migrations/ create the tables it uses and migrations.rs checks them at startup.
+-- Left out of the migrations on purpose:
+--   users.username and users.password: credentials live in auth-service, tokens carry users.id
+--   games, players_games, games_history_items: tables are lobbies and players_lobbies,
+--   finished hands go to hand_histories
+-- users.balance is not left out, 0001 creates it and 0003 adds it to older databases.
 DO $ $ BEGIN IF NOT EXISTS (
     SELECT
         1
@@ -1,16 +1,17 @@
 use pokereval_cactus::card::Card as PCard;
-use pokereval_cactus::evaluator;
 use rand::Rng;
 use std::collections::{BTreeMap, HashMap};
 
 use crate::{
+    equity::{calculate_equity_with_rng, evaluator, EquityLimits},
     game::{DeckState, GameState, KeyPositions, PlayerState},
     protos::{
         card::CardPair,
         client_state::ClientState,
+        equity::EquityRequest,
         game_state::{
-            Action, ActionType, GameStatus, PlayerCards, ShowdownOutcome, Street, StreetStatus,
-            Winner,
+            Action, ActionType, GameStatus, PlayerCards, PotResult, ShowdownOutcome, Street,
+            StreetStatus, Winner,
         },
         google::protobuf::{BoolValue, Int32Value},
         player::{Player, PlayerStatus},
@@ -40,7 +41,7 @@ impl Pot {
     fn new(unique_bet: i32, side_pot: i32, id: i32) -> Self {
         Pot {
             unique_bet,
-            side_pot: side_pot,
+            side_pot,
             side_pot_factor: 0,
             eligable_players: Vec::from(&[id]),
         }
@@ -52,16 +53,37 @@ impl StreetStatus {
         4
     }
 }
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct PotWinners {
     rank: i32,
     winners: Vec<i32>,
 }
 
+impl Default for PotWinners {
+    // cactus kev ranks: the lower the rank, the stronger the hand, so any hand beats no hand
+    fn default() -> Self {
+        PotWinners {
+            rank: i32::MAX,
+            winners: Vec::new(),
+        }
+    }
+}
+
 struct RankedPlayer<'a> {
     rank: i32,
     player: &'a mut Player,
 }
+
+// Strips everything private to the player the state was built for
+pub fn public_view(mut state: ClientState) -> ClientState {
+    state.player_id = 0;
+    state.cards = None;
+    state.amount_to_call = None;
+    state.min_amount_to_raise = None;
+    state.can_raise = None;
+    state
+}
+
 impl Dealer {
     // STATIC PUBLIC --------------------------------------------------------
 
@@ -76,8 +98,8 @@ impl Dealer {
         game_state: &mut GameState,
         player_state: &mut PlayerState,
         deck_state: &mut DeckState,
-    ) -> Result<Vec<ClientState>, &str> {
-        let button_index = rand::thread_rng().gen_range(0..player_state.players.len());
+    ) -> Result<Vec<ClientState>, &'static str> {
+        let button_index = deck_state.rng.gen_range(0..player_state.players.len());
 
         let positions =
             self.calculate_key_positions(button_index, player_state.players.len() as i32);
@@ -119,6 +141,15 @@ impl Dealer {
         self.create_client_state(player, game_state, player_state)
     }
 
+    pub fn get_client_state_for(
+        &self,
+        player: &Player,
+        game_state: &GameState,
+        player_state: &PlayerState,
+    ) -> ClientState {
+        self.create_client_state(player, game_state, player_state)
+    }
+
     pub fn get_client_states(
         &self,
         game_state: &GameState,
@@ -133,13 +164,22 @@ impl Dealer {
         player_state: &mut PlayerState,
         deck_state: &mut DeckState,
     ) -> UpdatedState {
-        game_state.street.street_status = StreetStatus::River.into();
-        while game_state.street.cards.len() != 5 {
-            game_state
-                .street
-                .cards
-                .push(deck_state.deck.cards.pop_front().unwrap());
+        // one step per call, so every street of the runout reaches clients with updated equity:
+        // hands are tabled on the street the all-in happened, then the board is dealt
+        if game_state.street.cards.len() < 5 {
+            if !game_state.revealed_cards.is_empty() {
+                self.deal_runout_street(game_state, deck_state);
+            }
+            self.reveal_all_in_hands(game_state, player_state, deck_state);
+            let states = self.create_client_states(game_state, player_state);
+
+            return UpdatedState {
+                client_states: states,
+                should_complete_game_cycle_automatically: true,
+                is_ready_for_next_hand: false,
+            };
         }
+
         let showdown_outcome = self.calculate_winner(true, game_state, player_state);
         self.post_showdown_cleanup(player_state);
         game_state.showdown_outcome = Some(showdown_outcome);
@@ -177,7 +217,7 @@ impl Dealer {
         &self,
         player_id: i32,
         lobby_id: i32,
-        game_state: &mut GameState,
+        _game_state: &mut GameState,
         player_state: &mut PlayerState,
     ) -> PlayerActionRequest {
         let player = player_state
@@ -205,7 +245,7 @@ impl Dealer {
         &self,
         player_id: i32,
         lobby_id: i32,
-        game_state: &mut GameState,
+        _game_state: &mut GameState,
         player_state: &mut PlayerState,
     ) -> PlayerActionRequest {
         let player = player_state
@@ -263,7 +303,7 @@ impl Dealer {
             ActionType::Fold => {
                 self.process_fold_action(payload.player_id, game_state, player_state);
                 let result = self.can_determine_winner(player_state);
-                if result.is_some() && result.unwrap() == true {
+                if result.is_some() && result.unwrap() {
                     let showdown_outcome = self.calculate_winner(false, game_state, player_state);
                     self.post_showdown_cleanup(player_state);
                     game_state.showdown_outcome = Some(showdown_outcome);
@@ -305,7 +345,7 @@ impl Dealer {
         // TODO: in case player has made invalid action, we have to wait until his timer ends and proceed it as fold
 
         let last_active = self
-            .calculate_last_active_player_index(&player_state, game_state)
+            .calculate_last_active_player_index(player_state, game_state)
             .unwrap();
         if last_active == game_state.positions.curr_player_index.unwrap() {
             game_state.raiser_index = None;
@@ -323,9 +363,10 @@ impl Dealer {
                     should_complete_game_cycle_automatically: false,
                 };
             }
-            if self.should_complete_game_cycle_automatically(player_state) == true {
+            if self.should_complete_game_cycle_automatically(player_state) {
                 self.mark_eliminated_players(player_state);
                 game_state.showdown_outcome = None;
+                self.reveal_all_in_hands(game_state, player_state, deck_state);
                 let states = self.create_client_states(game_state, player_state);
                 return UpdatedState {
                     client_states: states,
@@ -335,15 +376,15 @@ impl Dealer {
             }
             self.next_street(game_state, deck_state, player_state);
         } else {
-            self.next_player(&player_state, game_state);
+            self.next_player(player_state, game_state);
         }
         game_state.showdown_outcome = None;
         let states = self.create_client_states(game_state, player_state);
-        return UpdatedState {
+        UpdatedState {
             client_states: states,
             is_ready_for_next_hand: false,
             should_complete_game_cycle_automatically: false,
-        };
+        }
     }
 
     pub fn setup_next_cycle(
@@ -355,13 +396,17 @@ impl Dealer {
         self.setup_next_hand(player_state, deck_state, game_state);
         // TODO: refactor showdown automation cycle handling;
         game_state.showdown_outcome = None;
+        let should_complete_game_cycle_automatically =
+            self.should_complete_game_cycle_automatically(player_state);
+        if should_complete_game_cycle_automatically {
+            self.reveal_all_in_hands(game_state, player_state, deck_state);
+        }
         let states = self.create_client_states(game_state, player_state);
-        return UpdatedState {
+        UpdatedState {
             client_states: states,
             is_ready_for_next_hand: false,
-            should_complete_game_cycle_automatically: self
-                .should_complete_game_cycle_automatically(player_state),
-        };
+            should_complete_game_cycle_automatically,
+        }
     }
 
     // PRIVATE ----------------------------------------------------------------------------------------
@@ -422,8 +467,8 @@ impl Dealer {
         game_state: &GameState,
         player_state: &PlayerState,
     ) -> i32 {
-        if game_state.raiser_index.is_some() {
-            let min_call_amount = player_state.players[game_state.raiser_index.unwrap() as usize]
+        if let Some(raiser_index) = game_state.raiser_index {
+            let min_call_amount = player_state.players[raiser_index]
                 .bet_in_current_seed
                 - p.bet_in_current_seed;
             if min_call_amount > p.bank {
@@ -445,18 +490,18 @@ impl Dealer {
         // let min_raise = game_state.biggest_bet_on_curr_street + game_state.raise_amount;
         // if min_raise > p.bank { p.bank } else { min_raise }
         if game_state.raiser_index.is_some() {
-            return game_state.biggest_bet_on_curr_street + game_state.raise_amount;
+            game_state.biggest_bet_on_curr_street + game_state.raise_amount
         } else {
-            return if game_state.street.street_status() == StreetStatus::Preflop {
+            if game_state.street.street_status() == StreetStatus::Preflop {
                 game_state.big_blind * 2 // FIX: or the biggest bank on the table except self
             } else {
                 game_state.big_blind
-            };
+            }
         }
     }
 
     fn get_player_id_by_index(&self, index: usize, player_state: &PlayerState) -> i32 {
-        player_state.players.get(index as usize).unwrap().user_id
+        player_state.players.get(index).unwrap().user_id
     }
 
     fn get_filtered_players(
@@ -493,11 +538,17 @@ impl Dealer {
                 curr_button_id: None,
                 curr_player_id: None,
                 curr_small_blind_id: None,
-                game_status: game_state.status.clone().into(),
+                game_status: game_state.status.into(),
                 lobby_id: self.lobby_id,
                 players: filtered_players,
                 showdown_outcome: None,
                 street: None,
+                revealed_cards: Vec::new(),
+                equities: Vec::new(),
+                // stamped by the transport when the state is sent
+                sequence: 0,
+                state_hash: 0,
+                pot: 0,
             };
         }
 
@@ -543,13 +594,14 @@ impl Dealer {
             lobby_id: self.lobby_id,
             showdown_outcome: game_state.showdown_outcome.clone(),
             action_history: game_state.action_history.clone(),
+            revealed_cards: game_state.revealed_cards.clone(),
+            equities: game_state.equities.clone(),
+            sequence: 0,
+            state_hash: 0,
+            pot: game_state.game_bank,
         }
     }
 
-    fn pre_all_in_bets_calculations() {
-        todo!()
-    }
-
     // TODO: Send delta updates in future
     // TODO: move to game struct level
     fn create_client_states(
@@ -617,9 +669,7 @@ impl Dealer {
         for player in players {
             // TODO: will be great to write own 2+2 evaluator;
             // CARE: in future we can have sitouted players without cards...
-            let eval = evaluator::Evaluator::new();
-
-            let result = eval.evaluate(
+            let result = evaluator().evaluate(
                 vec![
                     PCard::new(
                         player
@@ -646,7 +696,7 @@ impl Dealer {
             );
 
             ranked_players.push(RankedPlayer {
-                player: player,
+                player,
                 rank: result,
             });
         }
@@ -692,6 +742,10 @@ impl Dealer {
             return ShowdownOutcome {
                 players_cards,
                 street_history: Some(game_state.street.clone()),
+                pots: vec![PotResult {
+                    amount: game_state.game_bank,
+                    winners: winners.clone(),
+                }],
                 winners,
                 process_flop_automatically: is_manual_street,
             };
@@ -702,6 +756,7 @@ impl Dealer {
         let pots: BTreeMap<i32, Pot> = self.calculate_pots(&players_with_bets);
 
         let mut ranked_players = self.calculate_hands_strength(game_state, &mut players_with_bets);
+        let mut pot_results = Vec::new();
 
         for (index, pot) in pots.iter().enumerate() {
             let mut pot_winners = PotWinners::default();
@@ -712,7 +767,7 @@ impl Dealer {
                         && ranked_player.player.action.as_ref().unwrap().action_type()
                             != ActionType::Fold
                     {
-                        if pot_winners.rank < ranked_player.rank {
+                        if ranked_player.rank < pot_winners.rank {
                             pot_winners = PotWinners {
                                 rank: ranked_player.rank,
                                 winners: vec![ranked_player.player.user_id],
@@ -725,17 +780,33 @@ impl Dealer {
             }
 
             let winners_amount = pot_winners.winners.len() as i32;
+            let mut pot_result = PotResult {
+                amount: pot.1.side_pot,
+                winners: Vec::new(),
+            };
 
             if winners_amount > 0 {
                 let share = pot.1.side_pot / winners_amount;
+                // chips which can't be split evenly go to the first winners, one each
+                let mut odd_chips = pot.1.side_pot % winners_amount;
                 for winner in pot_winners.winners {
                     let player = ranked_players.get_mut(&winner).unwrap();
-                    player.player.bank += share;
+                    let amount = if odd_chips > 0 {
+                        odd_chips -= 1;
+                        share + 1
+                    } else {
+                        share
+                    };
+                    player.player.bank += amount;
+                    pot_result.winners.push(Winner {
+                        player_id: player.player.user_id,
+                        win_amout: amount,
+                    });
                     // main pot
                     if index == 0 {
                         winners.push(Winner {
                             player_id: player.player.user_id,
-                            win_amout: share,
+                            win_amout: amount,
                         });
                     }
                     players_cards.push(PlayerCards {
@@ -744,6 +815,7 @@ impl Dealer {
                     })
                 }
             }
+            pot_results.push(pot_result);
         }
 
         ShowdownOutcome {
@@ -751,6 +823,7 @@ impl Dealer {
             street_history: Some(game_state.street.clone()),
             winners,
             process_flop_automatically: is_manual_street,
+            pots: pot_results,
         }
     }
 
@@ -929,8 +1002,8 @@ impl Dealer {
         player_state: &PlayerState,
     ) -> bool {
         if let Some(index) = game_state.raiser_index {
-            let raiser_bank = player_state.players[index as usize].bank;
-            return raiser_bank != 0 || raiser_bank == 0 && player.action.as_ref().is_none();
+            let raiser_bank = player_state.players[index].bank;
+            return raiser_bank != 0 || player.action.as_ref().is_none();
         }
         player.bank > 0
     }
@@ -953,13 +1026,13 @@ impl Dealer {
 
             let bet_is_all_in = self.is_all_in(player, bet_amount);
 
-            if self.can_raise(player, game_state, player_state) == false {
+            if !self.can_raise(player, game_state, player_state) {
                 println!("Player not eligable to raise");
                 return;
             }
 
             if bet_amount < valid_min_raise
-                && (bet_is_all_in == false && bet_amount < game_state.biggest_bet_on_curr_street)
+                && (!bet_is_all_in && bet_amount < game_state.biggest_bet_on_curr_street)
             {
                 println!("Player raise amount is not valid");
                 return;
@@ -1000,7 +1073,7 @@ impl Dealer {
     // REFACTOR
 
     fn get_loop_incremented_index(&self, index: usize, range: i32) -> usize {
-        return (index + 1) % range as usize;
+        (index + 1) % range as usize
     }
 
 
@@ -1012,7 +1085,7 @@ impl Dealer {
         blind_size: i32,
         game_state: &mut GameState,
     ) {
-        let player = &mut player_state.players[small_blind_index as usize];
+        let player = &mut player_state.players[small_blind_index];
         let small_blind_size = blind_size / 2;
         let small_blind_bet_amount = if small_blind_size > player.bank {
             player.bank
@@ -1032,7 +1105,7 @@ impl Dealer {
         game_state.action_history.push(action.clone());
         player.action = Some(action);
 
-        let player = &mut player_state.players[big_blind_index as usize];
+        let player = &mut player_state.players[big_blind_index];
         let big_blind_bet_amount = if blind_size > player.bank {
             player.bank
         } else {
@@ -1081,16 +1154,10 @@ impl Dealer {
             (game_state.positions.button_index.unwrap() + 1) % player_state.players.len();
 
         for _ in 0..player_state.players.len() {
-            if player_state.players[new_curr]
-                .action
-                .as_ref()
-                .unwrap()
-                .action_type()
-                != ActionType::Fold
-            {
+            if !self.has_folded(&player_state.players[new_curr]) {
                 return Some(new_curr);
             } else {
-                new_curr = (new_curr + 1) % player_state.players.len() as usize;
+                new_curr = (new_curr + 1) % player_state.players.len();
             }
         }
 
@@ -1122,14 +1189,10 @@ impl Dealer {
             (raiser_index + player_state.players.len() - 1) % player_state.players.len()
         } else {
             self.get_default_last_player_index(player_state, game_state)
-        } as usize;
+        };
 
         for _ in 0..player_state.players.len() {
-            let action = player_state.players[last_player_index]
-                .action
-                .as_ref()
-                .unwrap();
-            if action.action_type != ActionType::Fold as i32 {
+            if !self.has_folded(&player_state.players[last_player_index]) {
                 return Some(last_player_index);
             }
             last_player_index =
@@ -1139,6 +1202,64 @@ impl Dealer {
         None
     }
 
+    fn deal_runout_street(&self, game_state: &mut GameState, deck_state: &mut DeckState) {
+        let cards_amount = if game_state.street.cards.is_empty() {
+            3
+        } else {
+            1
+        };
+        for _ in 0..cards_amount {
+            game_state
+                .street
+                .cards
+                .push(deck_state.deck.cards.pop_front().unwrap());
+        }
+
+        let street_status = match game_state.street.cards.len() {
+            3 => StreetStatus::Flop,
+            4 => StreetStatus::Turn,
+            _ => StreetStatus::River,
+        };
+        game_state.street.street_status = street_status.into();
+    }
+
+    fn reveal_all_in_hands(
+        &self,
+        game_state: &mut GameState,
+        player_state: &PlayerState,
+        deck_state: &mut DeckState,
+    ) {
+        let revealed_cards: Vec<PlayerCards> = player_state
+            .players
+            .iter()
+            .filter(|p| {
+                p.cards.is_some()
+                    && (p.action.is_none()
+                        || p.action.as_ref().unwrap().action_type() != ActionType::Fold)
+            })
+            .map(|p| PlayerCards {
+                player_id: p.user_id,
+                cards: p.cards.clone(),
+            })
+            .collect();
+
+        let request = EquityRequest {
+            players: revealed_cards.clone(),
+            board: game_state.street.cards.clone(),
+            dead_cards: Vec::new(),
+        };
+
+        // deck rng keeps equity of seeded tables reproducible
+        game_state.equities = match calculate_equity_with_rng(&request, &mut deck_state.rng, EquityLimits::TABLE) {
+            Ok(response) => response.equities,
+            Err(e) => {
+                println!("can't calculate all-in equity: {}", e);
+                Vec::new()
+            }
+        };
+        game_state.revealed_cards = revealed_cards;
+    }
+
     fn next_street(
         &self,
         game_state: &mut GameState,
@@ -1152,7 +1273,7 @@ impl Dealer {
             return;
         } else {
             game_state.positions.curr_player_index =
-                self.calculate_curr_player_index_on_new_street(&player_state, game_state)
+                self.calculate_curr_player_index_on_new_street(player_state, game_state)
         }
 
         game_state.raise_amount = 0;
@@ -1189,6 +1310,8 @@ impl Dealer {
         game_state: &mut GameState,
     ) {
         game_state.street = Street::default();
+        game_state.revealed_cards = Vec::new();
+        game_state.equities = Vec::new();
         deck_state.new_random();
         game_state.action_history = Vec::new();
         self.deal_cards(deck_state, player_state, game_state);
@@ -1215,19 +1338,25 @@ impl Dealer {
         );
     }
 
+    fn has_folded(&self, player: &Player) -> bool {
+        player
+            .action
+            .as_ref()
+            .is_some_and(|a| a.action_type() == ActionType::Fold)
+    }
+
     fn next_player(&self, player_state: &PlayerState, game_state: &mut GameState) {
-        let mut curr_next = game_state.positions.curr_player_index.unwrap() as usize;
+        let mut curr_next = game_state.positions.curr_player_index.unwrap();
         let mut is_set = false;
 
         for _ in 0..player_state.players.len() {
             curr_next = (curr_next + 1) % player_state.players.len();
             if let Some(player) = player_state.players.get(curr_next) {
-                if let Some(action) = &player.action {
-                    if action.action_type() != ActionType::Fold {
-                        game_state.positions.curr_player_index = Some(curr_next);
-                        is_set = true;
-                        break;
-                    }
+                // no action yet means the player hasn't acted preflop, he is still in the hand
+                if !self.has_folded(player) {
+                    game_state.positions.curr_player_index = Some(curr_next);
+                    is_set = true;
+                    break;
                 }
             }
         }
@@ -10,6 +10,12 @@ pub struct DealerPool {
     pool: Mutex<HashMap<String, Vec<Dealer>>>,
 }
 
+impl Default for DealerPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DealerPool {
     pub fn new() -> Self {
         DealerPool {
@@ -22,9 +28,7 @@ impl DealerPool {
         if let Some(entry) = pool.get_mut(&lobby_id) {
             entry.push(v);
         } else {
-            let mut new_list = Vec::new();
-            new_list.push(v);
-            pool.insert(lobby_id, new_list);
+            pool.insert(lobby_id, vec![v]);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,668 @@
+use crate::{
+    card::CardDeck,
+    dealer::{public_view, Dealer, UpdatedState},
+    game::{DeckState, GameState, PlayerState},
+    protos::{
+        client_state::ClientState,
+        game_state::{Action, ActionType, GameStatus},
+        player::{Player, PlayerStatus},
+        requests::PlayerActionRequest,
+    },
+    responses::PlayerActionRequestError,
+};
+
+// Pure poker engine: every input is a Command, every output is an Event.
+// It knows nothing about sockets, threads or bots, the transport layer (Game)
+// decides how to deliver events and where the next command comes from.
+
+pub struct EngineState {
+    pub lobby_id: i32,
+    pub game_state: GameState,
+    pub player_state: PlayerState,
+    pub deck_state: DeckState,
+}
+
+impl Default for EngineState {
+    fn default() -> Self {
+        EngineState::new(0, 0)
+    }
+}
+
+impl EngineState {
+    pub fn new(lobby_id: i32, blind_size: i32) -> Self {
+        EngineState {
+            lobby_id,
+            game_state: GameState::new(blind_size),
+            player_state: PlayerState::new(),
+            deck_state: DeckState::new(CardDeck::new_random()),
+        }
+    }
+
+    // deterministic table: same seed and same commands produce the same hands
+    pub fn with_seed(lobby_id: i32, blind_size: i32, seed: u64) -> Self {
+        EngineState {
+            lobby_id,
+            game_state: GameState::new(blind_size),
+            player_state: PlayerState::new(),
+            deck_state: DeckState::with_seed(seed),
+        }
+    }
+
+    pub fn current_player(&self) -> Option<&Player> {
+        if self.game_state.status != GameStatus::Active {
+            return None;
+        }
+        self.game_state
+            .positions
+            .curr_player_index
+            .and_then(|index| self.player_state.players.get(index))
+    }
+
+    pub fn is_ready_to_start(&self) -> bool {
+        if self.game_state.status == GameStatus::Pause {
+            // restored hand continues only when at least two participants are back
+            let connected_players = self
+                .player_state
+                .players
+                .iter()
+                .filter(|p| p.status() != PlayerStatus::Disconnected)
+                .count();
+            return connected_players > 1;
+        }
+        // TODO: improve checking in case of player game status is not ready
+        self.game_state.status != GameStatus::Active && self.player_state.players.len() > 1
+    }
+
+    pub fn client_states(&self) -> Vec<ClientState> {
+        self.dealer()
+            .get_client_states(&self.game_state, &self.player_state)
+    }
+
+    pub fn client_state(&self, player_id: i32) -> ClientState {
+        self.dealer()
+            .get_client_state(&player_id, &self.game_state, &self.player_state)
+    }
+
+    // what a spectator sees: no hole cards except the ones tabled in an all-in runout
+    pub fn public_client_state(&self) -> ClientState {
+        let spectator = Player::default();
+        public_view(self.dealer().get_client_state_for(
+            &spectator,
+            &self.game_state,
+            &self.player_state,
+        ))
+    }
+
+    fn dealer(&self) -> Dealer {
+        Dealer::new(self.lobby_id)
+    }
+}
+
+pub enum Command {
+    Join(Player),
+    // deal the first hand, or continue a hand restored from snapshot
+    StartGame,
+    Action(PlayerActionRequest),
+    Timeout { player_id: i32 },
+    Disconnect { player_id: i32 },
+    // remove eliminated/disconnected players and deal the next hand
+    NextHand,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    StateChanged(Vec<ClientState>),
+    AwaitingAction { player_id: i32, is_bot: bool },
+    HandFinished,
+    PlayerEliminated { player_id: i32 },
+    GameStopped { status: GameStatus },
+    CommandRejected { reason: &'static str },
+}
+
+pub fn apply(mut state: EngineState, command: Command) -> (EngineState, Vec<Event>) {
+    let events = match command {
+        Command::Join(player) => join(&mut state, player),
+        Command::StartGame => start_game(&mut state),
+        Command::Action(request) => action(&mut state, request),
+        Command::Timeout { player_id } => timeout(&mut state, player_id),
+        Command::Disconnect { player_id } => disconnect(&mut state, player_id),
+        Command::NextHand => next_hand(&mut state),
+    };
+    (state, events)
+}
+
+fn is_current_player(state: &EngineState, player_id: i32) -> bool {
+    state
+        .current_player()
+        .is_some_and(|p| p.user_id == player_id)
+}
+
+fn join(state: &mut EngineState, mut player: Player) -> Vec<Event> {
+    let status = state.game_state.status;
+    let user_id = player.user_id;
+
+    // TODO: refactor ....
+    match state
+        .player_state
+        .players
+        .iter_mut()
+        .find(|p| p.user_id == user_id)
+    {
+        Some(p) => {
+            if p.status() != PlayerStatus::Disconnected {
+                // seat was held during reconnection grace period: cards, action and turn stay
+            } else if status == GameStatus::Pause {
+                // player returns to the restored hand with their cards and action
+                p.status = PlayerStatus::Ready.into();
+            } else if status == GameStatus::Active {
+                p.status = PlayerStatus::Ready.into();
+                let mut action = Action::default();
+                action.set_action_type(ActionType::Fold);
+                p.action = Some(action);
+            } else {
+                p.status = PlayerStatus::WaitingForPlayers.into();
+            }
+        }
+        None => {
+            if status == GameStatus::Active || status == GameStatus::Pause {
+                player.status = PlayerStatus::Ready.into();
+                let mut action = Action::default();
+                action.set_action_type(ActionType::Fold);
+                player.action = Some(action);
+            }
+            state.player_state.players.push(player);
+        }
+    }
+
+    vec![Event::StateChanged(state.client_states())]
+}
+
+fn start_game(state: &mut EngineState) -> Vec<Event> {
+    if state.game_state.status == GameStatus::Active {
+        return vec![Event::CommandRejected {
+            reason: "Game is already running",
+        }];
+    }
+
+    if state.game_state.status == GameStatus::Pause {
+        // hand restored from snapshot: players who are still away will be folded on their turn
+        state.game_state.status = GameStatus::Active;
+        let mut events = vec![Event::StateChanged(state.client_states())];
+        events.extend(awaiting_action(state));
+        return events;
+    }
+
+    remove_disconnected_players(state);
+
+    if state.player_state.players.len() < 2 {
+        return vec![Event::CommandRejected {
+            reason: "Not enough players to start a new game",
+        }];
+    }
+
+    // TODO: think about merging it with start_next_cylce function
+    let client_states = match state.dealer().start_new_game(
+        &mut state.game_state,
+        &mut state.player_state,
+        &mut state.deck_state,
+    ) {
+        Ok(states) => states,
+        Err(reason) => return vec![Event::CommandRejected { reason }],
+    };
+
+    let mut events = vec![Event::StateChanged(client_states)];
+    events.extend(awaiting_action(state));
+    events
+}
+
+fn action(state: &mut EngineState, request: PlayerActionRequest) -> Vec<Event> {
+    if request.lobby_id != state.lobby_id {
+        return vec![Event::CommandRejected {
+            reason: "Wrong lobby id in payload",
+        }];
+    }
+    if request.action.is_none() {
+        return vec![Event::CommandRejected {
+            reason: "Action is missing in payload",
+        }];
+    }
+    if !is_current_player(state, request.player_id) {
+        return vec![Event::CommandRejected {
+            reason: "Player can't act out of turn",
+        }];
+    }
+
+    update_game_state(state, Ok(request))
+}
+
+fn timeout(state: &mut EngineState, player_id: i32) -> Vec<Event> {
+    if !is_current_player(state, player_id) {
+        return vec![Event::CommandRejected {
+            reason: "Timeout of a player who is not on turn",
+        }];
+    }
+
+    let error = PlayerActionRequestError::Iddle {
+        id: player_id,
+        lobby_id: state.lobby_id,
+    };
+    update_game_state(state, Err(error))
+}
+
+fn disconnect(state: &mut EngineState, player_id: i32) -> Vec<Event> {
+    if is_current_player(state, player_id) {
+        let error = PlayerActionRequestError::Disconnected {
+            id: player_id,
+            lobby_id: state.lobby_id,
+        };
+        return update_game_state(state, Err(error));
+    }
+
+    match state
+        .player_state
+        .players
+        .iter_mut()
+        .find(|p| p.user_id == player_id)
+    {
+        Some(player) => {
+            player.set_status(PlayerStatus::Disconnected);
+            vec![Event::StateChanged(state.client_states())]
+        }
+        None => vec![Event::CommandRejected {
+            reason: "Player is not seated at the table",
+        }],
+    }
+}
+
+fn next_hand(state: &mut EngineState) -> Vec<Event> {
+    let mut events = remove_eliminated_players(state);
+    remove_disconnected_players(state);
+
+    let players_count = state.player_state.players.len();
+
+    if players_count == 0 {
+        prepare_to_game_stop(state);
+        state.game_state.status = GameStatus::None;
+        events.push(Event::GameStopped {
+            status: GameStatus::None,
+        });
+        return events;
+    }
+
+    let active_players = state
+        .player_state
+        .players
+        .iter()
+        .filter(|p| {
+            // can be sitouted players and we wanna pause game in such case
+            p.status() == PlayerStatus::Ready
+        })
+        .count();
+
+    // TODO: handle all possible cases
+    if active_players < 2 {
+        prepare_to_game_stop(state);
+        state.game_state.status = GameStatus::WaitingForPlayers;
+        events.push(Event::StateChanged(state.client_states()));
+        events.push(Event::GameStopped {
+            status: GameStatus::WaitingForPlayers,
+        });
+        return events;
+    }
+
+    let updated_state = state.dealer().setup_next_cycle(
+        &mut state.game_state,
+        &mut state.player_state,
+        &mut state.deck_state,
+    );
+
+    events.extend(continue_hand(state, updated_state));
+    events
+}
+
+fn update_game_state(
+    state: &mut EngineState,
+    action: Result<PlayerActionRequest, PlayerActionRequestError>,
+) -> Vec<Event> {
+    let updated_state = state.dealer().update_game_state(
+        action,
+        &mut state.game_state,
+        &mut state.player_state,
+        &mut state.deck_state,
+    );
+
+    continue_hand(state, updated_state)
+}
+
+// Runs the hand forward until somebody has to make a decision or the hand is over
+fn continue_hand(state: &mut EngineState, mut updated_state: UpdatedState) -> Vec<Event> {
+    let mut events = Vec::new();
+
+    loop {
+        events.push(Event::StateChanged(updated_state.client_states));
+
+        if updated_state.is_ready_for_next_hand {
+            events.push(Event::HandFinished);
+            return events;
+        }
+
+        if updated_state.should_complete_game_cycle_automatically {
+            updated_state = state.dealer().complete_game_cycle_automatically(
+                &mut state.game_state,
+                &mut state.player_state,
+                &mut state.deck_state,
+            );
+            continue;
+        }
+
+        events.extend(awaiting_action(state));
+        return events;
+    }
+}
+
+fn awaiting_action(state: &EngineState) -> Option<Event> {
+    state.current_player().map(|p| Event::AwaitingAction {
+        player_id: p.user_id,
+        is_bot: p.is_bot,
+    })
+}
+
+fn remove_eliminated_players(state: &mut EngineState) -> Vec<Event> {
+    let players: &mut Vec<Player> = &mut state.player_state.players;
+
+    let (retained, removed): (Vec<Player>, Vec<Player>) = players
+        .drain(..)
+        .partition(|p| p.status() != PlayerStatus::Eliminated);
+    state.player_state.players = retained;
+
+    removed
+        .iter()
+        .map(|p| Event::PlayerEliminated {
+            player_id: p.user_id,
+        })
+        .collect()
+}
+
+fn remove_disconnected_players(state: &mut EngineState) {
+    // TODO: provide disconnect event for other players?
+    state
+        .player_state
+        .players
+        .retain(|p| p.status() != PlayerStatus::Disconnected);
+}
+
+fn prepare_to_game_stop(state: &mut EngineState) {
+    // TODO: handle settings struct;
+    let blind_size = state.game_state.big_blind;
+
+    state.deck_state.new_random();
+    state.game_state = GameState::new(blind_size);
+    state.player_state.players.iter_mut().for_each(|p| {
+        p.action = None;
+        p.bet_in_current_seed = 0;
+        p.cards = None;
+        if p.status() == PlayerStatus::Ready {
+            p.status = PlayerStatus::WaitingForPlayers.into();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::game_state::StreetStatus;
+
+    const LOBBY_ID: i32 = 1;
+    const BLIND: i32 = 20;
+    const BANK: i32 = 1000;
+
+    fn table(players: i32) -> EngineState {
+        seeded_table(players, 42)
+    }
+
+    fn seeded_table(players: i32, seed: u64) -> EngineState {
+        let mut state = EngineState::with_seed(LOBBY_ID, BLIND, seed);
+        for user_id in 1..=players {
+            let player = Player {
+                user_id,
+                bank: BANK,
+                ..Default::default()
+            };
+            (state, _) = apply(state, Command::Join(player));
+        }
+        state
+    }
+
+    fn request(player_id: i32, action_type: ActionType, bet: i32) -> Command {
+        Command::Action(PlayerActionRequest {
+            player_id,
+            lobby_id: LOBBY_ID,
+            action: Some(Action {
+                action_type: action_type.into(),
+                bet,
+                player_id,
+                street_status: None,
+            }),
+        })
+    }
+
+    fn awaited(events: &[Event]) -> Option<i32> {
+        events.iter().rev().find_map(|e| match e {
+            Event::AwaitingAction { player_id, .. } => Some(*player_id),
+            _ => None,
+        })
+    }
+
+    fn rejected(events: &[Event]) -> Option<&'static str> {
+        events.iter().find_map(|e| match e {
+            Event::CommandRejected { reason } => Some(*reason),
+            _ => None,
+        })
+    }
+
+    fn chips(state: &EngineState) -> i32 {
+        let banks: i32 = state.player_state.players.iter().map(|p| p.bank).sum();
+        banks + state.game_state.game_bank
+    }
+
+    #[test]
+    fn start_game_deals_and_awaits_first_player() {
+        let (state, events) = apply(table(2), Command::StartGame);
+
+        assert_eq!(state.game_state.status, GameStatus::Active);
+        assert!(state.player_state.players.iter().all(|p| p.cards.is_some()));
+        assert_eq!(awaited(&events), state.current_player().map(|p| p.user_id));
+        assert_eq!(chips(&state), 2 * BANK);
+    }
+
+    #[test]
+    fn start_game_needs_two_players() {
+        let (_, events) = apply(table(1), Command::StartGame);
+        assert_eq!(
+            rejected(&events),
+            Some("Not enough players to start a new game")
+        );
+    }
+
+    #[test]
+    fn start_game_is_rejected_while_running() {
+        let (state, _) = apply(table(2), Command::StartGame);
+        let (_, events) = apply(state, Command::StartGame);
+        assert_eq!(rejected(&events), Some("Game is already running"));
+    }
+
+    #[test]
+    fn action_out_of_turn_is_rejected() {
+        let (state, events) = apply(table(2), Command::StartGame);
+        let waiting = awaited(&events).unwrap();
+        let other = if waiting == 1 { 2 } else { 1 };
+
+        let (_, events) = apply(state, request(other, ActionType::Fold, 0));
+        assert_eq!(rejected(&events), Some("Player can't act out of turn"));
+    }
+
+    #[test]
+    fn action_for_another_lobby_is_rejected() {
+        let (state, events) = apply(table(2), Command::StartGame);
+        let player_id = awaited(&events).unwrap();
+        let command = Command::Action(PlayerActionRequest {
+            player_id,
+            lobby_id: LOBBY_ID + 1,
+            action: None,
+        });
+
+        let (_, events) = apply(state, command);
+        assert_eq!(rejected(&events), Some("Wrong lobby id in payload"));
+    }
+
+    #[test]
+    fn fold_heads_up_finishes_hand_and_keeps_chips() {
+        let (state, events) = apply(table(2), Command::StartGame);
+        let player_id = awaited(&events).unwrap();
+
+        let (state, events) = apply(state, request(player_id, ActionType::Fold, 0));
+        assert!(events.iter().any(|e| matches!(e, Event::HandFinished)));
+        // the pot is paid out, game_bank is only cleared when the next hand is dealt
+        let banks: i32 = state.player_state.players.iter().map(|p| p.bank).sum();
+        assert_eq!(banks, 2 * BANK);
+
+        let (state, events) = apply(state, Command::NextHand);
+        assert!(awaited(&events).is_some());
+        assert_eq!(chips(&state), 2 * BANK);
+    }
+
+    #[test]
+    fn timeout_folds_only_the_player_on_turn() {
+        let (state, events) = apply(table(2), Command::StartGame);
+        let waiting = awaited(&events).unwrap();
+        let other = if waiting == 1 { 2 } else { 1 };
+
+        let (state, events) = apply(state, Command::Timeout { player_id: other });
+        assert_eq!(
+            rejected(&events),
+            Some("Timeout of a player who is not on turn")
+        );
+
+        let (_, events) = apply(state, Command::Timeout { player_id: waiting });
+        assert!(events.iter().any(|e| matches!(e, Event::HandFinished)));
+    }
+
+    #[test]
+    fn every_seat_acts_once_in_a_limped_preflop() {
+        for players in [3, 4, 6, 10] {
+            let (mut state, mut events) = apply(table(players), Command::StartGame);
+            let mut acted = Vec::new();
+
+            while state.game_state.street.street_status() == StreetStatus::Preflop {
+                let player_id = awaited(&events).expect("nobody is awaited preflop");
+                let call = state
+                    .client_state(player_id)
+                    .amount_to_call
+                    .map_or(0, |v| v.value);
+                let action_type = if call == 0 {
+                    ActionType::Check
+                } else {
+                    ActionType::Call
+                };
+                acted.push(player_id);
+                (state, events) = apply(state, request(player_id, action_type, call));
+                assert_eq!(rejected(&events), None);
+            }
+
+            acted.sort();
+            assert_eq!(acted, (1..=players).collect::<Vec<_>>());
+        }
+    }
+
+    // heads-up, the first player shoves preflop and the other one calls
+    fn all_in_preflop(state: EngineState) -> (EngineState, Vec<Event>) {
+        let (state, events) = apply(state, Command::StartGame);
+        let shover = awaited(&events).unwrap();
+        let bank = state
+            .player_state
+            .players
+            .iter()
+            .find(|p| p.user_id == shover)
+            .unwrap()
+            .bank;
+
+        let (state, events) = apply(state, request(shover, ActionType::Raise, bank));
+        assert_eq!(rejected(&events), None);
+        let caller = awaited(&events).unwrap();
+        let call = state
+            .client_state(caller)
+            .amount_to_call
+            .map_or(0, |v| v.value);
+
+        apply(state, request(caller, ActionType::Call, call))
+    }
+
+    fn states(events: &[Event]) -> impl Iterator<Item = &ClientState> {
+        events.iter().flat_map(|e| match e {
+            Event::StateChanged(states) => states.first(),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn preflop_all_in_shows_equity_before_the_flop() {
+        let (_, events) = all_in_preflop(table(2));
+        let first_revealed = states(&events)
+            .find(|s| !s.revealed_cards.is_empty())
+            .expect("all-in hands are never tabled");
+
+        let board = first_revealed.street.as_ref().map_or(0, |s| s.cards.len());
+        assert_eq!(board, 0);
+        assert_eq!(first_revealed.equities.len(), 2);
+        assert!(events.iter().any(|e| matches!(e, Event::HandFinished)));
+    }
+
+    #[test]
+    fn showdown_pays_the_hand_the_river_equity_shows_winning() {
+        for seed in 0..6 {
+            let (state, events) = all_in_preflop(seeded_table(2, seed));
+            let river = states(&events)
+                .filter(|s| s.street.as_ref().is_some_and(|s| s.cards.len() == 5))
+                .find(|s| !s.equities.is_empty())
+                .expect("river equity is never shown");
+            let outcome = states(&events)
+                .find_map(|s| s.showdown_outcome.as_ref())
+                .expect("no showdown outcome");
+
+            let mut expected: Vec<i32> = river
+                .equities
+                .iter()
+                .filter(|e| e.win == 1.0 || e.tie == 1.0)
+                .map(|e| e.player_id)
+                .collect();
+            let mut paid: Vec<i32> = outcome.winners.iter().map(|w| w.player_id).collect();
+            expected.sort();
+            paid.sort();
+            assert_eq!(paid, expected, "seed {}", seed);
+
+            if let [winner] = expected[..] {
+                let bank = |user_id: i32| {
+                    state
+                        .player_state
+                        .players
+                        .iter()
+                        .find(|p| p.user_id == user_id)
+                        .map_or(0, |p| p.bank)
+                };
+                assert_eq!(bank(winner), 2 * BANK, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn restored_hand_resumes_on_start() {
+        let (mut state, events) = apply(table(2), Command::StartGame);
+        let expected = awaited(&events);
+        state.game_state.status = GameStatus::Pause;
+
+        assert!(state.is_ready_to_start());
+        let (state, events) = apply(state, Command::StartGame);
+        assert_eq!(state.game_state.status, GameStatus::Active);
+        assert_eq!(awaited(&events), expected);
+    }
+}
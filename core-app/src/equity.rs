@@ -0,0 +1,534 @@
+use std::{error::Error, fmt, sync::OnceLock};
+
+use pokereval_cactus::{card::Card as PCard, evaluator::Evaluator};
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{
+    card::CardDeck,
+    protos::{
+        card::Card,
+        equity::{EquityRequest, EquityResponse, PlayerEquity},
+        game_state::PlayerCards,
+    },
+};
+
+// Win/tie equity of known hands. Every runout is enumerated when there are few enough of them
+// (from the flop on), otherwise runouts are sampled at random (preflop).
+
+pub const MIN_PLAYERS: usize = 2;
+pub const MAX_PLAYERS: usize = 10;
+const BOARD_SIZE: usize = 5;
+const DECK_SIZE: usize = 52;
+// limits are meant for this many hands, every runout is evaluated once per hand
+const LIMITS_PLAYERS: u64 = 3;
+
+// How much work one calculation may do: runouts are enumerated up to max_enumerated,
+// above that `samples` random runouts are evaluated
+#[derive(Debug, Clone, Copy)]
+pub struct EquityLimits {
+    pub max_enumerated: u64,
+    pub samples: u64,
+}
+
+impl EquityLimits {
+    // /equity endpoint, runs on a worker of the thread pool
+    pub const REQUEST: EquityLimits = EquityLimits {
+        max_enumerated: 50_000,
+        samples: 20_000,
+    };
+    // all-in runout, runs on the table actor between two streets
+    pub const TABLE: EquityLimits = EquityLimits {
+        max_enumerated: 2_000,
+        samples: 1_000,
+    };
+
+    // more hands get proportionally fewer runouts, so a full ring costs as much as three hands
+    fn for_players(self, players_amount: usize) -> EquityLimits {
+        let players_amount = players_amount as u64;
+        if players_amount <= LIMITS_PLAYERS {
+            return self;
+        }
+        EquityLimits {
+            max_enumerated: self.max_enumerated * LIMITS_PLAYERS / players_amount,
+            samples: self.samples * LIMITS_PLAYERS / players_amount,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EquityError {
+    WrongPlayersAmount(usize),
+    WrongBoardSize(usize),
+    MissingHoleCards { player_id: i32 },
+    InvalidCard { value: i32, suit: i32 },
+    DuplicateCard(String),
+    NotEnoughCards,
+}
+
+impl fmt::Display for EquityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EquityError::WrongPlayersAmount(amount) => write!(
+                f,
+                "equity needs from {} to {} players, got {}",
+                MIN_PLAYERS, MAX_PLAYERS, amount
+            ),
+            EquityError::WrongBoardSize(size) => {
+                write!(f, "board must have 0, 3, 4 or 5 cards, got {}", size)
+            }
+            EquityError::MissingHoleCards { player_id } => {
+                write!(f, "player {} has no hole cards", player_id)
+            }
+            EquityError::InvalidCard { value, suit } => {
+                write!(
+                    f,
+                    "card with value {} and suit {} doesn't exist",
+                    value, suit
+                )
+            }
+            EquityError::DuplicateCard(card) => write!(f, "card {} is used twice", card),
+            EquityError::NotEnoughCards => write!(f, "not enough cards left to complete the board"),
+        }
+    }
+}
+
+impl Error for EquityError {}
+
+pub fn calculate_equity(request: &EquityRequest) -> Result<EquityResponse, EquityError> {
+    calculate_equity_with_rng(request, &mut rand::thread_rng(), EquityLimits::REQUEST)
+}
+
+pub fn calculate_equity_with_rng<R: Rng>(
+    request: &EquityRequest,
+    rng: &mut R,
+    limits: EquityLimits,
+) -> Result<EquityResponse, EquityError> {
+    let players_amount = request.players.len();
+    if !(MIN_PLAYERS..=MAX_PLAYERS).contains(&players_amount) {
+        return Err(EquityError::WrongPlayersAmount(players_amount));
+    }
+
+    let board_size = request.board.len();
+    if ![0, 3, 4, 5].contains(&board_size) {
+        return Err(EquityError::WrongBoardSize(board_size));
+    }
+
+    let mut used = [false; DECK_SIZE];
+    let mut take = |card: &Card| -> Result<i32, EquityError> {
+        if !(0..13).contains(&card.value) || !(0..4).contains(&card.suit) {
+            return Err(EquityError::InvalidCard {
+                value: card.value,
+                suit: card.suit,
+            });
+        }
+        let index = card_index(card);
+        if used[index] {
+            return Err(EquityError::DuplicateCard(card.to_string()));
+        }
+        used[index] = true;
+        Ok(PCard::new(card.to_string()))
+    };
+
+    let mut hands = Vec::with_capacity(players_amount);
+    for player in &request.players {
+        let (card1, card2) = hole_cards(player)?;
+        hands.push(vec![take(card1)?, take(card2)?]);
+    }
+
+    let board = request
+        .board
+        .iter()
+        .map(&mut take)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for card in &request.dead_cards {
+        take(card)?;
+    }
+
+    let deck: Vec<i32> = CardDeck::new_ordered()
+        .cards
+        .iter()
+        .filter(|card| !used[card_index(card)])
+        .map(|card| PCard::new(card.to_string()))
+        .collect();
+
+    let missing = BOARD_SIZE - board.len();
+    if deck.len() < missing {
+        return Err(EquityError::NotEnoughCards);
+    }
+
+    let mut tally = Tally::new(hands, board);
+
+    let limits = limits.for_players(players_amount);
+    let possible_runouts = combinations_count(deck.len() as u64, missing as u64);
+    let is_exact = possible_runouts <= limits.max_enumerated;
+
+    if is_exact {
+        for_each_combination(&deck, missing, |runout| tally.add_runout(runout));
+    } else {
+        let mut deck = deck;
+        for _ in 0..limits.samples {
+            let (runout, _) = deck.partial_shuffle(rng, missing);
+            tally.add_runout(runout);
+        }
+    }
+
+    Ok(tally.into_response(&request.players, is_exact))
+}
+
+// lookup tables of the evaluator are expensive to build, they are shared by every table
+pub fn evaluator() -> &'static Evaluator {
+    static EVALUATOR: OnceLock<Evaluator> = OnceLock::new();
+    EVALUATOR.get_or_init(Evaluator::new)
+}
+
+struct Tally {
+    evaluator: &'static Evaluator,
+    hands: Vec<Vec<i32>>,
+    board: Vec<i32>,
+    wins: Vec<u64>,
+    ties: Vec<u64>,
+    runouts: u64,
+}
+
+impl Tally {
+    fn new(hands: Vec<Vec<i32>>, board: Vec<i32>) -> Self {
+        let players_amount = hands.len();
+        Tally {
+            evaluator: evaluator(),
+            hands,
+            board,
+            wins: vec![0; players_amount],
+            ties: vec![0; players_amount],
+            runouts: 0,
+        }
+    }
+
+    fn add_runout(&mut self, runout: &[i32]) {
+        let mut board = self.board.clone();
+        board.extend_from_slice(runout);
+
+        // cactus kev ranks: the lower the rank, the stronger the hand
+        let ranks: Vec<i32> = self
+            .hands
+            .iter()
+            .map(|hand| self.evaluator.evaluate(hand.clone(), board.clone()))
+            .collect();
+        let best = *ranks.iter().min().unwrap();
+        let winners = ranks.iter().filter(|r| **r == best).count();
+
+        for (i, rank) in ranks.iter().enumerate() {
+            if *rank != best {
+                continue;
+            }
+            if winners == 1 {
+                self.wins[i] += 1;
+            } else {
+                self.ties[i] += 1;
+            }
+        }
+
+        self.runouts += 1;
+    }
+
+    fn into_response(self, players: &[PlayerCards], is_exact: bool) -> EquityResponse {
+        let runouts = self.runouts.max(1) as f64;
+
+        let equities = players
+            .iter()
+            .enumerate()
+            .map(|(i, p)| PlayerEquity {
+                player_id: p.player_id,
+                win: self.wins[i] as f64 / runouts,
+                tie: self.ties[i] as f64 / runouts,
+            })
+            .collect();
+
+        EquityResponse {
+            equities,
+            is_exact,
+            runouts: self.runouts as i32,
+        }
+    }
+}
+
+fn hole_cards(player: &PlayerCards) -> Result<(&Card, &Card), EquityError> {
+    let missing = EquityError::MissingHoleCards {
+        player_id: player.player_id,
+    };
+
+    match &player.cards {
+        Some(pair) => match (&pair.card1, &pair.card2) {
+            (Some(card1), Some(card2)) => Ok((card1, card2)),
+            _ => Err(missing),
+        },
+        None => Err(missing),
+    }
+}
+
+fn card_index(card: &Card) -> usize {
+    card.value as usize * 4 + card.suit as usize
+}
+
+fn combinations_count(n: u64, k: u64) -> u64 {
+    (0..k).fold(1, |acc, i| acc * (n - i) / (i + 1))
+}
+
+fn for_each_combination<F: FnMut(&[i32])>(cards: &[i32], size: usize, mut f: F) {
+    if size == 0 {
+        f(&[]);
+        return;
+    }
+
+    let mut indices: Vec<usize> = (0..size).collect();
+    let mut combination = vec![0; size];
+
+    loop {
+        for (slot, &index) in combination.iter_mut().zip(indices.iter()) {
+            *slot = cards[index];
+        }
+        f(&combination);
+
+        // move the rightmost index which still has room, reset everything after it
+        let mut i = size;
+        while i > 0 && indices[i - 1] == cards.len() - size + i - 1 {
+            i -= 1;
+        }
+        if i == 0 {
+            return;
+        }
+        indices[i - 1] += 1;
+        for j in i..size {
+            indices[j] = indices[j - 1] + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::card::{CardPair, CardSuit, CardValue};
+    use rand::{rngs::StdRng, SeedableRng};
+    use CardSuit::{Clubs, Diamonds, Hearts, Spades};
+    use CardValue::{Ace, Eight, Jack, King, Queen, Ten, Three, Two};
+
+    fn card(value: CardValue, suit: CardSuit) -> Card {
+        Card::new(suit, value)
+    }
+
+    fn player(player_id: i32, card1: Card, card2: Card) -> PlayerCards {
+        PlayerCards {
+            player_id,
+            cards: Some(CardPair {
+                card1: Some(card1),
+                card2: Some(card2),
+            }),
+        }
+    }
+
+    fn aces() -> PlayerCards {
+        player(1, card(Ace, Spades), card(Ace, Hearts))
+    }
+
+    fn kings() -> PlayerCards {
+        player(2, card(King, Spades), card(King, Hearts))
+    }
+
+    fn request(players: Vec<PlayerCards>, board: Vec<Card>) -> EquityRequest {
+        EquityRequest {
+            players,
+            board,
+            dead_cards: Vec::new(),
+        }
+    }
+
+    fn calculate(request: &EquityRequest, limits: EquityLimits) -> EquityResponse {
+        calculate_equity_with_rng(request, &mut StdRng::seed_from_u64(1), limits).unwrap()
+    }
+
+    #[test]
+    fn every_runout_of_the_flop_is_counted() {
+        // kings win with one of the two kings left and no ace, nothing else beats the aces
+        let flop = vec![card(Two, Clubs), card(Three, Diamonds), card(Eight, Clubs)];
+        let response = calculate(&request(vec![aces(), kings()], flop), EquityLimits::REQUEST);
+
+        assert!(response.is_exact);
+        assert_eq!(response.runouts, 990);
+        let counts: Vec<(i64, i64)> = response
+            .equities
+            .iter()
+            .map(|e| {
+                (
+                    (e.win * 990.0).round() as i64,
+                    (e.tie * 990.0).round() as i64,
+                )
+            })
+            .collect();
+        assert_eq!(counts, vec![(907, 0), (83, 0)]);
+    }
+
+    #[test]
+    fn board_which_plays_splits_the_pot() {
+        let royal_flush = vec![
+            card(Ace, Clubs),
+            card(King, Clubs),
+            card(Queen, Clubs),
+            card(Jack, Clubs),
+            card(Ten, Clubs),
+        ];
+        let response = calculate(
+            &request(vec![aces(), kings()], royal_flush),
+            EquityLimits::REQUEST,
+        );
+
+        assert!(response.is_exact);
+        assert_eq!(response.runouts, 1);
+        for equity in &response.equities {
+            assert_eq!((equity.win, equity.tie), (0.0, 1.0));
+            assert_eq!(equity.win + equity.tie / 2.0, 0.5);
+        }
+    }
+
+    #[test]
+    fn preflop_is_sampled() {
+        let response = calculate(
+            &request(vec![aces(), kings()], Vec::new()),
+            EquityLimits::REQUEST,
+        );
+
+        assert!(!response.is_exact);
+        assert_eq!(response.runouts as u64, EquityLimits::REQUEST.samples);
+        let aces_win = response.equities[0].win;
+        assert!((0.80..=0.83).contains(&aces_win), "aces win {}", aces_win);
+    }
+
+    #[test]
+    fn limits_decide_between_enumeration_and_sampling() {
+        let flop = vec![card(Two, Clubs), card(Three, Diamonds), card(Eight, Clubs)];
+        let request = request(vec![aces(), kings()], flop);
+        let cases = [(990, 10, true, 990), (989, 10, false, 10)];
+
+        for (max_enumerated, samples, is_exact, runouts) in cases {
+            let limits = EquityLimits {
+                max_enumerated,
+                samples,
+            };
+            let response = calculate(&request, limits);
+            assert_eq!((response.is_exact, response.runouts), (is_exact, runouts));
+        }
+    }
+
+    #[test]
+    fn full_ring_gets_fewer_runouts() {
+        let deck: Vec<Card> = CardDeck::new_ordered().cards.into();
+        let players: Vec<PlayerCards> = (0..MAX_PLAYERS)
+            .map(|i| player(i as i32, deck[2 * i].clone(), deck[2 * i + 1].clone()))
+            .collect();
+        let response = calculate(&request(players, Vec::new()), EquityLimits::REQUEST);
+
+        assert!(!response.is_exact);
+        assert_eq!(
+            response.runouts as u64,
+            EquityLimits::REQUEST.samples * LIMITS_PLAYERS / MAX_PLAYERS as u64
+        );
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let flop = vec![card(Two, Clubs), card(Three, Diamonds), card(Eight, Clubs)];
+        let with_board = |board: Vec<Card>| request(vec![aces(), kings()], board);
+        let deck: Vec<Card> = CardDeck::new_ordered().cards.into();
+        let ten_players = || -> Vec<PlayerCards> {
+            (0..MAX_PLAYERS)
+                .map(|i| player(i as i32, deck[2 * i].clone(), deck[2 * i + 1].clone()))
+                .collect()
+        };
+        let mut eleven_players = ten_players();
+        eleven_players.push(player(11, deck[20].clone(), deck[21].clone()));
+        let mut deck_used_up = request(ten_players(), Vec::new());
+        deck_used_up.dead_cards = deck[20..48].to_vec();
+        let mut dead_card_on_board = with_board(flop.clone());
+        dead_card_on_board.dead_cards = vec![card(Eight, Clubs)];
+
+        let cases = [
+            (
+                request(vec![aces()], Vec::new()),
+                EquityError::WrongPlayersAmount(1),
+            ),
+            (
+                request(eleven_players, Vec::new()),
+                EquityError::WrongPlayersAmount(11),
+            ),
+            (
+                with_board(flop[..1].to_vec()),
+                EquityError::WrongBoardSize(1),
+            ),
+            (
+                with_board(flop[..2].to_vec()),
+                EquityError::WrongBoardSize(2),
+            ),
+            (
+                with_board(deck[30..36].to_vec()),
+                EquityError::WrongBoardSize(6),
+            ),
+            (
+                request(
+                    vec![
+                        aces(),
+                        PlayerCards {
+                            player_id: 2,
+                            cards: None,
+                        },
+                    ],
+                    Vec::new(),
+                ),
+                EquityError::MissingHoleCards { player_id: 2 },
+            ),
+            (
+                with_board(vec![
+                    Card { value: 13, suit: 0 },
+                    card(Two, Diamonds),
+                    card(Three, Diamonds),
+                ]),
+                EquityError::InvalidCard { value: 13, suit: 0 },
+            ),
+            (
+                with_board(vec![
+                    Card { value: 0, suit: -1 },
+                    card(Two, Diamonds),
+                    card(Three, Diamonds),
+                ]),
+                EquityError::InvalidCard { value: 0, suit: -1 },
+            ),
+            (
+                request(
+                    vec![aces(), player(2, card(Ace, Spades), card(King, Hearts))],
+                    Vec::new(),
+                ),
+                EquityError::DuplicateCard(card(Ace, Spades).to_string()),
+            ),
+            (
+                with_board(vec![
+                    card(Ace, Spades),
+                    card(Two, Diamonds),
+                    card(Three, Diamonds),
+                ]),
+                EquityError::DuplicateCard(card(Ace, Spades).to_string()),
+            ),
+            (
+                dead_card_on_board,
+                EquityError::DuplicateCard(card(Eight, Clubs).to_string()),
+            ),
+            (deck_used_up, EquityError::NotEnoughCards),
+        ];
+
+        for (request, expected) in cases {
+            let result = calculate_equity_with_rng(
+                &request,
+                &mut StdRng::seed_from_u64(1),
+                EquityLimits::TABLE,
+            );
+            assert_eq!(result.err(), Some(expected));
+        }
+    }
+}
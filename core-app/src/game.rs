@@ -1,42 +1,69 @@
 use std::{
     collections::HashMap,
-    sync::{
-        mpsc::{Receiver, Sender},
-        Arc, Mutex, RwLock,
-    },
+    mem,
+    sync::{mpsc::Sender, Arc},
+    time::{Duration, Instant},
 };
 
 use prost::Message;
+use rand::{rngs::StdRng, SeedableRng};
 
 use crate::{
     card::CardDeck,
-    dealer::Dealer,
+    dealer::public_view,
+    engine::{self, Command, EngineState, Event},
+    game_actor::GameMailbox,
+    game_orchestrator::StartGameError,
+    game_snapshot::{RecoveryPolicy, SnapshotStore},
+    hand_history::HandHistoryStore,
     protos::{
         client_state::ClientState,
-        game_state::{Action, ActionType, GameStatus, ShowdownOutcome, Street, StreetStatus},
+        equity::PlayerEquity,
+        game_snapshot::GameSnapshot,
+        game_state::{Action, GameStatus, PlayerCards, ShowdownOutcome, Street, StreetStatus},
+        hand_history::HandHistory,
         player::{Player, PlayerStatus},
-        requests::PlayerActionRequest,
-        responses::{GameOverMessage, ResponseMessageType},
+        requests::{PlayerActionRequest, ResyncRequest},
+        responses::{
+            ChatMessage, ChatRejectedMessage, GameOverMessage, ResponseMessage, ResponseMessageType,
+        },
     },
     responses::{
-        create_message_response, generate_client_state_responses, EncodableMessage,
-        GameChannelMessage, PlayerActionRequestError, SocketSourceMessage, TMessageResponse,
+        create_message_response, EncodableMessage, GameChannelMessage, SocketSourceMessage,
+        TMessageResponse,
     },
-    socket_pool::{ConnectionClosedEvent, ReadMessageError, SocketPool},
+    socket_pool::{ChatEvent, ConnectionClosedEvent, ReadMessageError, SocketPool},
+    spectator_pool::SpectatorPool,
+    state_delta::StateSync,
+    table_chat::{ChatError, TableChat},
     thread_pool::ThreadPool,
 };
 
 pub struct DeckState {
     pub deck: CardDeck,
+    // every shuffle and button draw of the table goes through this rng,
+    // so a seeded table replays exactly the same hands
+    pub rng: StdRng,
 }
 
 impl DeckState {
     pub fn new_random(&mut self) {
-        self.deck = CardDeck::new_random();
+        self.deck = CardDeck::new_shuffled(&mut self.rng);
     }
 
     pub fn new(deck: CardDeck) -> DeckState {
-        DeckState { deck }
+        DeckState {
+            deck,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn with_seed(seed: u64) -> DeckState {
+        let mut rng = StdRng::seed_from_u64(seed);
+        DeckState {
+            deck: CardDeck::new_shuffled(&mut rng),
+            rng,
+        }
     }
 }
 #[derive(Debug)]
@@ -68,6 +95,9 @@ pub struct GameState {
     pub biggest_bet_on_curr_street: i32,
     pub action_history: Vec<Action>,
     pub showdown_outcome: Option<ShowdownOutcome>,
+    // hands tabled during an all-in runout and their equity on the current street
+    pub revealed_cards: Vec<PlayerCards>,
+    pub equities: Vec<PlayerEquity>,
 }
 
 impl GameState {
@@ -86,6 +116,8 @@ impl GameState {
             positions: KeyPositions::new(),
             action_history: Vec::new(),
             showdown_outcome: None,
+            revealed_cards: Vec::new(),
+            equities: Vec::new(),
         }
     }
 }
@@ -93,6 +125,12 @@ pub struct PlayerState {
     pub players: Vec<Player>,
     pub bank_map: HashMap<i32, i32>,
 }
+impl Default for PlayerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PlayerState {
     pub fn new() -> PlayerState {
         PlayerState {
@@ -111,398 +149,957 @@ impl PlayerState {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct GameSettings {
     pub blind_size: i32,
+    pub starting_bank: i32,
+    // how long a player may think before the read gives up with Iddle
+    pub action_timeout: Duration,
+    // how long a disconnected player keeps the seat, the cards and the turn
+    pub reconnect_grace_period: Duration,
+    pub bot_url: String,
+}
+
+// how a session of the table ended
+pub enum SessionEnd {
+    Stopped,
+    // server is going down, the sender is answered once the game is persisted
+    ServerShutdown(Sender<()>),
+}
+
+#[derive(Default)]
+struct Session {
+    // player whose decision is being read right now
+    awaited_player: Option<i32>,
+    // server shutdown: the current hand is the last one, unless it's not finished by the deadline
+    shutdown: Option<(Instant, Sender<()>)>,
+}
+
+// what a running session needs from its actor
+pub struct SessionContext<'a> {
+    pub socket_pool: &'a Arc<SocketPool>,
+    pub thread_pool: &'a Arc<ThreadPool>,
+    // socket reads and bot answers come back through the inbox
+    pub mailbox: &'a GameMailbox,
 }
 
 pub struct Game {
-    dealer: Dealer,
-    game_state: GameState,
-    deck_state: DeckState,
-    player_state: PlayerState,
+    state: EngineState,
     lobby_id: i32,
+    settings: GameSettings,
+    snapshot_store: Option<Arc<dyn SnapshotStore>>,
+    hand_store: Option<Arc<dyn HandHistoryStore>>,
+    state_sync: StateSync,
+    spectators: Arc<SpectatorPool>,
+    // shared with the orchestrator, which hands out the history to new players and spectators
+    chat: Arc<TableChat>,
+    // players who lost connection, the engine learns about them only when grace period expires
+    disconnected_at: HashMap<i32, Instant>,
+    session: Option<Session>,
 }
 
 impl Game {
-    pub fn new(lobby_id: i32, settings: GameSettings) -> Self {
+    pub fn new(
+        lobby_id: i32,
+        settings: GameSettings,
+        snapshot_store: Option<Arc<dyn SnapshotStore>>,
+        hand_store: Option<Arc<dyn HandHistoryStore>>,
+        spectators: Arc<SpectatorPool>,
+        chat: Arc<TableChat>,
+    ) -> Self {
         Game {
-            dealer: Dealer::new(lobby_id),
-            deck_state: DeckState::new(CardDeck::new_random()),
-            game_state: GameState::new(settings.blind_size),
-            player_state: PlayerState::new(),
+            state: EngineState::new(lobby_id, settings.blind_size),
             lobby_id,
+            settings,
+            snapshot_store,
+            hand_store,
+            state_sync: StateSync::new(lobby_id),
+            spectators,
+            chat,
+            disconnected_at: HashMap::new(),
+            session: None,
         }
     }
-    pub fn get_game_status(&self) -> GameStatus {
-        self.game_state.status
-    }
 
-    fn get_player(&mut self, user_id: &i32) -> Option<&mut Player> {
-        self.player_state
+    // blind of the restored table comes from the snapshot, the rest from settings
+    pub fn from_snapshot(
+        snapshot: GameSnapshot,
+        settings: GameSettings,
+        snapshot_store: Option<Arc<dyn SnapshotStore>>,
+        hand_store: Option<Arc<dyn HandHistoryStore>>,
+        spectators: Arc<SpectatorPool>,
+        chat: Arc<TableChat>,
+    ) -> Self {
+        let lobby_id = snapshot.lobby_id;
+        let policy = snapshot.recovery_policy();
+        let (game_state, player_state, deck_state) = snapshot.into_states(policy);
+
+        let mut game = Game {
+            state: EngineState {
+                lobby_id,
+                game_state,
+                player_state,
+                deck_state,
+            },
+            lobby_id,
+            settings,
+            snapshot_store,
+            hand_store,
+            state_sync: StateSync::new(lobby_id),
+            spectators,
+            chat,
+            disconnected_at: HashMap::new(),
+            session: None,
+        };
+
+        // nobody has a socket after restart, players come back through join_lobby
+        game.state
+            .player_state
             .players
             .iter_mut()
-            .find(|p| p.user_id == *user_id)
+            .filter(|p| !p.is_bot)
+            .for_each(|p| p.status = PlayerStatus::Disconnected.into());
+
+        println!(
+            "game {} restored from snapshot with {:?} policy",
+            lobby_id, policy
+        );
+
+        game.persist_snapshot();
+        game
     }
 
-    pub fn is_ready_to_start(&self) -> bool {
-        // TODO: improve checking in case of player game status is not ready
-        self.game_state.status != GameStatus::Active && self.player_state.players.len() > 1
+    pub fn get_game_status(&self) -> GameStatus {
+        self.state.game_state.status
     }
 
-    pub fn hande_connection_update(
-        &mut self,
-        event: &ConnectionClosedEvent,
-        socket_pool: &Arc<SocketPool>,
-    ) -> bool {
-        let player = self.get_player(&event.user_id).unwrap();
+    // the finished hand is still in the state until the next one is dealt
+    fn record_hand(&self) {
+        if let Some(store) = &self.hand_store {
+            store.save_hand(&HandHistory::new(
+                self.lobby_id,
+                &self.state.game_state,
+                &self.state.player_state,
+            ));
+        }
+    }
+
+    pub fn persist_snapshot(&self) {
+        if let Some(store) = &self.snapshot_store {
+            store.save_snapshot(&GameSnapshot::new(
+                self.lobby_id,
+                &self.state.game_state,
+                &self.state.player_state,
+                &self.state.deck_state,
+            ));
+        }
+    }
+
+    // Called after the game loop panicked, when the in-memory state can be half updated,
+    // and on shutdown which can't wait for the hand: the last persisted snapshot is used
+    // to refund the interrupted hand
+    pub fn refund_current_hand(&mut self, socket_pool: &Arc<SocketPool>) {
+        let snapshot = self
+            .snapshot_store
+            .as_ref()
+            .and_then(|store| store.load_snapshot(self.lobby_id))
+            .unwrap_or_else(|| {
+                GameSnapshot::new(
+                    self.lobby_id,
+                    &self.state.game_state,
+                    &self.state.player_state,
+                    &self.state.deck_state,
+                )
+            });
+
+        let (game_state, player_state, deck_state) = snapshot.into_states(RecoveryPolicy::Refund);
+
+        // keep connection statuses from memory, they are more recent than the snapshot
+        let statuses: HashMap<i32, i32> = self
+            .state
+            .player_state
+            .players
+            .iter()
+            .map(|p| (p.user_id, p.status))
+            .collect();
+
+        self.state.game_state = game_state;
+        self.state.player_state = player_state;
+        self.state.deck_state = deck_state;
+
+        self.state.player_state.players.iter_mut().for_each(|p| {
+            if statuses.get(&p.user_id) == Some(&(PlayerStatus::Disconnected as i32)) {
+                p.status = PlayerStatus::Disconnected.into();
+            }
+        });
 
-        player.set_status(PlayerStatus::Disconnected);
+        self.persist_snapshot();
 
-        let states = self
-            .dealer
-            .get_client_states(&self.game_state, &self.player_state);
+        // clients can't apply deltas on top of a rolled back state
+        self.state_sync.forget_all();
+        self.spectators.broadcast(self.state.public_client_state());
+        let responses = self.state_sync.create_responses(self.state.client_states());
+        socket_pool.update_table_clients(self.lobby_id, responses);
+    }
 
-        // TODO: add hash sum for clientstate to check if client received current state or not
-        socket_pool.update_clients(generate_client_state_responses(states));
+    pub fn is_ready_to_start(&self) -> bool {
+        self.state.is_ready_to_start()
+    }
+
+    pub fn hande_connection_update(&mut self, event: &ConnectionClosedEvent) -> bool {
+        // game loop is not running, expired grace periods are handled once it starts
+        self.start_grace_period(event.user_id);
 
         true
     }
 
-    pub fn add_player(&mut self, mut player: Player, socket_pool: &Arc<SocketPool>) {
-        let user_id = player.user_id;
+    // Also the way back for disconnected players: within grace period they get
+    // the same seat, cards and turn
+    pub fn add_player(&mut self, player: Player, socket_pool: &Arc<SocketPool>) -> Vec<Event> {
+        if self.disconnected_at.remove(&player.user_id).is_some() {
+            println!(
+                "game {}: player {} reconnected",
+                self.lobby_id, player.user_id
+            );
+        }
+        // joining player gets a full state, everybody else a delta with the new seat
+        self.state_sync.forget(player.user_id);
+        self.dispatch(Command::Join(player), socket_pool)
+    }
 
-        // TODO: refactor ....
-        match self
+    fn start_grace_period(&mut self, user_id: i32) {
+        let is_seated = self
+            .state
             .player_state
             .players
-            .iter_mut()
-            .find(|p| p.user_id == user_id)
-        {
-            Some(p) => {
-                if self.game_state.status == GameStatus::Active {
-                    p.status = PlayerStatus::Ready.into();
-                    let mut action = Action::default();
-                    action.set_action_type(ActionType::Fold);
-                    p.action = Some(action);
-                } else {
-                    p.status = PlayerStatus::WaitingForPlayers.into();
-                }
-            }
-            None => {
-                if self.game_state.status == GameStatus::Active {
-                    player.status = PlayerStatus::Ready.into();
-                    let mut action = Action::default();
-                    action.set_action_type(ActionType::Fold);
-                    player.action = Some(action);
-                }
-                self.player_state.players.push(player);
-            }
+            .iter()
+            .any(|p| p.user_id == user_id && p.status() != PlayerStatus::Disconnected);
+
+        if is_seated {
+            self.state_sync.forget(user_id);
+            self.disconnected_at
+                .entry(user_id)
+                .or_insert_with(Instant::now);
         }
-        let states = self
-            .dealer
-            .get_client_states(&self.game_state, &self.player_state);
+    }
+
+    fn grace_deadline(&self, user_id: i32) -> Option<Instant> {
+        self.disconnected_at
+            .get(&user_id)
+            .map(|at| *at + self.settings.reconnect_grace_period)
+    }
+
+    fn expire_grace_periods(&mut self, socket_pool: &Arc<SocketPool>) -> Vec<Event> {
+        let now = Instant::now();
+        let grace_period = self.settings.reconnect_grace_period;
+        let expired: Vec<i32> = self
+            .disconnected_at
+            .iter()
+            .filter(|(_, at)| now.duration_since(**at) >= grace_period)
+            .map(|(user_id, _)| *user_id)
+            .collect();
 
-        // TODO: add hash sum for clientstate to check if client received current state or not
-        socket_pool.update_clients(generate_client_state_responses(states));
+        let mut events = Vec::new();
+        for user_id in expired {
+            self.disconnected_at.remove(&user_id);
+            events.extend(self.dispatch(Command::Disconnect { player_id: user_id }, socket_pool));
+        }
+        events
     }
 
-    fn process_elimated_players(&mut self, socket_pool: &Arc<SocketPool>) {
-        let players: &mut Vec<Player> = &mut self.player_state.players;
+    // Only players who still hold a seat and spectators of the table may chat,
+    // whoever left or was eliminated keeps the socket until it closes but can't post anymore
+    pub fn post_chat(&self, event: ChatEvent, socket_pool: &Arc<SocketPool>) {
+        let user_id = event.key.user_id;
+        let is_seated = self.state.player_state.players.iter().any(|p| {
+            p.user_id == user_id
+                && !matches!(
+                    p.status(),
+                    PlayerStatus::Disconnected | PlayerStatus::Eliminated
+                )
+        });
+        let may_post = if event.key.spectator {
+            self.spectators.contains(user_id)
+        } else {
+            is_seated
+        };
 
-        let (retained, removed): (Vec<Player>, Vec<Player>) = players
-            .drain(..)
-            .partition(|p| p.status() != PlayerStatus::Eliminated);
-        self.player_state.players = retained;
+        let result = if may_post {
+            self.chat.post(user_id, &event.text, event.key.spectator)
+        } else {
+            Err(ChatError::NotAtTable)
+        };
 
-        let messages: Vec<TMessageResponse> = removed
-            .iter()
-            .filter_map(|p| {
-                // TODO: Add messages in message queue for disconnected players
-                if p.status() == PlayerStatus::Disconnected {
-                    None
-                } else {
-                    return Some(create_message_response(
-                        GameOverMessage {
-                            user_id: p.user_id,
-                            reason: String::from("Not enough funds to continue"),
+        match result {
+            Ok(message) => self.deliver_chat(message, socket_pool),
+            Err(e) => {
+                println!("chat message of {} rejected: {}", event.key, e);
+                let rejected = ChatRejectedMessage {
+                    lobby_id: self.lobby_id,
+                    reason: e.to_string(),
+                };
+                if event.key.spectator {
+                    socket_pool.update_spectator(
+                        user_id,
+                        self.lobby_id,
+                        ResponseMessage {
+                            payload: rejected.encode_message(),
+                            payload_type: ResponseMessageType::ChatRejected.into(),
                         },
-                        ResponseMessageType::GameOver,
-                        p.user_id,
-                    ));
+                    );
+                } else {
+                    socket_pool.update_table_clients(
+                        self.lobby_id,
+                        vec![create_message_response(
+                            rejected,
+                            ResponseMessageType::ChatRejected,
+                            user_id,
+                        )],
+                    );
                 }
+            }
+        }
+    }
+
+    // Chat goes to every connected player at the table and, with the usual delay, to spectators
+    fn deliver_chat(&self, message: ChatMessage, socket_pool: &Arc<SocketPool>) {
+        let responses = self
+            .state
+            .player_state
+            .players
+            .iter()
+            .filter(|p| {
+                !p.is_bot
+                    && p.status() != PlayerStatus::Disconnected
+                    && !self.disconnected_at.contains_key(&p.user_id)
             })
+            .map(|p| create_message_response(message.clone(), ResponseMessageType::Chat, p.user_id))
             .collect();
+        socket_pool.update_table_clients(self.lobby_id, responses);
 
-        socket_pool.update_clients(messages);
+        self.spectators.broadcast_message(ResponseMessage {
+            payload: message.encode_message(),
+            payload_type: ResponseMessageType::Chat.into(),
+        });
     }
 
-    fn process_disconnected_players(&mut self) {
-        let players: &mut Vec<Player> = &mut self.player_state.players;
+    // Client has missed a delta or its state hash doesn't match, send the whole state again
+    pub fn resync_player(&mut self, request: &ResyncRequest, socket_pool: &Arc<SocketPool>) {
+        let is_seated = self
+            .state
+            .player_state
+            .players
+            .iter()
+            .any(|p| p.user_id == request.player_id && !p.is_bot);
+
+        if !is_seated {
+            println!(
+                "game {}: resync requested by player {} who is not seated",
+                self.lobby_id, request.player_id
+            );
+            return;
+        }
 
-        let (retained, removed): (Vec<Player>, Vec<Player>) = players
-            .drain(..)
-            .partition(|p| p.status() != PlayerStatus::Disconnected);
+        println!(
+            "game {}: resync player {} from sequence {} to {}",
+            self.lobby_id,
+            request.player_id,
+            request.last_sequence,
+            self.state_sync.sequence()
+        );
 
-        // TODO: provide disconnect event for other players?
-        self.player_state.players = retained;
+        let client_state = self.state.client_state(request.player_id);
+        let response = self.state_sync.create_full_state_response(client_state);
+        socket_pool.update_table_clients(self.lobby_id, vec![response]);
     }
 
+    // Runs command through the engine and delivers produced events to the clients
+    fn dispatch(&mut self, command: Command, socket_pool: &Arc<SocketPool>) -> Vec<Event> {
+        let state = mem::take(&mut self.state);
+        let (state, events) = engine::apply(state, command);
+        self.state = state;
+
+        if events.iter().any(|e| matches!(e, Event::HandFinished)) {
+            self.record_hand();
+        }
 
-    fn prepare_to_game_stop(&mut self) {
-        // TODO: handle settings struct;
-        let blind_size = self.game_state.big_blind;
+        socket_pool.update_table_clients(self.lobby_id, self.create_responses(&events));
 
-        self.deck_state = DeckState::new(CardDeck::new_random());
-        self.game_state = GameState::new(blind_size);
-        self.player_state.players.iter_mut().for_each(|p|{
-            p.action = None;
-            p.bet_in_current_seed = 0;
-            p.cards = None;
-            if p.status() == PlayerStatus::Ready {
-                p.status = PlayerStatus::WaitingForPlayers.into();
+        match events.iter().find_map(|e| match e {
+            Event::GameStopped { status } => Some(*status),
+            _ => None,
+        }) {
+            Some(GameStatus::None) => {
+                if let Some(store) = &self.snapshot_store {
+                    store.remove_snapshot(self.lobby_id);
+                }
             }
-        })
+            _ => self.persist_snapshot(),
+        }
+
+        events
     }
 
-    fn update_game_state(
-        &mut self,
-        socket_pool: &Arc<SocketPool>,
-        action: Result<PlayerActionRequest, PlayerActionRequestError>,
-    ) -> GameStatus {
-        let mut updated_state = self.dealer.update_game_state(
-            action,
-            &mut self.game_state,
-            &mut self.player_state,
-            &mut self.deck_state,
-        );
+    fn create_responses(&mut self, events: &[Event]) -> Vec<TMessageResponse> {
+        let mut responses = Vec::new();
 
-        socket_pool.update_clients(generate_client_state_responses(updated_state.client_states));
-
-        'a: loop {
-            if updated_state.is_ready_for_next_hand {
-                // WARN: locally tested: sometimes client is responding with pong right before disconnecting 
-                // that leads to additional game cycle for disconnected player
-                self.verify_connections(socket_pool);
-                self.process_elimated_players(&socket_pool);
-                self.process_disconnected_players();
-                
-                let players_count = self.player_state.players.len();
-
-                if players_count == 0 {
-                    self.prepare_to_game_stop();
-                    self.game_state.status = GameStatus::None;
-                    return GameStatus::None;
+        for event in events {
+            match event {
+                Event::StateChanged(states) => {
+                    let public_state = match states.first() {
+                        Some(state) => public_view(state.clone()),
+                        None => self.state.public_client_state(),
+                    };
+                    self.spectators.broadcast(public_state);
+                    responses.extend(self.state_sync.create_responses(states.clone()))
+                }
+                Event::PlayerEliminated { player_id } => {
+                    // disconnected players get it from the offline queue of socket pool on reconnect
+                    responses.push(create_message_response(
+                        GameOverMessage {
+                            user_id: *player_id,
+                            reason: String::from("Not enough funds to continue"),
+                        },
+                        ResponseMessageType::GameOver,
+                        *player_id,
+                    ))
                 }
-                let active_players: Vec<&Player> = self.player_state.players.iter().filter(|p| {
-                    // can be sitouted players and we wanna pause game in such case
-                    p.status() == PlayerStatus::Ready
-                }).collect();
-
-                // TODO: handle all possible cases
-                if active_players.len() < 2 {
-                    self.prepare_to_game_stop();
-                    self.game_state.status = GameStatus::WaitingForPlayers;
-                    let states = self
-                        .dealer
-                        .get_client_states(&mut self.game_state, &mut self.player_state);
-                    socket_pool.update_clients(generate_client_state_responses(states));
-                    return GameStatus::WaitingForPlayers;
+                Event::CommandRejected { reason } => {
+                    println!("game {}: command rejected: {}", self.lobby_id, reason)
                 }
-
-                updated_state = self.dealer.setup_next_cycle(
-                    &mut self.game_state,
-                    &mut self.player_state,
-                    &mut self.deck_state,
-                );
-
-                socket_pool
-                    .update_clients(generate_client_state_responses(updated_state.client_states));
-                continue 'a;
-            } else if updated_state.should_complete_game_cycle_automatically {
-                updated_state = self.dealer.complete_game_cycle_automatically(
-                    &mut self.game_state,
-                    &mut self.player_state,
-                    &mut self.deck_state,
-                );
-                socket_pool
-                    .update_clients(generate_client_state_responses(updated_state.client_states));
-                continue 'a;
+                Event::AwaitingAction { .. } | Event::HandFinished | Event::GameStopped { .. } => {}
             }
-            break;
         }
 
-        return GameStatus::Active;
+        responses
     }
 
     fn verify_connections(&mut self, socket_pool: &Arc<SocketPool>) {
-        for p in  self.player_state.players.iter_mut() {
-            // TODO: optimize by batching
-            let connected = socket_pool.check_connection_health(p.user_id);
+        let user_ids: Vec<i32> = self
+            .state
+            .player_state
+            .players
+            .iter()
+            .filter(|p| !p.is_bot && p.status() != PlayerStatus::Disconnected)
+            .map(|p| p.user_id)
+            .collect();
+
+        for user_id in user_ids {
+            let connected = socket_pool.check_connection_health(user_id, self.lobby_id);
 
             if !connected {
-                p.status = PlayerStatus::Disconnected.into();
+                self.start_grace_period(user_id);
             }
         }
     }
 
-    pub fn run(
-        &mut self,
-        socket_pool: Arc<SocketPool>,
-        thread_pool: Arc<ThreadPool>,
-        rx: Arc<Mutex<Receiver<GameChannelMessage>>>,
-        tx: Arc<RwLock<Sender<GameChannelMessage>>>,
-    ) -> Result<(), &str> {
-        self.verify_connections(&socket_pool);
-        self.process_disconnected_players();
-
-        let players_count = self.player_state.players.len();
-
-        if players_count < 2 {
-            return Err("Not enough players to start a new game");
-        }
-        // TODO: think about merging it with start_next_cylce function
-        let game_states: Vec<ClientState> = self
-            .dealer
-            .start_new_game(
-                &mut self.game_state,
-                &mut self.player_state,
-                &mut self.deck_state,
-            )
-            .unwrap();
+    fn request_bot_action(
+        &self,
+        user_id: i32,
+        thread_pool: &Arc<ThreadPool>,
+        mailbox: &GameMailbox,
+    ) {
+        let lobby_id = self.lobby_id;
+        let mailbox = mailbox.clone();
+        let client_state = self.state.client_state(user_id);
+        let bot_url = self.settings.bot_url.clone();
 
-        socket_pool.update_clients(generate_client_state_responses(game_states));
+        thread_pool.execute(move || {
+            let response = ureq::post(&bot_url)
+                .set("Content-Type", "application/json")
+                .send_bytes(&ClientState::encode_message(&client_state));
 
-        'outer_loop: loop {
-            // TODO: refactor
-            let user_id = self
-                .dealer
-                .get_next_player_id(&mut self.game_state, &mut self.player_state);
-            let player = self
-                .dealer
-                .get_next_player(&mut self.game_state, &mut self.player_state);
-            let is_bot = player.is_bot;
+            match response {
+                Ok(response) => {
+                    if response.status() == 200 {
+                        println!("Response: {:?}", response);
 
-            if is_bot {
-                let lobby_id = self.lobby_id;
-                let id = player.user_id;
-                let clone_tx: Arc<RwLock<Sender<GameChannelMessage>>> = Arc::clone(&tx);
-                let client_state =
-                    self.dealer
-                        .get_client_state(&id, &mut self.game_state, &mut self.player_state);
-                let llama_bot_api_url = "http://127.0.0.1:5000/pocker_move";
-
-                thread_pool.execute(move || {
-                    let response = ureq::post(llama_bot_api_url)
-                        .set("Content-Type", "application/json")
-                        .send_bytes(&ClientState::encode_message(&client_state));
-
-                    match response {
-                        Ok(response) => {
-                            if response.status() == 200 {
-                                println!("Response: {:?}", response);
-
-                                let mut buf: Vec<u8> = Vec::new();
-                                response.into_reader().read_to_end(&mut buf).unwrap();
-
-                                let mut reader = std::io::Cursor::new(buf);
-
-                                let mut action = Action::default();
-                                action.merge(&mut reader).unwrap();
-
-                                println!("Bot message sent successfully: {:?}", action);
-                                let mut payload = PlayerActionRequest::default();
-                                payload.action = Some(action);
-                                payload.lobby_id = lobby_id;
-                                payload.player_id = id;
-                                clone_tx
-                                    .read()
-                                    .unwrap()
-                                    .send(GameChannelMessage::InnerSource(payload))
-                                    .unwrap();
-                            } else {
-                                eprintln!("Failed to send bot message: {:?}", response.status());
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Error sending bot message: {:?}", e);
+                        let mut buf: Vec<u8> = Vec::new();
+                        response.into_reader().read_to_end(&mut buf).unwrap();
+
+                        let mut reader = std::io::Cursor::new(buf);
+
+                        let mut action = Action::default();
+                        action.merge(&mut reader).unwrap();
+
+                        println!("Bot message sent successfully: {:?}", action);
+                        let payload = PlayerActionRequest {
+                            action: Some(action),
+                            lobby_id,
+                            player_id: user_id,
+                        };
+                        if !mailbox.send(GameChannelMessage::InnerSource(payload)) {
+                            println!("game {} is over, bot action dropped", lobby_id);
                         }
+                    } else {
+                        eprintln!("Failed to send bot message: {:?}", response.status());
                     }
-                });
-            } else {
-                let clone_s_pool = Arc::clone(&socket_pool);
-                let clone_tx: Arc<RwLock<Sender<GameChannelMessage>>> = Arc::clone(&tx);
-
-                thread_pool.execute(move || {
-                    let result: Result<PlayerActionRequest, ReadMessageError> =
-                        clone_s_pool.read_client_message::<PlayerActionRequest>(user_id);
-                    clone_tx
-                        .read()
-                        .unwrap()
-                        .send(GameChannelMessage::SocketSource(
-                            SocketSourceMessage::PlayerActionRequest(result),
-                        ))
-                        .unwrap();
-                });
+                }
+                Err(e) => {
+                    eprintln!("Error sending bot message: {:?}", e);
+                }
             }
+        });
+    }
+
+    // no thread waits for the answer, the socket reactor calls back when it arrives
+    fn request_player_action(
+        &self,
+        user_id: i32,
+        socket_pool: &Arc<SocketPool>,
+        mailbox: &GameMailbox,
+    ) {
+        let lobby_id = self.lobby_id;
+        let mailbox = mailbox.clone();
+
+        socket_pool.read_client_message(
+            user_id,
+            lobby_id,
+            self.settings.action_timeout,
+            move |result: Result<PlayerActionRequest, ReadMessageError>| {
+                let message =
+                    GameChannelMessage::SocketSource(SocketSourceMessage::PlayerActionRequest {
+                        user_id,
+                        result,
+                    });
+                if !mailbox.send(message) {
+                    println!("game {} is over, action of {} dropped", lobby_id, user_id);
+                }
+            },
+        );
+    }
+
+    // One session of the table lasts from StartGame until the game stops. The actor hands
+    // every message of its inbox to the session while it runs, see GameActor
+    pub fn start_session(
+        &mut self,
+        ctx: &SessionContext,
+    ) -> Result<Option<SessionEnd>, &'static str> {
+        if self.state.game_state.status != GameStatus::Pause {
+            self.verify_connections(ctx.socket_pool);
+        }
+        self.expire_grace_periods(ctx.socket_pool);
+
+        let events = self.dispatch(Command::StartGame, ctx.socket_pool);
+
+        if let Some(reason) = events.iter().find_map(|e| match e {
+            Event::CommandRejected { reason } => Some(*reason),
+            _ => None,
+        }) {
+            return Err(reason);
+        }
 
-            loop {
-                let message = rx.lock().unwrap().recv().unwrap();
-                match message {
-                    GameChannelMessage::SocketSource(r) => match r {
-                        SocketSourceMessage::PlayerActionRequest(p) => match p {
-                            Ok(m) => {
-                                let game_status = self.update_game_state(&socket_pool, Ok(m));
-                                if game_status == GameStatus::WaitingForPlayers || game_status == GameStatus::None  {
-                                    break 'outer_loop;
-                                } else {
-                                    continue 'outer_loop;
-                                }
+        self.session = Some(Session::default());
+        Ok(self.advance(events, ctx))
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.session.is_some()
+    }
+
+    // when the actor has to wake the session up: a grace period or the shutdown deadline
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let session = self.session.as_ref()?;
+        self.disconnected_at
+            .keys()
+            .filter_map(|user_id| self.grace_deadline(*user_id))
+            .chain(session.shutdown.as_ref().map(|(deadline, _)| *deadline))
+            .min()
+    }
+
+    pub fn handle_session_message(
+        &mut self,
+        message: GameChannelMessage,
+        ctx: &SessionContext,
+    ) -> Option<SessionEnd> {
+        let command = match message {
+            GameChannelMessage::Start(reply) => {
+                let _ = reply.send(Err(StartGameError::AlreadyRunning));
+                return None;
+            }
+            // a running game doesn't need to be started
+            GameChannelMessage::IsReadyToStart(reply) => {
+                let _ = reply.send(false);
+                return None;
+            }
+            GameChannelMessage::Shutdown { deadline, reply } => {
+                self.session.as_mut()?.shutdown = Some((deadline, reply));
+                return None;
+            }
+            GameChannelMessage::Tick => return self.handle_tick(ctx),
+            GameChannelMessage::Resync(request) => {
+                self.resync_player(&request, ctx.socket_pool);
+                return None;
+            }
+            GameChannelMessage::Chat(event) => {
+                self.post_chat(event, ctx.socket_pool);
+                return None;
+            }
+            GameChannelMessage::SocketSource(r) => match r {
+                SocketSourceMessage::PlayerActionRequest { user_id, result } => {
+                    self.clear_awaited(user_id);
+                    match result {
+                        // the seat of the connection acts, whatever id the client put in the payload
+                        Ok(mut m) => {
+                            m.player_id = user_id;
+                            if let Some(action) = m.action.as_mut() {
+                                action.player_id = user_id;
                             }
-                            Err(e) => {
-                                let error: PlayerActionRequestError = match e {
-                                    ReadMessageError::Disconnected => {
-                                        PlayerActionRequestError::Disconnected {
-                                            id: self.dealer.get_next_player_id(
-                                                &mut self.game_state,
-                                                &mut self.player_state,
-                                            ),
-                                            lobby_id: self.lobby_id,
-                                        }
-                                    }
-                                    ReadMessageError::Iddle => PlayerActionRequestError::Iddle {
-                                        id: self.dealer.get_next_player_id(
-                                            &mut self.game_state,
-                                            &mut self.player_state,
-                                        ),
-                                        lobby_id: self.lobby_id,
-                                    },
-                                };
-                                let game_status = self.update_game_state(&socket_pool, Err(error));
-                                if game_status == GameStatus::WaitingForPlayers || game_status == GameStatus::None  {
-                                    break 'outer_loop;
-                                } else {
-                                    continue 'outer_loop;
-                                }
+                            Command::Action(m)
+                        }
+                        Err(ReadMessageError::Disconnected) => {
+                            self.start_grace_period(user_id);
+                            if self.grace_deadline(user_id).is_some() {
+                                return self.advance(Vec::new(), ctx);
                             }
-                        },
-                        SocketSourceMessage::ConnectionClosed(e) => {
-                            self.hande_connection_update(&e, &socket_pool);
+                            // already disconnected, e.g. restored from snapshot and not back yet:
+                            // no grace period to wait for, the turn is folded
+                            Command::Disconnect { player_id: user_id }
                         }
-                    },
-                    GameChannelMessage::HttpRequestSource(r) => {
-                        self.add_player(r.player, &socket_pool)
-                    }
-                    GameChannelMessage::InnerSource(m) => {
-                        let game_status = self.update_game_state(&socket_pool, Ok(m));
-                        if game_status == GameStatus::WaitingForPlayers || game_status == GameStatus::None  {
-                            break 'outer_loop;
-                        } else {
-                            continue 'outer_loop;
+                        // garbage instead of a decision counts as no decision
+                        Err(ReadMessageError::Iddle) | Err(ReadMessageError::Malformed) => {
+                            Command::Timeout { player_id: user_id }
                         }
                     }
-                };
+                }
+                SocketSourceMessage::ConnectionClosed(e) => {
+                    self.start_grace_period(e.user_id);
+                    return self.advance(Vec::new(), ctx);
+                }
+            },
+            GameChannelMessage::HttpRequestSource(r) => {
+                let events = self.add_player(r.player, ctx.socket_pool);
+                return self.advance(events, ctx);
+            }
+            GameChannelMessage::InnerSource(m) => {
+                self.clear_awaited(m.player_id);
+                Command::Action(m)
+            }
+        };
+
+        let events = self.dispatch(command, ctx.socket_pool);
+        self.advance(events, ctx)
+    }
+
+    // Called after the session panicked: the hand is refunded and the session is over
+    pub fn abort_session(&mut self, socket_pool: &Arc<SocketPool>) {
+        self.session = None;
+        self.refund_current_hand(socket_pool);
+    }
+
+    fn handle_tick(&mut self, ctx: &SessionContext) -> Option<SessionEnd> {
+        let shutdown_is_due = self
+            .session
+            .as_ref()?
+            .shutdown
+            .as_ref()
+            .is_some_and(|(deadline, _)| *deadline <= Instant::now());
+        if shutdown_is_due {
+            println!(
+                "game {}: hand is not finished before shutdown, refunding",
+                self.lobby_id
+            );
+            self.refund_current_hand(ctx.socket_pool);
+            let (_, reply) = self.session.take()?.shutdown?;
+            return Some(SessionEnd::ServerShutdown(reply));
+        }
+
+        let events = self.expire_grace_periods(ctx.socket_pool);
+        self.advance(events, ctx)
+    }
+
+    fn clear_awaited(&mut self, user_id: i32) {
+        if let Some(session) = self.session.as_mut() {
+            if session.awaited_player == Some(user_id) {
+                session.awaited_player = None;
             }
         }
-        return Ok(());
+    }
+
+    // Moves the session on after a command: deals the next hand, ends the session
+    // or asks the player on turn for a decision
+    fn advance(&mut self, mut events: Vec<Event>, ctx: &SessionContext) -> Option<SessionEnd> {
+        while events.iter().any(|e| matches!(e, Event::HandFinished)) {
+            // stacks after the hand are already persisted by dispatch
+            if let Some((_, reply)) = self.session.as_mut()?.shutdown.take() {
+                self.session = None;
+                return Some(SessionEnd::ServerShutdown(reply));
+            }
+            // WARN: locally tested: sometimes client is responding with pong right before disconnecting
+            // that leads to additional game cycle for disconnected player
+            self.verify_connections(ctx.socket_pool);
+            self.expire_grace_periods(ctx.socket_pool);
+            events = self.dispatch(Command::NextHand, ctx.socket_pool);
+        }
+
+        if events
+            .iter()
+            .any(|e| matches!(e, Event::GameStopped { .. }))
+        {
+            return Some(match self.session.take()?.shutdown {
+                Some((_, reply)) => SessionEnd::ServerShutdown(reply),
+                None => SessionEnd::Stopped,
+            });
+        }
+
+        // several commands may leave the same player on turn, their pending read is reused
+        let (player_id, is_bot) = self.state.current_player().map(|p| (p.user_id, p.is_bot))?;
+
+        // player without connection can't be read, the turn waits for reconnect or grace period end
+        let awaited_player = self.session.as_ref()?.awaited_player;
+        if awaited_player != Some(player_id) && self.grace_deadline(player_id).is_none() {
+            self.session.as_mut()?.awaited_player = Some(player_id);
+            if is_bot {
+                self.request_bot_action(player_id, ctx.thread_pool, ctx.mailbox);
+            } else {
+                self.request_player_action(player_id, ctx.socket_pool, ctx.mailbox);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game_actor::ActorRuntime, memory_repository::InMemoryRepository,
+        protos::game_state::ActionType, repository::Repository, socket_pool::ConnectionKey,
+        table_chat::AllowAll,
+    };
+    use std::sync::mpsc;
+
+    const LOBBY_ID: i32 = 1;
+
+    fn settings() -> GameSettings {
+        GameSettings {
+            blind_size: 20,
+            starting_bank: 1000,
+            action_timeout: Duration::from_secs(1),
+            reconnect_grace_period: Duration::from_secs(60),
+            bot_url: String::new(),
+        }
+    }
+
+    fn chat() -> Arc<TableChat> {
+        Arc::new(TableChat::new(LOBBY_ID, Arc::new(AllowAll)))
+    }
+
+    fn chat_event(user_id: i32, text: &str) -> ChatEvent {
+        ChatEvent {
+            key: ConnectionKey::table(user_id, LOBBY_ID),
+            text: String::from(text),
+        }
+    }
+
+    fn interrupted_hand() -> GameSnapshot {
+        let mut state = EngineState::with_seed(LOBBY_ID, 20, 3);
+        for user_id in 1..=2 {
+            let player = Player {
+                user_id,
+                bank: 1000,
+                ..Default::default()
+            };
+            (state, _) = engine::apply(state, Command::Join(player));
+        }
+        (state, _) = engine::apply(state, Command::StartGame);
+
+        GameSnapshot::new(
+            LOBBY_ID,
+            &state.game_state,
+            &state.player_state,
+            &state.deck_state,
+        )
+    }
+
+    #[test]
+    fn absent_player_of_restored_hand_is_folded() {
+        let snapshot = interrupted_hand();
+        assert_eq!(snapshot.recovery_policy(), RecoveryPolicy::Restore);
+
+        let socket_pool = Arc::new(SocketPool::new());
+        let repository = Arc::new(InMemoryRepository::new());
+        let hand_store: Arc<dyn HandHistoryStore> = repository.clone();
+        let spectators = Arc::new(SpectatorPool::new(
+            LOBBY_ID,
+            Duration::ZERO,
+            Arc::clone(&socket_pool),
+        ));
+        let game = Game::from_snapshot(
+            snapshot,
+            settings(),
+            None,
+            Some(hand_store),
+            spectators,
+            chat(),
+        );
+        let mailbox =
+            ActorRuntime::new(1).spawn(LOBBY_ID, game, socket_pool, Arc::new(ThreadPool::new(1)));
+
+        // nobody came back after restart: the player on turn has no socket and no grace period
+        let (reply, started) = mpsc::channel();
+        assert!(mailbox.send(GameChannelMessage::Start(reply)));
+        assert_eq!(started.recv().unwrap(), Ok(()));
+
+        // a stuck hand would only be refunded at the deadline
+        let (reply, stopped) = mpsc::channel();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        assert!(mailbox.send(GameChannelMessage::Shutdown { deadline, reply }));
+        stopped
+            .recv_timeout(Duration::from_secs(10))
+            .expect("game is stuck on a disconnected player");
+
+        let hands = repository.get_hands_by_user_id(1, 10).unwrap();
+        assert_eq!(hands.len(), 1);
+    }
+
+    #[test]
+    fn start_rejected_by_the_engine_is_reported() {
+        let socket_pool = Arc::new(SocketPool::new());
+        let spectators = Arc::new(SpectatorPool::new(
+            LOBBY_ID,
+            Duration::ZERO,
+            Arc::clone(&socket_pool),
+        ));
+        let game = Game::new(LOBBY_ID, settings(), None, None, spectators, chat());
+        let mailbox =
+            ActorRuntime::new(1).spawn(LOBBY_ID, game, socket_pool, Arc::new(ThreadPool::new(1)));
+
+        let (reply, started) = mpsc::channel();
+        assert!(mailbox.send(GameChannelMessage::Start(reply)));
+        assert_eq!(
+            started.recv().unwrap(),
+            Err(StartGameError::Rejected(
+                "Not enough players to start a new game"
+            ))
+        );
+
+        // nothing is running, so the next start is tried again instead of reported as running
+        let (reply, started) = mpsc::channel();
+        assert!(mailbox.send(GameChannelMessage::Start(reply)));
+        assert!(matches!(
+            started.recv().unwrap(),
+            Err(StartGameError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn action_is_taken_for_the_user_of_the_connection() {
+        let socket_pool = Arc::new(SocketPool::new());
+        let thread_pool = Arc::new(ThreadPool::new(1));
+        let repository = Arc::new(InMemoryRepository::new());
+        let hand_store: Arc<dyn HandHistoryStore> = repository.clone();
+        let spectators = || {
+            Arc::new(SpectatorPool::new(
+                LOBBY_ID,
+                Duration::ZERO,
+                Arc::clone(&socket_pool),
+            ))
+        };
+        let mut game = Game::new(
+            LOBBY_ID,
+            settings(),
+            None,
+            Some(hand_store),
+            spectators(),
+            chat(),
+        );
+        for user_id in 1..=2 {
+            let player = Player {
+                user_id,
+                bank: 1000,
+                ..Default::default()
+            };
+            game.add_player(player, &socket_pool);
+        }
+
+        // nobody reads from the inbox, it is only needed by the session
+        let idle = Game::new(LOBBY_ID, settings(), None, None, spectators(), chat());
+        let mailbox = ActorRuntime::new(1).spawn(
+            LOBBY_ID,
+            idle,
+            Arc::clone(&socket_pool),
+            Arc::clone(&thread_pool),
+        );
+        let ctx = SessionContext {
+            socket_pool: &socket_pool,
+            thread_pool: &thread_pool,
+            mailbox: &mailbox,
+        };
+        game.start_session(&ctx).unwrap();
+
+        let on_turn = game.state.current_player().unwrap().user_id;
+        let other = if on_turn == 1 { 2 } else { 1 };
+        // the payload names the other player, the read was made on the connection of the one on turn
+        let message = GameChannelMessage::SocketSource(SocketSourceMessage::PlayerActionRequest {
+            user_id: on_turn,
+            result: Ok(PlayerActionRequest {
+                player_id: other,
+                lobby_id: LOBBY_ID,
+                action: Some(Action {
+                    action_type: ActionType::Fold.into(),
+                    player_id: other,
+                    ..Default::default()
+                }),
+            }),
+        });
+        game.handle_session_message(message, &ctx);
+
+        let hands = repository.get_hands_by_user_id(on_turn, 10).unwrap();
+        assert_eq!(hands.len(), 1);
+        let fold = hands[0]
+            .actions
+            .iter()
+            .find(|a| a.action_type() == ActionType::Fold)
+            .unwrap();
+        assert_eq!(fold.player_id, on_turn);
+    }
+
+    #[test]
+    fn only_players_holding_a_seat_can_chat() {
+        let socket_pool = Arc::new(SocketPool::new());
+        let spectators = Arc::new(SpectatorPool::new(
+            LOBBY_ID,
+            Duration::ZERO,
+            Arc::clone(&socket_pool),
+        ));
+        let chat = chat();
+        let mut game = Game::new(
+            LOBBY_ID,
+            settings(),
+            None,
+            None,
+            spectators,
+            Arc::clone(&chat),
+        );
+        for user_id in 1..=2 {
+            let player = Player {
+                user_id,
+                bank: 1000,
+                ..Default::default()
+            };
+            game.add_player(player, &socket_pool);
+        }
+
+        game.post_chat(chat_event(1, "gl"), &socket_pool);
+        game.post_chat(chat_event(2, "hf"), &socket_pool);
+        assert_eq!(chat.history().messages.len(), 2);
+
+        // left the table, the socket may still be open for a while
+        game.dispatch(Command::Disconnect { player_id: 2 }, &socket_pool);
+        game.post_chat(chat_event(2, "bye"), &socket_pool);
+        // never sat down
+        game.post_chat(chat_event(3, "hi"), &socket_pool);
+        // watches without being a spectator of the table
+        game.post_chat(
+            ChatEvent {
+                key: ConnectionKey::spectator(4, LOBBY_ID),
+                text: String::from("hi"),
+            },
+            &socket_pool,
+        );
+
+        let authors: Vec<i32> = chat.history().messages.iter().map(|m| m.user_id).collect();
+        assert_eq!(authors, vec![1, 2]);
     }
 }
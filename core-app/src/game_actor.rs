@@ -0,0 +1,295 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    game::{Game, SessionContext, SessionEnd},
+    game_orchestrator::StartGameError,
+    responses::{GameChannelMessage, SocketSourceMessage},
+    socket_pool::SocketPool,
+    thread_pool::ThreadPool,
+};
+
+// how many messages an actor handles before the worker moves on to other tables
+const MESSAGES_PER_TURN: usize = 32;
+
+// Every table runs as an actor: it owns the Game and handles its inbox one message at a time,
+// so nobody else ever locks or reads the game state. Actors don't have threads of their own,
+// a fixed number of runtime workers take turns on the tables which have messages.
+pub struct GameActor {
+    lobby_id: i32,
+    game: Game,
+    socket_pool: Arc<SocketPool>,
+    // for bot requests, which block until the bot answers
+    thread_pool: Arc<ThreadPool>,
+}
+
+struct ActorCell {
+    inbox: Mutex<VecDeque<GameChannelMessage>>,
+    actor: Mutex<GameActor>,
+    // set while the actor waits for a worker or runs on one, so it never runs on two at once
+    scheduled: AtomicBool,
+    stopped: AtomicBool,
+    // earliest timer of the actor which hasn't fired yet
+    tick_at: Mutex<Option<Instant>>,
+}
+
+// The only way to reach a game. Socket callbacks, bot answers and timers use it as well.
+#[derive(Clone)]
+pub struct GameMailbox {
+    cell: Arc<ActorCell>,
+    runtime: Arc<ActorRuntime>,
+}
+
+type Timers = BTreeMap<(Instant, u64), GameMailbox>;
+
+pub struct ActorRuntime {
+    workers: ThreadPool,
+    timers: Arc<(Mutex<Timers>, Condvar)>,
+    next_timer_id: AtomicU64,
+}
+
+impl ActorRuntime {
+    pub fn new(threads: usize) -> Arc<Self> {
+        let timers: Arc<(Mutex<Timers>, Condvar)> =
+            Arc::new((Mutex::new(BTreeMap::new()), Condvar::new()));
+
+        let timers_clone = Arc::clone(&timers);
+        thread::Builder::new()
+            .name(String::from("game-timers"))
+            .spawn(move || run_timers(&timers_clone))
+            .expect("failed to spawn game timers");
+
+        Arc::new(ActorRuntime {
+            workers: ThreadPool::new(threads),
+            timers,
+            next_timer_id: AtomicU64::new(0),
+        })
+    }
+
+    // returns the inbox of the started actor
+    pub fn spawn(
+        self: &Arc<Self>,
+        lobby_id: i32,
+        game: Game,
+        socket_pool: Arc<SocketPool>,
+        thread_pool: Arc<ThreadPool>,
+    ) -> GameMailbox {
+        let actor = GameActor {
+            lobby_id,
+            game,
+            socket_pool,
+            thread_pool,
+        };
+
+        GameMailbox {
+            cell: Arc::new(ActorCell {
+                inbox: Mutex::new(VecDeque::new()),
+                actor: Mutex::new(actor),
+                scheduled: AtomicBool::new(false),
+                stopped: AtomicBool::new(false),
+                tick_at: Mutex::new(None),
+            }),
+            runtime: Arc::clone(self),
+        }
+    }
+
+    fn add_timer(&self, at: Instant, mailbox: GameMailbox) {
+        let id = self.next_timer_id.fetch_add(1, Ordering::Relaxed);
+        let (timers, wakeup) = &*self.timers;
+        timers.lock().unwrap().insert((at, id), mailbox);
+        wakeup.notify_one();
+    }
+}
+
+// Single thread for the timers of all tables: each one is delivered as a Tick to its actor
+fn run_timers(timers: &(Mutex<Timers>, Condvar)) {
+    let (timers, wakeup) = timers;
+    let mut guard = timers.lock().unwrap();
+
+    loop {
+        let now = Instant::now();
+        let due: Vec<(Instant, u64)> = guard
+            .range(..(now, u64::MAX))
+            .map(|(key, _)| *key)
+            .collect();
+        if !due.is_empty() {
+            let fired: Vec<(Instant, GameMailbox)> = due
+                .into_iter()
+                .filter_map(|key| guard.remove(&key).map(|mailbox| (key.0, mailbox)))
+                .collect();
+            drop(guard);
+            for (at, mailbox) in fired {
+                mailbox.fire_tick(at);
+            }
+            guard = timers.lock().unwrap();
+            continue;
+        }
+
+        guard = match guard.keys().next().map(|(at, _)| *at) {
+            Some(at) => wakeup.wait_timeout(guard, at - now).unwrap().0,
+            None => wakeup.wait(guard).unwrap(),
+        };
+    }
+}
+
+impl GameMailbox {
+    // false when the actor is stopped
+    pub fn send(&self, message: GameChannelMessage) -> bool {
+        if self.cell.stopped.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.cell.inbox.lock().unwrap().push_back(message);
+        self.schedule();
+        true
+    }
+
+    fn schedule(&self) {
+        if self.cell.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mailbox = self.clone();
+        self.runtime.workers.execute(move || mailbox.run_turn());
+    }
+
+    fn run_turn(&self) {
+        let mut actor = self.cell.actor.lock().unwrap();
+
+        for _ in 0..MESSAGES_PER_TURN {
+            let message = match self.cell.inbox.lock().unwrap().pop_front() {
+                Some(message) => message,
+                None => break,
+            };
+            if !actor.handle(message, self) {
+                self.cell.stopped.store(true, Ordering::SeqCst);
+                self.cell.inbox.lock().unwrap().clear();
+                return;
+            }
+        }
+        if let Some(deadline) = actor.game.next_deadline() {
+            self.schedule_tick(deadline);
+        }
+        drop(actor);
+
+        self.cell.scheduled.store(false, Ordering::SeqCst);
+        // a message may have arrived after the last one was taken
+        if !self.cell.inbox.lock().unwrap().is_empty() {
+            self.schedule();
+        }
+    }
+
+    fn schedule_tick(&self, deadline: Instant) {
+        let mut tick_at = self.cell.tick_at.lock().unwrap();
+        if tick_at.is_some_and(|at| at <= deadline) {
+            return;
+        }
+        *tick_at = Some(deadline);
+        drop(tick_at);
+
+        self.runtime.add_timer(deadline, self.clone());
+    }
+
+    fn fire_tick(&self, at: Instant) {
+        let mut tick_at = self.cell.tick_at.lock().unwrap();
+        if *tick_at == Some(at) {
+            *tick_at = None;
+        }
+        drop(tick_at);
+
+        self.send(GameChannelMessage::Tick);
+    }
+}
+
+impl GameActor {
+    // false once the actor is stopped for shutdown
+    fn handle(&mut self, message: GameChannelMessage, mailbox: &GameMailbox) -> bool {
+        let ctx = SessionContext {
+            socket_pool: &self.socket_pool,
+            thread_pool: &self.thread_pool,
+            mailbox,
+        };
+
+        if self.game.is_running() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.game.handle_session_message(message, &ctx)
+            }));
+            return self.finish_session(result);
+        }
+
+        match message {
+            // the answer waits for the engine, a start it rejects is no start at all
+            GameChannelMessage::Start(reply) => {
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| self.game.start_session(&ctx)));
+                let (answer, result) = match result {
+                    Ok(Ok(end)) => (Ok(()), Ok(end)),
+                    Ok(Err(reason)) => (Err(StartGameError::Rejected(reason)), Ok(None)),
+                    Err(panic) => (
+                        Err(StartGameError::Rejected("game failed to start")),
+                        Err(panic),
+                    ),
+                };
+                let proceed = self.finish_session(result);
+                let _ = reply.send(answer);
+                return proceed;
+            }
+            GameChannelMessage::Shutdown { reply, .. } => {
+                self.stop(reply);
+                return false;
+            }
+            GameChannelMessage::IsReadyToStart(reply) => {
+                let _ = reply.send(self.game.is_ready_to_start());
+            }
+            GameChannelMessage::HttpRequestSource(m) => {
+                self.game.add_player(m.player, &self.socket_pool);
+            }
+            GameChannelMessage::SocketSource(SocketSourceMessage::ConnectionClosed(e)) => {
+                self.game.hande_connection_update(&e);
+            }
+            GameChannelMessage::Resync(request) => {
+                self.game.resync_player(&request, &self.socket_pool);
+            }
+            GameChannelMessage::Chat(event) => {
+                self.game.post_chat(event, &self.socket_pool);
+            }
+            // answers and timers which arrived after their session was over
+            GameChannelMessage::SocketSource(SocketSourceMessage::PlayerActionRequest {
+                ..
+            })
+            | GameChannelMessage::InnerSource(_)
+            | GameChannelMessage::Tick => {}
+        }
+        true
+    }
+
+    // false when the session ended with the server shutdown
+    fn finish_session(&mut self, result: thread::Result<Option<SessionEnd>>) -> bool {
+        match result {
+            Ok(None) | Ok(Some(SessionEnd::Stopped)) => true,
+            Ok(Some(SessionEnd::ServerShutdown(reply))) => {
+                self.stop(reply);
+                false
+            }
+            Err(_) => {
+                eprintln!("game {} panicked, refunding current hand", self.lobby_id);
+                self.game.abort_session(&self.socket_pool);
+                true
+            }
+        }
+    }
+
+    // final stacks go to the snapshot, the game is restored from it after restart
+    fn stop(&self, reply: Sender<()>) {
+        self.game.persist_snapshot();
+        println!("game {} stopped for shutdown", self.lobby_id);
+        let _ = reply.send(());
+    }
+}
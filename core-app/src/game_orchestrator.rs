@@ -1,74 +1,197 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
+    fmt,
+    net::TcpStream,
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
 use rand::Rng;
+use tungstenite::WebSocket;
 
 use crate::{
     game::{Game, GameSettings},
-    protos::{player::Player, user::User},
-    responses::{generate_game_started_responses, GameChannelMessage, SocketSourceMessage},
-    socket_pool::{ConnectionClosedEvent, SocketPool},
+    game_actor::{ActorRuntime, GameMailbox},
+    game_snapshot::SnapshotStore,
+    hand_history::HandHistoryStore,
+    protos::{
+        player::Player,
+        requests::ResyncRequest,
+        responses::{ResponseMessage, ResponseMessageType},
+        user::User,
+    },
+    responses::{
+        create_message_response, generate_game_started_responses, EncodableMessage,
+        GameChannelMessage, SocketSourceMessage,
+    },
+    socket_pool::{ChatEvent, ConnectionClosedEvent, SendError, SendEvent, SocketPool},
+    spectator_pool::SpectatorPool,
+    table_chat::{AllowAll, ChatModerator, TableChat},
     thread_pool::ThreadPool,
 };
 
+const SHUTDOWN_MARGIN: Duration = Duration::from_secs(2);
+
 pub struct GameOrchestrator {
     game_pool: Mutex<HashMap<i32, GameClient>>,
-    user_map: Mutex<HashMap<i32, HashSet<i32>>>,
+    snapshot_store: Option<Arc<dyn SnapshotStore>>,
+    hand_store: Option<Arc<dyn HandHistoryStore>>,
+    spectator_delay: Duration,
+    // defaults of every table
+    game_settings: GameSettings,
+    // profanity filter or mutes for the chats of all tables
+    chat_moderator: Arc<dyn ChatModerator>,
+    socket_pool: Arc<SocketPool>,
+    thread_pool: Arc<ThreadPool>,
+    // workers and timers shared by the actors of all tables
+    actor_runtime: Arc<ActorRuntime>,
+    // set once on shutdown, no new games, seats or starts afterwards
+    draining: AtomicBool,
 }
 pub struct GameClient {
-    game: Arc<RwLock<Game>>,
-    sender: Arc<RwLock<Sender<GameChannelMessage>>>,
-    receiver: Arc<Mutex<Receiver<GameChannelMessage>>>,
+    // inbox of the game actor, the only way to reach the game
+    mailbox: GameMailbox,
+    // shared with the game, so spectators are added without going through the actor
+    spectators: Arc<SpectatorPool>,
+    // posted to by the game, read here so the history goes out without waiting for the actor
+    chat: Arc<TableChat>,
 }
 pub struct JoinGameMessage {
     pub player: Player,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum StartGameError {
+    LobbyNotFound,
+    AlreadyRunning,
+    ShuttingDown,
+    // the engine refused to deal, e.g. not enough players
+    Rejected(&'static str),
+}
+
+impl fmt::Display for StartGameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartGameError::LobbyNotFound => write!(f, "lobby doesn't exist"),
+            StartGameError::AlreadyRunning => write!(f, "game is already running"),
+            StartGameError::ShuttingDown => write!(f, "server is shutting down"),
+            StartGameError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
 impl GameOrchestrator {
-    pub fn new() -> Self {
-        return GameOrchestrator {
+    pub fn new(
+        snapshot_store: Option<Arc<dyn SnapshotStore>>,
+        hand_store: Option<Arc<dyn HandHistoryStore>>,
+        spectator_delay: Duration,
+        game_settings: GameSettings,
+        socket_pool: Arc<SocketPool>,
+        thread_pool: Arc<ThreadPool>,
+        actor_runtime: Arc<ActorRuntime>,
+    ) -> Self {
+        GameOrchestrator {
             game_pool: Mutex::new(HashMap::new()),
-            user_map: Mutex::new(HashMap::new()),
-        };
+            snapshot_store,
+            hand_store,
+            spectator_delay,
+            game_settings,
+            chat_moderator: Arc::new(AllowAll),
+            socket_pool,
+            thread_pool,
+            actor_runtime,
+            draining: AtomicBool::new(false),
+        }
     }
-    // TODO: add More ConnectionEvents:
-    // e.g. ConnectionRestored
-    pub fn update_player_connection_status(
-        &self,
-        event: ConnectionClosedEvent,
-        socket_pool: &Arc<SocketPool>,
-    ) {
-        let user_map = self.user_map.lock().unwrap();
 
-        match user_map.get(&event.user_id) {
+    // profanity filter or mutes, set before the orchestrator is shared and any table is created
+    pub fn with_chat_moderator(mut self, chat_moderator: Arc<dyn ChatModerator>) -> Self {
+        self.chat_moderator = chat_moderator;
+        self
+    }
+
+    // Recreates games which were alive before restart.
+    // Interrupted hands are either continued or refunded, see GameSnapshot::recovery_policy
+    pub fn restore_games(&self) -> usize {
+        let store = match &self.snapshot_store {
+            Some(store) => store,
+            None => return 0,
+        };
+
+        let snapshots = store.load_snapshots();
+        let restored = snapshots.len();
+
+        for snapshot in snapshots {
+            let lobby_id = snapshot.lobby_id;
+            let spectators = Arc::new(self.create_spectator_pool(lobby_id));
+            let chat = self.create_chat(lobby_id);
+            let game = Game::from_snapshot(
+                snapshot,
+                self.game_settings.clone(),
+                Some(Arc::clone(store)),
+                self.hand_store.clone(),
+                Arc::clone(&spectators),
+                Arc::clone(&chat),
+            );
+
+            self.insert_game(lobby_id, game, spectators, chat);
+        }
+
+        restored
+    }
+    // Closed connection only starts the grace period of the seat at its table, restored connection
+    // comes back through join_game: the player reopens /join_lobby and gets the current state
+    pub fn update_player_connection_status(&self, event: ConnectionClosedEvent) {
+        // lobby feed has no seat behind it
+        let lobby_id = match event.lobby_id {
+            Some(lobby_id) => lobby_id,
             None => return,
-            Some(game_ids) => {
-                game_ids.iter().for_each(move |id| {
-                    let mut game_pool = self.game_pool.lock().unwrap();
-                    let game_client = game_pool.get_mut(id).unwrap();
-
-                    match game_client.game.try_write() {
-                        Ok(mut game) => {
-                            game.hande_connection_update(&event, socket_pool);
-                        }
-                        Err(_) => {
-                            game_client
-                                .sender
-                                .read()
-                                .unwrap()
-                                .send(GameChannelMessage::SocketSource(
-                                    SocketSourceMessage::ConnectionClosed(event.clone()),
-                                ))
-                                .unwrap();
-                        }
-                    };
-                });
+        };
+
+        self.send_to_game(
+            lobby_id,
+            GameChannelMessage::SocketSource(SocketSourceMessage::ConnectionClosed(event)),
+        );
+    }
+
+    // The game decides whether the sender may chat, only it knows who still holds a seat
+    pub fn post_chat(&self, event: ChatEvent) {
+        let Some(lobby_id) = event.key.lobby_id else {
+            return;
+        };
+        if !self.send_to_game(lobby_id, GameChannelMessage::Chat(event)) {
+            println!("chat message for lobby {} dropped, no such game", lobby_id);
+        }
+    }
+
+    // Client which fell behind lost its queued states, its table sends the full state again.
+    // Failed sends close the connection, that comes as ConnectionClosedEvent
+    pub fn handle_send_event(&self, event: SendEvent) {
+        let key = match event {
+            SendEvent::Coalesced { key } => key,
+            SendEvent::Failed {
+                reason: SendError::NotConnected,
+                ..
+            } => return,
+            SendEvent::Failed { key, reason } => {
+                println!("sending to {} failed: {}", key, reason);
+                return;
             }
+        };
+
+        if let Some(lobby_id) = key.lobby_id {
+            self.send_to_game(
+                lobby_id,
+                GameChannelMessage::Resync(ResyncRequest {
+                    lobby_id,
+                    player_id: key.user_id,
+                    ..Default::default()
+                }),
+            );
         }
     }
 
@@ -78,127 +201,204 @@ impl GameOrchestrator {
         pool.contains_key(&lobby_id)
     }
 
-    pub fn create_game(&self, lobby_id: i32, settings: GameSettings) -> bool {
-        let mut pool = self.game_pool.lock().unwrap();
+    pub fn create_game(&self, lobby_id: i32) -> bool {
+        if self.is_draining() {
+            return false;
+        }
+        let spectators = Arc::new(self.create_spectator_pool(lobby_id));
+        let chat = self.create_chat(lobby_id);
+        let game = Game::new(
+            lobby_id,
+            self.game_settings.clone(),
+            self.snapshot_store.clone(),
+            self.hand_store.clone(),
+            Arc::clone(&spectators),
+            Arc::clone(&chat),
+        );
+
+        self.insert_game(lobby_id, game, spectators, chat);
+
+        true
+    }
+
+    fn create_spectator_pool(&self, lobby_id: i32) -> SpectatorPool {
+        SpectatorPool::new(
+            lobby_id,
+            self.spectator_delay,
+            Arc::clone(&self.socket_pool),
+        )
+    }
 
-        let game = Game::new(lobby_id, settings);
-        let game_mutex = RwLock::new(game);
-        let game_arc = Arc::new(game_mutex);
+    fn create_chat(&self, lobby_id: i32) -> Arc<TableChat> {
+        Arc::new(TableChat::new(lobby_id, Arc::clone(&self.chat_moderator)))
+    }
 
-        let (sender, receiver) = channel();
+    fn insert_game(
+        &self,
+        lobby_id: i32,
+        game: Game,
+        spectators: Arc<SpectatorPool>,
+        chat: Arc<TableChat>,
+    ) {
+        let mailbox = self.actor_runtime.spawn(
+            lobby_id,
+            game,
+            Arc::clone(&self.socket_pool),
+            Arc::clone(&self.thread_pool),
+        );
 
-        pool.insert(
+        self.game_pool.lock().unwrap().insert(
             lobby_id,
             GameClient {
-                game: game_arc,
-                sender: Arc::new(RwLock::new(sender)),
-                receiver: Arc::new(Mutex::new(receiver)),
+                mailbox,
+                spectators,
+                chat,
             },
         );
+    }
 
-        true
+    // false when there is no such game
+    fn send_to_game(&self, lobby_id: i32, message: GameChannelMessage) -> bool {
+        let pool = self.game_pool.lock().unwrap();
+
+        match pool.get(&lobby_id) {
+            Some(game_client) => game_client.mailbox.send(message),
+            None => false,
+        }
     }
 
-    pub fn join_game(&self, lobby_id: i32, user: User, socket_pool: &Arc<SocketPool>) {
+    pub fn join_game(&self, lobby_id: i32, user: User) -> bool {
+        if self.is_draining() {
+            return false;
+        }
         let id = user.id;
-        let player = Player::from_user(user);
+        let player = Player::from_user(user, self.game_settings.starting_bank);
 
-        let pool = match self.game_pool.try_lock() {
-            Ok(v) => v,
-            Err(e) => {
-                panic!("error in join game while locking game_pool {:?}", e);
-            }
+        let pool = self.game_pool.lock().unwrap();
+        let game_client = match pool.get(&lobby_id) {
+            Some(g) => g,
+            None => return false,
         };
 
-        let game_m = pool.get(&lobby_id).unwrap();
-
-        let mut lock = game_m.game.try_write();
-
-        if let Ok(ref mut mutex) = lock {
-            mutex.add_player(player, socket_pool);
-        } else {
-            let g = game_m.sender.read().unwrap();
-            g.send(GameChannelMessage::HttpRequestSource(JoinGameMessage {
+        if !game_client
+            .mailbox
+            .send(GameChannelMessage::HttpRequestSource(JoinGameMessage {
                 player,
             }))
-            .unwrap();
+        {
+            return false;
         }
-        self.user_map
-            .lock()
-            .unwrap()
-            .entry(id)
-            .or_insert_with(HashSet::new)
-            .insert(lobby_id);
+        // the history belongs to the table, the lobby feed is for messages outside of it
+        self.socket_pool.update_table_clients(
+            lobby_id,
+            vec![create_message_response(
+                game_client.chat.history(),
+                ResponseMessageType::ChatHistory,
+                id,
+            )],
+        );
+        true
     }
 
-    pub fn spawn_bot(&self, lobby_id: i32, socket_pool: &Arc<SocketPool>) {
-        let mut bot_player = Player::default();
-        bot_player.user_name = String::from("Chat gpt");
-        bot_player.bank = 10000;
-        bot_player.is_bot = true;
-        let mut rng = rand::thread_rng();
-        bot_player.user_id = -rng.gen_range(1..i32::MAX);
+    pub fn observe_game(&self, lobby_id: i32, user_id: i32, socket: WebSocket<TcpStream>) -> bool {
+        let pool = self.game_pool.lock().unwrap();
 
-        let pool = match self.game_pool.try_lock() {
-            Ok(v) => v,
-            Err(e) => {
-                panic!("error in join game while locking game_pool {:?}", e);
+        match pool.get(&lobby_id) {
+            Some(game_client) => {
+                let chat_history = ResponseMessage {
+                    payload: game_client.chat.history().encode_message(),
+                    payload_type: ResponseMessageType::ChatHistory.into(),
+                };
+                game_client
+                    .spectators
+                    .add(user_id, socket, vec![chat_history]);
+                true
             }
+            None => false,
+        }
+    }
+
+    pub fn resync_player(&self, request: ResyncRequest) -> bool {
+        self.send_to_game(request.lobby_id, GameChannelMessage::Resync(request))
+    }
+
+    pub fn spawn_bot(&self, lobby_id: i32) -> bool {
+        if self.is_draining() {
+            return false;
+        }
+        let mut rng = rand::thread_rng();
+        let bot_player = Player {
+            user_name: String::from("Chat gpt"),
+            bank: self.game_settings.starting_bank,
+            is_bot: true,
+            user_id: -rng.gen_range(1..i32::MAX),
+            ..Default::default()
         };
 
-        let game_m = pool.get(&lobby_id).unwrap();
+        self.send_to_game(
+            lobby_id,
+            GameChannelMessage::HttpRequestSource(JoinGameMessage { player: bot_player }),
+        )
+    }
 
-        let mut lock = game_m.game.try_write();
+    pub fn should_start_game(&self, lobby_id: i32) -> bool {
+        let (reply, answer) = channel();
 
-        if let Ok(ref mut mutex) = lock {
-            mutex.add_player(bot_player, socket_pool);
-        } else {
-            let g = game_m.sender.read().unwrap();
-            g.send(GameChannelMessage::HttpRequestSource(JoinGameMessage {
-                player: bot_player,
-            }))
-            .unwrap();
+        if !self.send_to_game(lobby_id, GameChannelMessage::IsReadyToStart(reply)) {
+            return false;
         }
+        answer.recv().unwrap_or(false)
     }
 
-    pub fn should_start_game(&self, lobby_id: i32) -> bool {
-        let pool = self.game_pool.lock().unwrap();
+    pub fn start_game(&self, lobby_id: i32) -> Result<(), StartGameError> {
+        if self.is_draining() {
+            return Err(StartGameError::ShuttingDown);
+        }
+        let (reply, answer) = channel();
 
-        let game_m = pool.get(&lobby_id).unwrap();
+        if !self.send_to_game(lobby_id, GameChannelMessage::Start(reply)) {
+            return Err(StartGameError::LobbyNotFound);
+        }
+        answer
+            .recv()
+            .unwrap_or(Err(StartGameError::LobbyNotFound))?;
 
-        match  game_m.game.try_read() {
-            Ok(g) => return g.is_ready_to_start(),
-            Err(_) => {
-                return false
-            }
-        } ;        
+        let game_started_responses = generate_game_started_responses(lobby_id, &[], 10);
+        self.socket_pool.update_clients(game_started_responses);
+        Ok(())
     }
 
-    pub fn start_game(
-        &self,
-        lobby_id: i32,
-        thread_pool: Arc<ThreadPool>,
-        socket_pool: Arc<SocketPool>,
-    ) {
-        let pool = self.game_pool.lock().unwrap();
-        let game_client = pool.get(&lobby_id).unwrap();
-
-        let game_clone = Arc::clone(&game_client.game);
-        let pool = Arc::clone(&thread_pool);
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
 
-        let game_started_responses = generate_game_started_responses(lobby_id, &Vec::new(), 10);
-        socket_pool.update_clients(game_started_responses);
+    // Stops accepting new games and seats, then lets every table finish its current hand.
+    // Hands still running at the deadline are refunded, all games are persisted.
+    // Players and spectators get the notice afterwards from SocketPool::close_all
+    pub fn shutdown(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
 
-        let receiver_clone = Arc::clone(&game_client.receiver);
-        let sender_clone = Arc::clone(&game_client.sender);
+        let mut pending = Vec::new();
+        let pool = self.game_pool.lock().unwrap();
+        for (lobby_id, game_client) in pool.iter() {
+            let (reply, answer) = channel();
+            if game_client
+                .mailbox
+                .send(GameChannelMessage::Shutdown { deadline, reply })
+            {
+                pending.push((*lobby_id, answer));
+            }
+        }
+        drop(pool);
 
-        thread_pool.execute(move || {
-            let ref mut game = game_clone.write().unwrap();
-            
-            match game.run(socket_pool, pool, receiver_clone, sender_clone) {
-                Ok(_) => {},
-                Err(er) => println!("game shutdown abruptly: {}", er)
-            };
-        });
+        // the refund itself needs a moment after the deadline
+        let wait_until = deadline + SHUTDOWN_MARGIN;
+        for (lobby_id, answer) in pending {
+            let left = wait_until.saturating_duration_since(Instant::now());
+            if answer.recv_timeout(left).is_err() {
+                eprintln!("game {} didn't stop before shutdown", lobby_id);
+            }
+        }
     }
 }
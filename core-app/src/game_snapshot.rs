@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+use crate::{
+    card::CardDeck,
+    game::{DeckState, GameState, KeyPositions, PlayerState},
+    protos::{
+        game_snapshot::{GameSnapshot, KeyPositionsSnapshot},
+        game_state::{ActionType, GameStatus},
+        player::PlayerStatus,
+    },
+};
+
+pub trait SnapshotStore: Send + Sync {
+    fn save_snapshot(&self, snapshot: &GameSnapshot);
+    fn load_snapshot(&self, lobby_id: i32) -> Option<GameSnapshot>;
+    fn load_snapshots(&self) -> Vec<GameSnapshot>;
+    fn remove_snapshot(&self, lobby_id: i32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    // continue the interrupted hand as soon as players reconnect
+    Restore,
+    // give every player back the chips they put in the pot during the interrupted hand
+    Refund,
+}
+
+impl KeyPositionsSnapshot {
+    fn from(positions: &KeyPositions) -> Self {
+        KeyPositionsSnapshot {
+            small_blind_index: positions.small_blind_index.map(|i| i as i32),
+            big_blind_index: positions.big_blind_index.map(|i| i as i32),
+            curr_player_index: positions.curr_player_index.map(|i| i as i32),
+            button_index: positions.button_index.map(|i| i as i32),
+        }
+    }
+}
+
+impl KeyPositions {
+    fn from_snapshot(positions: Option<KeyPositionsSnapshot>) -> Self {
+        let positions = positions.unwrap_or_default();
+        KeyPositions {
+            small_blind_index: positions.small_blind_index.map(|i| i as usize),
+            big_blind_index: positions.big_blind_index.map(|i| i as usize),
+            curr_player_index: positions.curr_player_index.map(|i| i as usize),
+            button_index: positions.button_index.map(|i| i as usize),
+        }
+    }
+}
+
+impl GameSnapshot {
+    pub fn new(
+        lobby_id: i32,
+        game_state: &GameState,
+        player_state: &PlayerState,
+        deck_state: &DeckState,
+    ) -> Self {
+        GameSnapshot {
+            lobby_id,
+            status: game_state.status.into(),
+            street: Some(game_state.street.clone()),
+            game_bank: game_state.game_bank,
+            big_blind: game_state.big_blind,
+            raise_amount: game_state.raise_amount,
+            raiser_index: game_state.raiser_index.map(|i| i as i32),
+            positions: Some(KeyPositionsSnapshot::from(&game_state.positions)),
+            biggest_bet_on_curr_street: game_state.biggest_bet_on_curr_street,
+            action_history: game_state.action_history.clone(),
+            showdown_outcome: game_state.showdown_outcome.clone(),
+            players: player_state.players.clone(),
+            bank_map: player_state.bank_map.clone(),
+            deck: deck_state.deck.cards.iter().cloned().collect(),
+        }
+    }
+
+    // Hand can be continued only if it was interrupted while waiting for a player decision,
+    // everything else (showdown, automatic runout, broken positions) is refunded
+    pub fn recovery_policy(&self) -> RecoveryPolicy {
+        if self.status() != GameStatus::Active || self.showdown_outcome.is_some() {
+            return RecoveryPolicy::Refund;
+        }
+
+        let players_amount = self.players.len();
+
+        let positions = self.positions.clone().unwrap_or_default();
+        let positions_are_valid = [
+            positions.small_blind_index,
+            positions.big_blind_index,
+            positions.curr_player_index,
+            positions.button_index,
+        ]
+        .iter()
+        .all(|i| matches!(i, Some(i) if (*i as usize) < players_amount));
+
+        if players_amount < 2 || !positions_are_valid {
+            return RecoveryPolicy::Refund;
+        }
+
+        let board_cards = self.street.as_ref().map_or(0, |s| s.cards.len());
+        if self.deck.len() + board_cards < 5 {
+            return RecoveryPolicy::Refund;
+        }
+
+        let is_folded = |action_type: Option<ActionType>| action_type == Some(ActionType::Fold);
+
+        let players_in_hand = self
+            .players
+            .iter()
+            .filter(|p| !is_folded(p.action.as_ref().map(|a| a.action_type())))
+            .collect::<Vec<_>>();
+
+        let everyone_has_cards = players_in_hand.iter().all(|p| p.cards.is_some());
+        let players_able_to_act = players_in_hand.iter().filter(|p| p.bank > 0).count();
+
+        if !everyone_has_cards || players_able_to_act < 2 {
+            return RecoveryPolicy::Refund;
+        }
+
+        RecoveryPolicy::Restore
+    }
+
+    pub fn into_states(self, policy: RecoveryPolicy) -> (GameState, PlayerState, DeckState) {
+        match policy {
+            RecoveryPolicy::Restore => self.into_restored_states(),
+            RecoveryPolicy::Refund => self.into_refunded_states(),
+        }
+    }
+
+    fn into_restored_states(self) -> (GameState, PlayerState, DeckState) {
+        let mut game_state = GameState::new(self.big_blind);
+
+        // Pause keeps the hand frozen until enough players are back
+        game_state.status = GameStatus::Pause;
+        game_state.street = self.street.unwrap_or_default();
+        game_state.game_bank = self.game_bank;
+        game_state.raise_amount = self.raise_amount;
+        game_state.raiser_index = self.raiser_index.map(|i| i as usize);
+        game_state.positions = KeyPositions::from_snapshot(self.positions);
+        game_state.biggest_bet_on_curr_street = self.biggest_bet_on_curr_street;
+        game_state.action_history = self.action_history;
+
+        let player_state = PlayerState {
+            players: self.players,
+            bank_map: self.bank_map,
+        };
+
+        let deck_state = DeckState::new(CardDeck {
+            cards: VecDeque::from(self.deck),
+        });
+
+        (game_state, player_state, deck_state)
+    }
+
+    fn into_refunded_states(self) -> (GameState, PlayerState, DeckState) {
+        let game_state = GameState::new(self.big_blind);
+
+        let mut players = self.players;
+
+        // bet_in_current_seed holds everything a player has put in the pot during the hand,
+        // so bank + bet_in_current_seed is exactly the stack before the hand started
+        players.iter_mut().for_each(|p| {
+            p.bank += p.bet_in_current_seed;
+            p.bet_in_current_seed = 0;
+            p.action = None;
+            p.cards = None;
+            if p.status() == PlayerStatus::Ready {
+                p.status = PlayerStatus::WaitingForPlayers.into();
+            }
+        });
+
+        let player_state = PlayerState {
+            players,
+            bank_map: self.bank_map,
+        };
+
+        (
+            game_state,
+            player_state,
+            DeckState::new(CardDeck::new_random()),
+        )
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    game::{GameState, PlayerState},
+    protos::hand_history::{HandHistory, HandPlayer},
+};
+
+pub trait HandHistoryStore: Send + Sync {
+    fn save_hand(&self, hand: &HandHistory);
+}
+
+impl HandHistory {
+    // Taken right after the hand is finished, before the next one is dealt
+    pub fn new(lobby_id: i32, game_state: &GameState, player_state: &PlayerState) -> Self {
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        HandHistory {
+            lobby_id,
+            big_blind: game_state.big_blind,
+            players: player_state
+                .players
+                .iter()
+                .map(|p| HandPlayer {
+                    user_id: p.user_id,
+                    is_bot: p.is_bot,
+                    bank: p.bank,
+                })
+                .collect(),
+            button_index: game_state.positions.button_index.map(|i| i as i32),
+            small_blind_index: game_state.positions.small_blind_index.map(|i| i as i32),
+            big_blind_index: game_state.positions.big_blind_index.map(|i| i as i32),
+            actions: game_state.action_history.clone(),
+            board: game_state.street.cards.clone(),
+            showdown_outcome: game_state.showdown_outcome.clone(),
+            finished_at,
+        }
+    }
+
+    pub fn has_player(&self, user_id: i32) -> bool {
+        self.players.iter().any(|p| p.user_id == user_id)
+    }
+}
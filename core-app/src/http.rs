@@ -0,0 +1,643 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, ErrorKind, Read},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Minimal HTTP/1.1 server side: request parsing and routing, no external framework.
+// Bodies are read by Content-Length only, chunked requests are refused.
+
+pub const MAX_HEADERS_SIZE: usize = 16 * 1024;
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Head,
+}
+
+impl Method {
+    pub fn parse(value: &str) -> Option<Method> {
+        match value {
+            "GET" => Some(Method::Get),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "OPTIONS" => Some(Method::Options),
+            "HEAD" => Some(Method::Head),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Head => "HEAD",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    Malformed(&'static str),
+    UnsupportedMethod(String),
+    UnsupportedTransferEncoding,
+    HeadersTooLarge,
+    BodyTooLarge(usize),
+    Io(io::Error),
+}
+
+impl HttpError {
+    // None when the connection is broken and there is nobody to answer
+    pub fn status_line(&self) -> Option<&'static str> {
+        match self {
+            HttpError::Malformed(_) => Some("HTTP/1.1 400 Bad Request"),
+            HttpError::UnsupportedMethod(_) => Some("HTTP/1.1 501 Not Implemented"),
+            HttpError::UnsupportedTransferEncoding => Some("HTTP/1.1 501 Not Implemented"),
+            HttpError::HeadersTooLarge => Some("HTTP/1.1 431 Request Header Fields Too Large"),
+            HttpError::BodyTooLarge(_) => Some("HTTP/1.1 413 Payload Too Large"),
+            HttpError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            HttpError::UnsupportedMethod(method) => write!(f, "method {} is not supported", method),
+            HttpError::UnsupportedTransferEncoding => {
+                write!(f, "only Content-Length bodies are supported")
+            }
+            HttpError::HeadersTooLarge => {
+                write!(f, "headers are larger than {} bytes", MAX_HEADERS_SIZE)
+            }
+            HttpError::BodyTooLarge(size) => write!(
+                f,
+                "body of {} bytes is larger than {} bytes",
+                size, MAX_BODY_SIZE
+            ),
+            HttpError::Io(e) => write!(f, "connection error: {}", e),
+        }
+    }
+}
+
+impl Error for HttpError {}
+
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub path: String,
+    pub version: String,
+    pub query: HashMap<String, String>,
+    // names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    // filled by Router from patterns like /lobbies/:lobby_id
+    pub params: HashMap<String, String>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|v| v.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|v| v.as_str())
+    }
+
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.header("authorization")?
+            .strip_prefix("Bearer ")
+            .map(|token| token.trim())
+    }
+
+    // HTTP/1.1 keeps the connection by default, HTTP/1.0 only when asked
+    pub fn keep_alive(&self) -> bool {
+        let connection = self
+            .header("connection")
+            .map(|v| v.to_ascii_lowercase())
+            .unwrap_or_default();
+
+        if self.version == "HTTP/1.0" {
+            connection == "keep-alive"
+        } else {
+            connection != "close"
+        }
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+// Every kept-alive connection holds a worker while it waits for the next request,
+// so only a few of them may stay open and the rest of the workers take new connections
+pub struct KeepAliveLimit {
+    open: AtomicUsize,
+    max: usize,
+}
+
+// released when dropped, together with the connection
+pub struct KeepAliveSlot<'a> {
+    limit: &'a KeepAliveLimit,
+}
+
+impl KeepAliveLimit {
+    pub fn new(max: usize) -> Self {
+        KeepAliveLimit {
+            open: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<KeepAliveSlot<'_>> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| KeepAliveSlot { limit: self })
+    }
+}
+
+impl Drop for KeepAliveSlot<'_> {
+    fn drop(&mut self) {
+        self.limit.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Ok(None) means the client closed the connection before sending anything
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>, HttpError> {
+    let head = match read_head(reader)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut request = parse_head(&head)?;
+
+    if request
+        .header("transfer-encoding")
+        .is_some_and(|v| !v.eq_ignore_ascii_case("identity"))
+    {
+        return Err(HttpError::UnsupportedTransferEncoding);
+    }
+
+    let content_length = match request.header("content-length") {
+        Some(v) => v
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| HttpError::Malformed("invalid Content-Length"))?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err(HttpError::BodyTooLarge(content_length));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(HttpError::Io)?;
+    request.body = body;
+
+    Ok(Some(request))
+}
+
+// Parses request line and headers, body is left empty.
+// Also used for websocket handshakes which are peeked from the stream.
+pub fn parse_head(head: &str) -> Result<HttpRequest, HttpError> {
+    // lines() accepts both CRLF and bare LF line endings
+    let mut lines = head.lines();
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if parts.next().is_none() => (m, t, v),
+        _ => return Err(HttpError::Malformed("invalid request line")),
+    };
+
+    let method =
+        Method::parse(method).ok_or_else(|| HttpError::UnsupportedMethod(method.to_string()))?;
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(HttpError::Malformed("unsupported HTTP version"));
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (target, HashMap::new()),
+    };
+    if !path.starts_with('/') {
+        return Err(HttpError::Malformed("path must be absolute"));
+    }
+
+    let mut headers = HashMap::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(HttpError::Malformed("invalid header"))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    Ok(HttpRequest {
+        method,
+        path: path.to_string(),
+        version: version.to_string(),
+        query,
+        headers,
+        body: Vec::new(),
+        params: HashMap::new(),
+    })
+}
+
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key, true), percent_decode(value, true)),
+            None => (percent_decode(pair, true), String::new()),
+        })
+        .collect()
+}
+
+// '+' stands for a space only in query strings, in the path it is a plain character
+fn percent_decode(value: &str, plus_is_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_is_space => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Reads up to and including the empty line after headers
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Option<String>, HttpError> {
+    let mut head = Vec::new();
+
+    loop {
+        let mut line = Vec::new();
+        let read = reader
+            .by_ref()
+            .take((MAX_HEADERS_SIZE + 1 - head.len()) as u64)
+            .read_until(b'\n', &mut line);
+
+        match read {
+            Ok(0) if head.is_empty() => return Ok(None),
+            Ok(0) => return Err(HttpError::Malformed("connection closed inside headers")),
+            Ok(_) => {}
+            // idle keep-alive connection ran into read timeout
+            Err(e)
+                if head.is_empty()
+                    && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(HttpError::Io(e)),
+        }
+
+        // some clients send empty lines between requests
+        if head.is_empty() && (line == b"\r\n" || line == b"\n") {
+            continue;
+        }
+
+        head.extend_from_slice(&line);
+        if head.len() > MAX_HEADERS_SIZE {
+            return Err(HttpError::HeadersTooLarge);
+        }
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+    }
+
+    String::from_utf8(head)
+        .map(Some)
+        .map_err(|_| HttpError::Malformed("headers are not valid UTF-8"))
+}
+
+pub enum RouteMatch<'a, H> {
+    Found(&'a H, HashMap<String, String>),
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+struct Route<H> {
+    method: Method,
+    segments: Vec<String>,
+    handler: H,
+}
+
+// Routes are matched in the order they were added. Pattern segments starting
+// with ':' match any single segment and are returned as path parameters.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn add(&mut self, method: Method, pattern: &str, handler: H) -> &mut Self {
+        self.routes.push(Route {
+            method,
+            segments: split_path(pattern),
+            handler,
+        });
+        self
+    }
+
+    pub fn find(&self, method: Method, path: &str) -> RouteMatch<'_, H> {
+        let segments = split_path(path);
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, &segments) {
+                Some(params) => params,
+                None => continue,
+            };
+            // HEAD is answered by GET handlers
+            if route.method == method || (method == Method::Head && route.method == Method::Get) {
+                return RouteMatch::Found(&route.handler, params);
+            }
+            allowed.push(route.method);
+        }
+
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn match_segments(pattern: &[String], path: &[String]) -> Option<HashMap<String, String>> {
+    if pattern.len() != path.len() {
+        return None;
+    }
+
+    let mut params = HashMap::new();
+    for (expected, actual) in pattern.iter().zip(path) {
+        match expected.strip_prefix(':') {
+            Some(name) => {
+                params.insert(name.to_string(), percent_decode(actual, false));
+            }
+            None if expected == actual => {}
+            None => return None,
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(raw: &str) -> Result<Option<HttpRequest>, HttpError> {
+        read_request(&mut Cursor::new(raw.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn request_with_body_and_query_is_parsed() {
+        let request = read(
+            "POST /lobbies?name=big+blind&seats=6 HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Content-Length: 5\r\n\
+             \r\n\
+             hello",
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/lobbies");
+        assert_eq!(request.query["name"], "big blind");
+        assert_eq!(request.query["seats"], "6");
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn requests_of_one_connection_are_read_one_by_one() {
+        let mut reader = Cursor::new(
+            b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\n\r\n".to_vec(),
+        );
+
+        let first = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            (first.path.as_str(), first.body.as_slice()),
+            ("/a", &b"abc"[..])
+        );
+        let second = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!((second.path.as_str(), second.body.len()), ("/b", 0));
+        assert!(read_request(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_requests_are_refused() {
+        let oversized = format!(
+            "GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(MAX_HEADERS_SIZE)
+        );
+        let too_long_body = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let cases: Vec<(&str, String, &str)> = vec![
+            (
+                "no version",
+                "GET /\r\n\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "extra part in request line",
+                "GET / HTTP/1.1 x\r\n\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "HTTP/2",
+                "GET / HTTP/2\r\n\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "relative path",
+                "GET lobbies HTTP/1.1\r\n\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "header without colon",
+                "GET / HTTP/1.1\r\nHost localhost\r\n\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "closed inside headers",
+                "GET / HTTP/1.1\r\nHost: localhost\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "invalid Content-Length",
+                "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n".into(),
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "unknown method",
+                "BREW / HTTP/1.1\r\n\r\n".into(),
+                "HTTP/1.1 501 Not Implemented",
+            ),
+            (
+                "chunked body",
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n".into(),
+                "HTTP/1.1 501 Not Implemented",
+            ),
+            (
+                "oversized headers",
+                oversized,
+                "HTTP/1.1 431 Request Header Fields Too Large",
+            ),
+            (
+                "oversized body",
+                too_long_body,
+                "HTTP/1.1 413 Payload Too Large",
+            ),
+        ];
+
+        for (name, raw, status_line) in cases {
+            match read(&raw) {
+                Ok(_) => panic!("{}: accepted", name),
+                Err(e) => assert_eq!(e.status_line(), Some(status_line), "{}: {}", name, e),
+            }
+        }
+    }
+
+    #[test]
+    fn body_shorter_than_content_length_is_a_broken_connection() {
+        let result = read("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc");
+        assert!(matches!(result, Err(HttpError::Io(_))));
+        assert!(result.unwrap_err().status_line().is_none());
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_header() {
+        let cases = [
+            ("HTTP/1.1", None, true),
+            ("HTTP/1.1", Some("close"), false),
+            ("HTTP/1.1", Some("Close"), false),
+            ("HTTP/1.0", None, false),
+            ("HTTP/1.0", Some("Keep-Alive"), true),
+        ];
+        for (version, connection, expected) in cases {
+            let mut raw = format!("GET / {}\r\n", version);
+            if let Some(connection) = connection {
+                raw.push_str(&format!("Connection: {}\r\n", connection));
+            }
+            raw.push_str("\r\n");
+            let request = parse_head(&raw).unwrap();
+            assert_eq!(
+                request.keep_alive(),
+                expected,
+                "{} {:?}",
+                version,
+                connection
+            );
+        }
+    }
+
+    #[test]
+    fn keep_alive_slots_are_limited_and_released() {
+        let limit = KeepAliveLimit::new(2);
+        let first = limit.try_acquire();
+        let second = limit.try_acquire();
+        assert!(first.is_some() && second.is_some());
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+        assert!(KeepAliveLimit::new(0).try_acquire().is_none());
+    }
+
+    #[test]
+    fn routes_are_matched_with_path_params() {
+        let mut router: Router<&str> = Router::new();
+        router
+            .add(Method::Get, "/lobbies", "list")
+            .add(Method::Post, "/lobbies/:lobby_id/start", "start")
+            .add(Method::Get, "/players/:user_id/stats", "stats");
+
+        let found = |method: Method, path: &str| match router.find(method, path) {
+            RouteMatch::Found(handler, params) => Some((*handler, params)),
+            _ => None,
+        };
+
+        let (handler, params) = found(Method::Post, "/lobbies/7/start").unwrap();
+        assert_eq!((handler, params["lobby_id"].as_str()), ("start", "7"));
+        // trailing and doubled slashes don't matter
+        assert_eq!(found(Method::Get, "//lobbies/").unwrap().0, "list");
+        // HEAD is served by GET
+        assert_eq!(found(Method::Head, "/lobbies").unwrap().0, "list");
+
+        // percent escapes are decoded, '+' is a plain character outside of the query
+        let (_, params) = found(Method::Get, "/players/a+b%20c/stats").unwrap();
+        assert_eq!(params["user_id"], "a+b c");
+
+        assert!(matches!(
+            router.find(Method::Delete, "/lobbies"),
+            RouteMatch::MethodNotAllowed(allowed) if allowed == vec![Method::Get]
+        ));
+        assert!(matches!(
+            router.find(Method::Get, "/lobbies/7"),
+            RouteMatch::NotFound
+        ));
+        assert!(matches!(
+            router.find(Method::Get, "/players/1/stats/extra"),
+            RouteMatch::NotFound
+        ));
+    }
+
+    #[test]
+    fn broken_percent_escapes_are_kept() {
+        let query = parse_query("a=100%&b=%zz&c=%41");
+        assert_eq!(query["a"], "100%");
+        assert_eq!(query["b"], "%zz");
+        assert_eq!(query["c"], "A");
+    }
+}
@@ -1,13 +1,30 @@
+pub mod auth;
 pub mod card;
+pub mod config;
 pub mod dealer;
 pub mod dealer_pool;
+pub mod engine;
+pub mod equity;
 pub mod game;
+pub mod game_actor;
 pub mod game_orchestrator;
+pub mod game_snapshot;
+pub mod hand_history;
+pub mod http;
 pub mod lobby;
+pub mod memory_repository;
+pub mod message_queue;
+pub mod migrations;
 pub mod player;
+pub mod player_stats;
 pub mod postgres_database;
+pub mod repository;
 pub mod responses;
+pub mod simulation;
 pub mod socket_pool;
+pub mod spectator_pool;
+pub mod state_delta;
+pub mod table_chat;
 pub mod thread_pool;
 
 pub mod protos {
@@ -23,6 +40,26 @@ pub mod protos {
         include!("protos_rs/game_state.rs");
     }
 
+    pub mod game_snapshot {
+        include!("protos_rs/game_snapshot.rs");
+    }
+
+    pub mod hand_history {
+        include!("protos_rs/hand_history.rs");
+    }
+
+    pub mod player_stats {
+        include!("protos_rs/player_stats.rs");
+    }
+
+    pub mod equity {
+        include!("protos_rs/equity.rs");
+    }
+
+    pub mod state_delta {
+        include!("protos_rs/state_delta.rs");
+    }
+
     pub mod empty {
         include!("protos_rs/empty.rs");
     }
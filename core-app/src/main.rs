@@ -1,35 +1,52 @@
 use fun_poker::{
-    dealer_pool::DealerPool,
-    game::GameSettings,
-    game_orchestrator::GameOrchestrator,
+    auth::{AuthError, JwtVerifier},
+    config::{Config, DatabaseConfig, RepositoryBackend},
+    equity::calculate_equity,
+    game_actor::ActorRuntime,
+    game_orchestrator::{GameOrchestrator, StartGameError},
+    game_snapshot::SnapshotStore,
+    hand_history::HandHistoryStore,
+    http::{
+        parse_head, read_request, HttpRequest, KeepAliveLimit, Method, RouteMatch, Router,
+        MAX_HEADERS_SIZE,
+    },
+    memory_repository::InMemoryRepository,
+    message_queue::MessageQueue,
+    player_stats::calculate_stats,
     postgres_database::PostgresDatabase,
     protos::{
-        requests::{
-            CreateLobbyRequest, JoinLobbyRequest, ObserveLobbyRequest, SpawnBotRequest,
-            StartGameRequest,
-        },
+        equity::EquityRequest,
+        requests::{CreateLobbyRequest, ResyncRequest, SpawnBotRequest, StartGameRequest},
+        responses::{ResponseMessage, ResponseMessageType, ServerShutdownMessage},
         user::User,
     },
+    repository::{DbError, Repository},
     responses::EncodableMessage,
-    socket_pool::{ConnectionClosedEvent, PlayerChannelClient, SocketPool},
+    socket_pool::{ChatEvent, ConnectionClosedEvent, PlayerChannelClient, SendEvent, SocketPool},
     thread_pool::ThreadPool,
 };
 use prost::{DecodeError, Message};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 use std::env;
 
 use std::{
-    collections::HashMap,
-    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    fmt,
+    io::{BufReader, Write},
     net::{TcpListener, TcpStream},
+    path::PathBuf,
     sync::Arc,
+    thread,
+    time::Duration,
 };
 
-use tungstenite::accept;
-
-enum RequestType {
-    Http,
+use tungstenite::{
+    accept_hdr,
+    handshake::server::{Callback, ErrorResponse, Request, Response},
     WebSocket,
-}
+};
 
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -38,443 +55,959 @@ pub struct EmptyMessage {}
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <IP:PORT>", args[0]);
-        std::process::exit(1);
+    let (config_path, address) = match parse_args(&args[1..]) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: {} [--config <PATH>] [IP:PORT]", args[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let mut config = match Config::load(config_path.as_deref()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // several instances run behind nginx from one config, see nginx.conf
+    if let Some(address) = address {
+        config.server.address = address;
     }
 
-    let address = &args[1];
+    // tokens are issued by auth-service, both have to share the secret and the issuer
+    let jwt_verifier = match JwtVerifier::from_env() {
+        Ok(v) => Arc::new(v),
+        Err(name) => {
+            eprintln!("{} environment variable is required", name);
+            std::process::exit(1);
+        }
+    };
+
+    let arc_repo = create_repository(&config.database);
+    let listener = TcpListener::bind(&config.server.address).unwrap();
+    let offline_message_ttl = Duration::from_secs(config.timeouts.offline_message_ttl_seconds);
+    let socket_pool = SocketPool::with_offline_queue(MessageQueue::new(offline_message_ttl));
 
-    // TODO: add multiple db connections for concurrency
-    // r2d2 or deadpool-postgres or self implementation
-    let repository: PostgresDatabase = PostgresDatabase::new().unwrap();
-    let listener = TcpListener::bind(address).unwrap();
-    let socket_pool = SocketPool::new();
-    let dealer_pool = DealerPool::new();
+    let snapshot_store: Arc<dyn SnapshotStore> = arc_repo.clone();
+    let hand_store: Arc<dyn HandHistoryStore> = arc_repo.clone();
+
+    let spectator_delay = Duration::from_secs(config.lobby.spectator_delay_seconds);
 
-    let game_orchestrator = GameOrchestrator::new();
-    let arc_dealer_pool = Arc::new(dealer_pool);
     let arc_socket_pool: Arc<SocketPool> = Arc::new(socket_pool);
-    let arc_game_orchestrator: Arc<GameOrchestrator> = Arc::new(game_orchestrator);
 
-    let pool = ThreadPool::new(20);
+    let pool = ThreadPool::new(config.server.threads);
     let arc_thread_pool: Arc<ThreadPool> = Arc::new(pool);
-    let arc_repo: Arc<PostgresDatabase> = Arc::new(repository);
 
-    setup_sockets_health_checker(&arc_game_orchestrator, &arc_socket_pool);
+    let game_orchestrator = GameOrchestrator::new(
+        Some(snapshot_store),
+        Some(hand_store),
+        spectator_delay,
+        config.game_settings(),
+        Arc::clone(&arc_socket_pool),
+        Arc::clone(&arc_thread_pool),
+        ActorRuntime::new(config.server.game_threads),
+    );
+    let restored_games = game_orchestrator.restore_games();
+    println!("restored {} games from snapshots", restored_games);
+
+    let arc_game_orchestrator: Arc<GameOrchestrator> = Arc::new(game_orchestrator);
+
+    setup_socket_listeners(&arc_game_orchestrator, &arc_socket_pool);
+
+    let shutdown_timeout = Duration::from_secs(config.timeouts.shutdown_seconds);
+
+    let context = Arc::new(AppContext {
+        repo: arc_repo,
+        socket_pool: arc_socket_pool,
+        game_orchestrator: arc_game_orchestrator,
+        jwt_verifier,
+        router: routes(),
+        ws_router: ws_routes(),
+        keep_alive: KeepAliveLimit::new(config.server.threads / KEEP_ALIVE_SHARE),
+    });
+
+    setup_shutdown_handler(Arc::clone(&context), shutdown_timeout);
 
     for stream in listener.incoming() {
-        // TODO: think about dependecy injection pattern to decrease arguments amount
         let stream = stream.unwrap();
-        let clone_game_orchestrator = Arc::clone(&arc_game_orchestrator);
-        let clone_pool = Arc::clone(&arc_thread_pool);
-        let clone_repo = Arc::clone(&arc_repo);
-        let clone_dealer_pool = Arc::clone(&arc_dealer_pool);
-        let clone_socket_pool = Arc::clone(&arc_socket_pool);
+        let clone_context = Arc::clone(&context);
         arc_thread_pool.execute(move || {
-            handle_connection(
-                stream,
-                clone_repo,
-                clone_socket_pool,
-                clone_dealer_pool,
-                clone_pool,
-                clone_game_orchestrator,
-            );
+            handle_connection(stream, &clone_context);
         });
     }
 }
 
-fn setup_sockets_health_checker(
+// returns the --config path and the address given as a positional argument
+fn parse_args(args: &[String]) -> Result<(Option<PathBuf>, Option<String>), String> {
+    let mut config_path = None;
+    let mut address = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(path) => config_path = Some(PathBuf::from(path)),
+                None => return Err(String::from("--config needs a path")),
+            },
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if address.is_none() => address = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok((config_path, address))
+}
+
+// memory backend runs without a database, everything is lost on restart
+fn create_repository(config: &DatabaseConfig) -> Arc<dyn Repository> {
+    match config.backend {
+        RepositoryBackend::Memory => {
+            println!("using in-memory repository");
+            Arc::new(InMemoryRepository::with_demo_users())
+        }
+        // every request takes its own connection from the pool
+        RepositoryBackend::Postgres => {
+            let database = match PostgresDatabase::new(config) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("failed to connect to the database: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            // the queries are useless against a schema they don't match
+            match database.migrate() {
+                Ok(applied) => println!("database is up to date, {} migrations applied", applied),
+                Err(e) => {
+                    eprintln!("failed to migrate the database: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            Arc::new(database)
+        }
+    }
+}
+
+// for the notice and close frames after the games are stopped
+const SOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// SIGTERM/SIGINT drain the server: no new joins, running hands finish or get refunded,
+// final stacks are persisted, clients get the notice and their sockets are closed
+fn setup_shutdown_handler(context: Arc<AppContext>, timeout: Duration) {
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("failed to register signal handlers: {}", e);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            println!("received signal {}, shutting down", signal);
+
+            let notice = ResponseMessage {
+                payload: ServerShutdownMessage {
+                    reason: String::from("server restarting"),
+                }
+                .encode_message(),
+                payload_type: ResponseMessageType::ServerShutdown.into(),
+            };
+            context.game_orchestrator.shutdown(timeout);
+            context.socket_pool.close_all(notice, SOCKET_CLOSE_TIMEOUT);
+
+            println!("shutdown complete");
+            std::process::exit(0);
+        }
+    });
+}
+
+fn setup_socket_listeners(
     game_orchestrator: &Arc<GameOrchestrator>,
     socket_pool: &Arc<SocketPool>,
 ) {
-    let socket_event_listener = socket_pool.spawn_health_checker();
+    let game_o = Arc::clone(game_orchestrator);
+
+    socket_pool.add_connection_closed_listener(Box::new(move |e: ConnectionClosedEvent| {
+        game_o.update_player_connection_status(e);
+    }));
 
     let game_o = Arc::clone(game_orchestrator);
-    let socket_o = Arc::clone(socket_pool);
+    socket_pool.add_send_listener(Box::new(move |e: SendEvent| {
+        game_o.handle_send_event(e);
+    }));
 
-    let on_connection_closed = Box::new(move |e: ConnectionClosedEvent| {
-        game_o.update_player_connection_status(e, &socket_o);
-    });
+    let game_o = Arc::clone(game_orchestrator);
+    socket_pool.add_chat_listener(Box::new(move |e: ChatEvent| {
+        game_o.post_chat(e);
+    }));
+}
+
+// Websocket handlers get the upgraded socket. The handshake is already verified by then:
+// token, route and the lobby id for routes which need it.
+type WsHandler = fn(WebSocket<TcpStream>, WsConnection, &AppContext);
+
+// how long to wait for the client to send the whole request head
+const HANDSHAKE_PEEK_ATTEMPTS: usize = 50;
 
-    socket_event_listener
-        .lock()
-        .unwrap()
-        .push(on_connection_closed);
+struct WsRoute {
+    needs_lobby: bool,
+    // rejected before the upgrade when the lobby has no game
+    needs_game: bool,
+    handler: WsHandler,
 }
 
-fn determine_request_type(stream: &TcpStream) -> RequestType {
-    let mut buffer = [0; 1024];
-    let result = stream.peek(&mut buffer);
+struct WsConnection {
+    user_id: i32,
+    lobby_id: Option<i32>,
+}
 
-    match result {
-        Ok(_) => {
-            let request_str = String::from_utf8_lossy(&buffer);
-            // improve checking for websocket handshake
-            if request_str.contains("Upgrade: websocket") {
-                RequestType::WebSocket
-            } else {
-                RequestType::Http
-            }
+fn ws_routes() -> Router<WsRoute> {
+    let mut router: Router<WsRoute> = Router::new();
+    router
+        .add(
+            Method::Get,
+            "/join_lobby",
+            WsRoute {
+                needs_lobby: true,
+                needs_game: false,
+                handler: join_lobby_ws_handler,
+            },
+        )
+        .add(
+            Method::Get,
+            "/observe",
+            WsRoute {
+                needs_lobby: true,
+                needs_game: true,
+                handler: observe_ws_handler,
+            },
+        )
+        // older clients
+        .add(
+            Method::Get,
+            "/observe_lobby",
+            WsRoute {
+                needs_lobby: true,
+                needs_game: true,
+                handler: observe_ws_handler,
+            },
+        )
+        .add(
+            Method::Get,
+            "/lobby_feed",
+            WsRoute {
+                needs_lobby: false,
+                needs_game: false,
+                handler: lobby_feed_ws_handler,
+            },
+        )
+        // frontend connects to /ws after login
+        .add(
+            Method::Get,
+            "/ws",
+            WsRoute {
+                needs_lobby: false,
+                needs_game: false,
+                handler: lobby_feed_ws_handler,
+            },
+        );
+    router
+}
+
+// Looks at the request head without consuming it, so either the HTTP parser
+// or the websocket handshake can read the request afterwards
+fn peek_request_head(stream: &TcpStream) -> Option<String> {
+    let mut buffer = vec![0; MAX_HEADERS_SIZE];
+
+    for _ in 0..HANDSHAKE_PEEK_ATTEMPTS {
+        let read = stream.peek(&mut buffer).ok()?;
+        if read == 0 {
+            return None;
+        }
+        if let Some(end) = buffer[..read].windows(4).position(|w| w == b"\r\n\r\n") {
+            return String::from_utf8(buffer[..end + 4].to_vec()).ok();
         }
-        Err(_) => RequestType::Http,
+        if read == buffer.len() {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(10));
     }
+    None
 }
 
-fn get_path_from_uri(uri: &str) -> &str {
-    uri.split("?").next().unwrap()
+// Token comes either as `token` query parameter or, for browsers which can't set headers,
+// as one of the values of Sec-WebSocket-Protocol. Returns the token and the protocol to echo back.
+fn extract_token(request: &HttpRequest) -> Option<(String, Option<String>)> {
+    if let Some(token) = request.query.get("token") {
+        return Some((token.clone(), None));
+    }
+
+    request
+        .header("sec-websocket-protocol")?
+        .split(',')
+        .map(|protocol| protocol.trim())
+        .find(|protocol| protocol.matches('.').count() == 2)
+        .map(|token| (token.to_string(), Some(token.to_string())))
 }
 
-fn handle_web_socket_connection_handshake(stream: TcpStream, socket_pool: Arc<SocketPool>, thread_pool: Arc<ThreadPool>, game_orchestrator: Arc<GameOrchestrator>,repo: Arc<PostgresDatabase>) {
-    let mut buffer = [0; 1024];
-    let result: Result<usize, std::io::Error> = stream.peek(&mut buffer);
-    let request_str = String::from_utf8_lossy(&buffer);
+// browsers drop the connection unless the requested protocol is echoed back
+struct EchoProtocol(Option<String>);
 
-    let request_uri = request_str.lines().next().unwrap();
-    let uri = request_uri.split(" ").skip(1).next().unwrap();
-    let path = get_path_from_uri(uri);
-    let map = parse_queries_from_uri(uri);
-   
-    let user_id: i32 = match result {
-        Ok(_) => {
-            // TODO: implement JWT verifier
-            let user_id = map.get("user_id").unwrap();
-            i32::from_str_radix(user_id, 10).unwrap()
+impl Callback for EchoProtocol {
+    fn on_request(self, _: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        if let Some(protocol) = self.0 {
+            if let Ok(value) = protocol.parse() {
+                response
+                    .headers_mut()
+                    .insert("Sec-WebSocket-Protocol", value);
+            }
         }
-        Err(_) => todo!("Bad request"),
-    };
+        Ok(response)
+    }
+}
 
-    stream.set_nonblocking(true).unwrap();
-    let websocket = accept(stream).unwrap();
+// answers the handshake with a plain HTTP error, the connection is never upgraded
+fn reject_handshake(mut stream: TcpStream, status_line: &'static str, reason: &dyn fmt::Display) {
+    println!("websocket handshake rejected: {}", reason);
 
-    socket_pool.add(PlayerChannelClient {
-        client_id: user_id,
-        socket: websocket,
-    });
-
-    if path == "/join_lobby" {
-        let lobby_id = map.get("lobby_id").unwrap();
-        let lobby_id = i32::from_str_radix(lobby_id, 10).unwrap();
-        join_lobby_request_socket_handler(lobby_id,user_id, repo, game_orchestrator, socket_pool, thread_pool);
+    let mut reply = HttpReply::empty(status_line);
+    if status_line == "HTTP/1.1 401 Unauthorized" {
+        reply
+            .headers
+            .push(("WWW-Authenticate", String::from("Bearer")));
+    }
+    if let Err(e) = stream.write_all(&construct_response(reply, false, false)) {
+        println!("failed to send handshake rejection: {}", e);
     }
 }
 
-fn handle_http_request(
-    mut stream: TcpStream,
-    repo: Arc<PostgresDatabase>,
-    socket_pool: Arc<SocketPool>,
-    _dealer_pool: Arc<DealerPool>,
-    thread_pool: Arc<ThreadPool>,
-    game_orchestrator: Arc<GameOrchestrator>,
+fn handle_web_socket_connection_handshake(
+    stream: TcpStream,
+    request: HttpRequest,
+    context: &AppContext,
 ) {
-    let mut buf_reader = BufReader::new(&stream);
+    let route = match context.ws_router.find(request.method, &request.path) {
+        RouteMatch::Found(route, _) => route,
+        RouteMatch::MethodNotAllowed(_) => {
+            return reject_handshake(stream, "HTTP/1.1 405 Method Not Allowed", &request.path)
+        }
+        RouteMatch::NotFound => {
+            return reject_handshake(stream, "HTTP/1.1 404 Not Found", &request.path)
+        }
+    };
+    if context.game_orchestrator.is_draining() {
+        return reject_handshake(
+            stream,
+            "HTTP/1.1 503 Service Unavailable",
+            &"server is shutting down",
+        );
+    }
 
-    let request_line = buf_reader.by_ref().lines().next().unwrap().unwrap();
+    // user id comes only from a verified token, never from the query
+    let (token, protocol) = match extract_token(&request) {
+        Some(v) => v,
+        None => {
+            return reject_handshake(
+                stream,
+                "HTTP/1.1 401 Unauthorized",
+                &AuthError::MissingToken,
+            )
+        }
+    };
+    let user_id: i32 = match context.jwt_verifier.verify(&token) {
+        Ok(claims) => claims.user_id,
+        Err(e) => return reject_handshake(stream, "HTTP/1.1 401 Unauthorized", &e),
+    };
 
-    let status_line = "HTTP/1.1 200 OK";
+    let lobby_id = if route.needs_lobby {
+        match request.query.get("lobby_id").map(|v| v.parse::<i32>()) {
+            Some(Ok(v)) => Some(v),
+            _ => {
+                return reject_handshake(
+                    stream,
+                    "HTTP/1.1 400 Bad Request",
+                    &"lobby_id is missing or invalid",
+                )
+            }
+        }
+    } else {
+        None
+    };
 
-    let path = request_line.split(" ").skip(1).next().unwrap();
+    if let (true, Some(lobby_id)) = (route.needs_game, lobby_id) {
+        if !context.game_orchestrator.is_game_exists(lobby_id) {
+            return reject_handshake(
+                stream,
+                "HTTP/1.1 404 Not Found",
+                &format!("lobby {} has no game", lobby_id),
+            );
+        }
+    }
 
-    let (message, status_line): (Box<dyn EncodableMessage>, &str) = match path {
-        "/createLobby" => create_lobby_handler(buf_reader, repo, game_orchestrator),
-        "/getLobbies" => (Box::new(repo.get_lobbies()), &status_line),
-        "/startGame" => start_game_request_handler(
-            buf_reader,
-            repo,
-            socket_pool,
-            thread_pool,
-            game_orchestrator,
-        ),
-        // "/join_lobby" => join_lobby_request_handler(
-        //     buf_reader,
-        //     repo,
-        //     game_orchestrator,
-        //     socket_pool,
-        //     thread_pool,
-        // ),
-        "/spawnAIBot" => spawn_ai_bot_handler(buf_reader, game_orchestrator, socket_pool),
-        // "/observeLobby" => observe_lobby_request_handler(buff_reader),
-        _ => (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    // handshake runs in blocking mode, bounded by the read timeout of the connection.
+    // The socket pool makes the socket non blocking once it takes it over
+    let websocket = match accept_hdr(stream, EchoProtocol(protocol)) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("websocket handshake of user {} failed: {}", user_id, e);
+            return;
+        }
     };
 
-    let response = construct_response(status_line, message);
+    if let Err(e) = websocket.get_ref().set_read_timeout(None) {
+        println!("failed to reset read timeout: {}", e);
+        return;
+    }
 
-    stream.write_all(&response).unwrap();
+    (route.handler)(websocket, WsConnection { user_id, lobby_id }, context);
 }
 
-fn _observe_lobby_request_handler(buf_reader: BufReader<&TcpStream>) {
-    let decode_fn = |cursor: &mut Cursor<&[u8]>| ObserveLobbyRequest::decode(cursor);
+// spectators have no seat, they get only the public view of the table
+fn observe_ws_handler(
+    websocket: WebSocket<TcpStream>,
+    connection: WsConnection,
+    context: &AppContext,
+) {
+    let lobby_id = connection.lobby_id.unwrap_or_default();
+    if !context
+        .game_orchestrator
+        .observe_game(lobby_id, connection.user_id, websocket)
+    {
+        println!(
+            "user {} tried to observe missing lobby {}",
+            connection.user_id, lobby_id
+        );
+    }
+}
 
-    let result = parse_message(buf_reader, decode_fn);
+// one socket per table, so a player can sit at several tables at once
+fn join_lobby_ws_handler(
+    websocket: WebSocket<TcpStream>,
+    connection: WsConnection,
+    context: &AppContext,
+) {
+    context.socket_pool.add(PlayerChannelClient {
+        client_id: connection.user_id,
+        lobby_id: connection.lobby_id,
+        socket: websocket,
+    });
 
-    let _request = match result {
-        Ok(v) => v,
-        _ => todo!(),
-    };
+    join_lobby_request_socket_handler(
+        connection.lobby_id.unwrap_or_default(),
+        connection.user_id,
+        Arc::clone(&context.repo),
+        Arc::clone(&context.game_orchestrator),
+    );
 }
 
-fn spawn_ai_bot_handler(
-    buf_reader: BufReader<&TcpStream>,
-    game_orchestrator: Arc<GameOrchestrator>,
-    socket_pool: Arc<SocketPool>,
-) -> (Box<dyn EncodableMessage>, &str) {
-    let decode_fn = |cursor: &mut Cursor<&[u8]>| SpawnBotRequest::decode(cursor);
+// connection without a table: game start notices and queued messages
+fn lobby_feed_ws_handler(
+    websocket: WebSocket<TcpStream>,
+    connection: WsConnection,
+    context: &AppContext,
+) {
+    context.socket_pool.add(PlayerChannelClient {
+        client_id: connection.user_id,
+        lobby_id: None,
+        socket: websocket,
+    });
+}
 
-    let result = parse_message(buf_reader, decode_fn);
+// HTTP handlers get the parsed request, shared services and the id of the authenticated user
+type Handler = fn(&HttpRequest, &AppContext, i32) -> (Box<dyn EncodableMessage>, &'static str);
 
-    let request = match result {
-        Ok(v) => v,
-        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
-    };
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+// at most this part of the workers may wait on idle keep-alive connections
+const KEEP_ALIVE_SHARE: usize = 4;
 
-    game_orchestrator.spawn_bot(request.lobby_id, &socket_pool);
+// Everything connection handlers need, shared by all connections
+struct AppContext {
+    repo: Arc<dyn Repository>,
+    socket_pool: Arc<SocketPool>,
+    game_orchestrator: Arc<GameOrchestrator>,
+    jwt_verifier: Arc<JwtVerifier>,
+    router: Router<Handler>,
+    ws_router: Router<WsRoute>,
+    keep_alive: KeepAliveLimit,
+}
 
-    (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
+fn routes() -> Router<Handler> {
+    let mut router: Router<Handler> = Router::new();
+    router
+        .add(Method::Get, "/getLobbies", get_lobbies_handler)
+        .add(Method::Post, "/createLobby", create_lobby_handler)
+        .add(Method::Post, "/startGame", start_game_request_handler)
+        .add(
+            Method::Post,
+            "/lobbies/:lobby_id/start",
+            start_game_request_handler,
+        )
+        .add(Method::Post, "/spawnAIBot", spawn_ai_bot_handler)
+        .add(
+            Method::Post,
+            "/lobbies/:lobby_id/bots",
+            spawn_ai_bot_handler,
+        )
+        .add(Method::Post, "/equity", equity_handler)
+        .add(Method::Get, "/players/:user_id/stats", player_stats_handler)
+        .add(Method::Post, "/resync", resync_handler);
+    router
 }
 
-fn parse_message<T, F>(mut buf_reader: BufReader<&TcpStream>, decode: F) -> Result<T, DecodeError>
-where
-    T: Message,
-    F: for<'a> FnOnce(&'a mut Cursor<&'a [u8]>) -> Result<T, DecodeError>,
-{
-    let body_start = get_body_buffer_position(&buf_reader);
+struct HttpReply {
+    status_line: &'static str,
+    message: Box<dyn EncodableMessage>,
+    headers: Vec<(&'static str, String)>,
+}
 
-    let buffer = buf_reader.fill_buf().unwrap();
+impl HttpReply {
+    fn new(status_line: &'static str, message: Box<dyn EncodableMessage>) -> Self {
+        HttpReply {
+            status_line,
+            message,
+            headers: Vec::new(),
+        }
+    }
 
-    let mut cursor = Cursor::new(buffer);
-    cursor.seek(SeekFrom::Start(body_start as u64)).unwrap();
+    fn empty(status_line: &'static str) -> Self {
+        Self::new(status_line, Box::new(EmptyMessage {}))
+    }
+}
 
-    let result: Result<T, DecodeError> = decode(&mut cursor);
+fn handle_http_request(stream: TcpStream, context: &AppContext) {
+    let mut writer = match stream.try_clone() {
+        Ok(v) => v,
+        Err(e) => {
+            println!("failed to clone http stream: {}", e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+
+    // keep-alive: several requests may come over one connection, as long as a slot is free
+    let mut keep_alive_slot = None;
+    for served in 1..=MAX_REQUESTS_PER_CONNECTION {
+        let mut request = match read_request(&mut reader) {
+            Ok(Some(v)) => v,
+            Ok(None) => return,
+            Err(e) => {
+                println!("bad http request: {}", e);
+                if let Some(status_line) = e.status_line() {
+                    let response = construct_response(HttpReply::empty(status_line), false, false);
+                    let _ = writer.write_all(&response);
+                }
+                return;
+            }
+        };
 
-    result
+        if keep_alive_slot.is_none() && request.keep_alive() {
+            keep_alive_slot = context.keep_alive.try_acquire();
+        }
+        let keep_alive = keep_alive_slot.is_some()
+            && request.keep_alive()
+            && served < MAX_REQUESTS_PER_CONNECTION;
+        let is_head = request.method == Method::Head;
+        let reply = route_request(&mut request, context);
+
+        let response = construct_response(reply, keep_alive, is_head);
+        if let Err(e) = writer.write_all(&response) {
+            println!("failed to send http response: {}", e);
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+    }
 }
 
-fn create_lobby_handler(
-    buf_reader: BufReader<&TcpStream>,
-    repo: Arc<PostgresDatabase>,
-    game_orchestrator: Arc<GameOrchestrator>,
-) -> (Box<dyn EncodableMessage>, &str) {
-    let decode_fn = |cursor: &mut Cursor<&[u8]>| CreateLobbyRequest::decode(cursor);
+fn route_request(request: &mut HttpRequest, context: &AppContext) -> HttpReply {
+    if request.is_websocket_upgrade() {
+        // upgrade is only accepted as the first request of a connection
+        return HttpReply::empty("HTTP/1.1 400 Bad Request");
+    }
 
-    let result = parse_message(buf_reader, decode_fn);
+    let (handler, params) = match context.router.find(request.method, &request.path) {
+        RouteMatch::Found(handler, params) => (*handler, params),
+        RouteMatch::MethodNotAllowed(allowed) => {
+            let allowed: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+            let allowed = format!("{}, OPTIONS", allowed.join(", "));
 
-    let create_lobby_request = match result {
-        Ok(v) => v,
-        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+            let mut reply = if request.method == Method::Options {
+                // CORS preflight, answered without authentication
+                HttpReply::empty("HTTP/1.1 204 No Content")
+            } else {
+                HttpReply::empty("HTTP/1.1 405 Method Not Allowed")
+            };
+            reply.headers.push(("Allow", allowed.clone()));
+            reply
+                .headers
+                .push(("Access-Control-Allow-Methods", allowed));
+            reply.headers.push((
+                "Access-Control-Allow-Headers",
+                String::from("Authorization, Content-Type"),
+            ));
+            reply
+                .headers
+                .push(("Access-Control-Max-Age", String::from("86400")));
+            return reply;
+        }
+        RouteMatch::NotFound => return HttpReply::empty("HTTP/1.1 404 Not Found"),
+    };
+    request.params = params;
+
+    // every route needs a token of auth-service, the user id is taken from it
+    let verified = request
+        .bearer_token()
+        .ok_or(AuthError::MissingToken)
+        .and_then(|token| context.jwt_verifier.verify(token));
+    let user_id = match verified {
+        Ok(claims) => claims.user_id,
+        Err(e) => {
+            println!("request to {} rejected: {}", request.path, e);
+            let mut reply = HttpReply::empty("HTTP/1.1 401 Unauthorized");
+            reply
+                .headers
+                .push(("WWW-Authenticate", String::from("Bearer")));
+            return reply;
+        }
     };
 
-    let lobby_id = repo.create_lobby(create_lobby_request.payload.unwrap());
+    let (message, status_line) = handler(request, context, user_id);
+    HttpReply::new(status_line, message)
+}
 
-    let created = game_orchestrator.create_game(lobby_id, GameSettings { blind_size: 100 });
+fn decode_body<T: Message + Default>(request: &HttpRequest) -> Result<T, DecodeError> {
+    T::decode(request.body.as_slice())
+}
 
-    if created {
-        return (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK");
-    } else {
-        return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request");
+// lobby id from the path (/lobbies/:lobby_id/...) wins over the one in the body
+fn path_lobby_id(request: &HttpRequest, body_lobby_id: i32) -> Option<i32> {
+    match request.param("lobby_id") {
+        Some(v) => v.parse::<i32>().ok(),
+        None => Some(body_lobby_id),
     }
 }
 
-fn parse_queries_from_uri(uri: &str) -> HashMap<&str, &str> {
-    let query_start: usize = uri.find("?").unwrap();
+fn get_lobbies_handler(
+    _: &HttpRequest,
+    context: &AppContext,
+    _: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    match context.repo.get_lobbies() {
+        Ok(lobbies) => (Box::new(lobbies), "HTTP/1.1 200 OK"),
+        Err(e) => {
+            eprintln!("failed to load lobbies: {}", e);
+            (Box::new(EmptyMessage {}), db_error_status(&e))
+        }
+    }
+}
 
-    let queries = &uri[query_start + 1..];
+fn db_error_status(e: &DbError) -> &'static str {
+    match e {
+        DbError::NotFound => "HTTP/1.1 404 Not Found",
+        DbError::ConstraintViolation(_) => "HTTP/1.1 409 Conflict",
+        DbError::Unavailable(_) => "HTTP/1.1 503 Service Unavailable",
+        DbError::Query(_) => "HTTP/1.1 500 Internal Server Error",
+    }
+}
 
-    let pairs = queries.split("&");
+fn spawn_ai_bot_handler(
+    http_request: &HttpRequest,
+    context: &AppContext,
+    user_id: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let request = match decode_body::<SpawnBotRequest>(http_request) {
+        Ok(v) => v,
+        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
+    let lobby_id = match path_lobby_id(http_request, request.lobby_id) {
+        Some(v) => v,
+        None => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
 
-    let mut keys_values = HashMap::new();
+    if let Err(status_line) = authorize_lobby_owner(context.repo.as_ref(), lobby_id, user_id) {
+        return (Box::new(EmptyMessage {}), status_line);
+    }
 
-    for pair in pairs {
-        let mut key_value = pair.split("=");
+    if context.game_orchestrator.spawn_bot(lobby_id) {
+        (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
+    } else {
+        (Box::new(EmptyMessage {}), "HTTP/1.1 404 Not Found")
+    }
+}
 
-        let key = key_value.next().unwrap();
-        let value = key_value.next().unwrap();
+fn resync_handler(
+    http_request: &HttpRequest,
+    context: &AppContext,
+    user_id: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let mut request = match decode_body::<ResyncRequest>(http_request) {
+        Ok(v) => v,
+        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
+    request.player_id = user_id;
 
-        keys_values.insert(key, value);
+    // full state itself goes through the websocket, so it's ordered with deltas
+    if context.game_orchestrator.resync_player(request) {
+        (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
+    } else {
+        (Box::new(EmptyMessage {}), "HTTP/1.1 404 Not Found")
     }
-    keys_values
 }
 
-fn _parse_body(mut buf_reader: BufReader<&TcpStream>) -> Vec<u8> {
-    let mut headers = Vec::new();
-
-    loop {
-        let mut line = Vec::new();
-        buf_reader.read_until(b'\n', &mut line).unwrap();
+fn equity_handler(
+    http_request: &HttpRequest,
+    _: &AppContext,
+    _: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let request = match decode_body::<EquityRequest>(http_request) {
+        Ok(v) => v,
+        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
 
-        if line == b"\r\n" {
-            break;
+    match calculate_equity(&request) {
+        Ok(response) => (Box::new(response), "HTTP/1.1 200 OK"),
+        Err(e) => {
+            eprintln!("equity request rejected: {}", e);
+            (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request")
         }
-        headers.extend_from_slice(&line);
     }
+}
 
-    let headers_str = String::from_utf8_lossy(&headers);
-
-    let content_length = headers_str
-        .lines()
-        .find(|line| line.starts_with("Content-Length:"))
-        .and_then(|line| line.split(':').nth(1))
-        .and_then(|len| len.trim().parse::<usize>().ok())
-        .unwrap_or(0);
+// any signed in user can look at the stats of an opponent
+const DEFAULT_STATS_HANDS: usize = 1000;
+const MAX_STATS_HANDS: usize = 10_000;
+
+fn player_stats_handler(
+    http_request: &HttpRequest,
+    context: &AppContext,
+    _: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let user_id = match http_request
+        .param("user_id")
+        .and_then(|v| v.parse::<i32>().ok())
+    {
+        Some(v) => v,
+        None => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
 
-    let mut body = vec![0; content_length];
+    // ?hands=N looks at the last N hands only, the whole history would be loaded otherwise
+    let limit = match http_request.query.get("hands").map(|v| v.parse::<usize>()) {
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_STATS_HANDS),
+        Some(_) => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+        None => DEFAULT_STATS_HANDS,
+    };
 
-    if content_length > 0 {
-        buf_reader.read_exact(&mut body).unwrap();
+    let hands = context
+        .repo
+        .get_user_by_id(user_id)
+        .and_then(|_| context.repo.get_hands_by_user_id(user_id, limit));
+    match hands {
+        Ok(hands) => (
+            Box::new(calculate_stats(user_id, &hands)),
+            "HTTP/1.1 200 OK",
+        ),
+        Err(e) => {
+            eprintln!("failed to load hands of user {}: {}", user_id, e);
+            (Box::new(EmptyMessage {}), db_error_status(&e))
+        }
     }
-
-    body
 }
 
-fn get_body_buffer_position(buf_reader: &BufReader<&TcpStream>) -> usize {
-    let buffer = buf_reader.buffer();
+fn create_lobby_handler(
+    http_request: &HttpRequest,
+    context: &AppContext,
+    user_id: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let mut lobby = match decode_body::<CreateLobbyRequest>(http_request) {
+        Ok(CreateLobbyRequest {
+            payload: Some(lobby),
+        }) => lobby,
+        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
+    // author is whoever holds the token, not whoever is named in the body
+    lobby.author_id = user_id;
+
+    if context.game_orchestrator.is_draining() {
+        return (
+            Box::new(EmptyMessage {}),
+            "HTTP/1.1 503 Service Unavailable",
+        );
+    }
+    let lobby_id = match context.repo.create_lobby(lobby) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("failed to create lobby: {}", e);
+            return (Box::new(EmptyMessage {}), db_error_status(&e));
+        }
+    };
 
-    let headers_end = buffer
-        .windows(4)
-        .position(|window| window == b"\r\n\r\n")
-        .map(|pos| pos + 4)
-        .expect("Headers not found");
+    let created = context.game_orchestrator.create_game(lobby_id);
 
-    headers_end
+    if created {
+        (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
+    } else {
+        (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request")
+    }
 }
 
 fn start_game_request_handler(
-    buf_reader: BufReader<&TcpStream>,
-    repo: Arc<PostgresDatabase>,
-    socket_pool: Arc<SocketPool>,
-    thread_pool: Arc<ThreadPool>,
-    game_orchestrator: Arc<GameOrchestrator>,
-) -> (Box<dyn EncodableMessage>, &str) {
-    let decode_fn = |cursor: &mut Cursor<&[u8]>| StartGameRequest::decode(cursor);
-
-    let request = parse_message(buf_reader, decode_fn).unwrap();
+    http_request: &HttpRequest,
+    context: &AppContext,
+    user_id: i32,
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let request = match decode_body::<StartGameRequest>(http_request) {
+        Ok(v) => v,
+        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
+    let lobby_id = match path_lobby_id(http_request, request.lobby_id) {
+        Some(v) => v,
+        None => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+    };
 
-    // TODO: if game started return error;
-    // TODO: retrieve lobby id, player id from request and validate them;
+    if let Err(status_line) = authorize_lobby_owner(context.repo.as_ref(), lobby_id, user_id) {
+        return (Box::new(EmptyMessage {}), status_line);
+    }
 
     // THINK ABOUT HOW AND WHEN USER SHOULD BE ABLE TO JOIN THE GAME
-    let _users: Vec<User> = repo.get_users_by_lobby_id(request.lobby_id);
-
-    game_orchestrator.start_game(request.lobby_id, thread_pool, socket_pool);
+    let _users: Vec<User> = match context.repo.get_users_by_lobby_id(lobby_id) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("failed to load players of lobby {}: {}", lobby_id, e);
+            return (Box::new(EmptyMessage {}), db_error_status(&e));
+        }
+    };
 
-    (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
+    match context.game_orchestrator.start_game(lobby_id) {
+        Ok(_) => (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK"),
+        Err(e) => {
+            println!("game {} not started: {}", lobby_id, e);
+            let status_line = match e {
+                StartGameError::LobbyNotFound => "HTTP/1.1 404 Not Found",
+                StartGameError::AlreadyRunning => "HTTP/1.1 409 Conflict",
+                StartGameError::ShuttingDown => "HTTP/1.1 503 Service Unavailable",
+                StartGameError::Rejected(_) => "HTTP/1.1 409 Conflict",
+            };
+            (Box::new(EmptyMessage {}), status_line)
+        }
+    }
 }
 
-fn join_lobby_request_socket_handler(lobby_id: i32, user_id: i32,  repo: Arc<PostgresDatabase>,
-    game_orchestrator: Arc<GameOrchestrator>,
-    socket_pool: Arc<SocketPool>,
-    thread_pool: Arc<ThreadPool>) -> (Box<dyn EncodableMessage>, &'static str) {
-        let user = repo.get_user_by_id(user_id);
-
-        repo.add_user_to_lobby(lobby_id, user.id);
-    
-        let game_created = if !game_orchestrator.is_game_exists(lobby_id) {
-            let created =
-                game_orchestrator.create_game(lobby_id, GameSettings { blind_size: 100 });
-    
-            created
-        } else {
-            true
-        };
-        // TODO: think about sending messages to game_orchestrator...
-        if game_created {
-            game_orchestrator.join_game(lobby_id, user, &socket_pool);
-        } else {
-            return (Box::new(EmptyMessage {}), "HTTP/1.1 500 Internal Server Error");
-        }
-    
-    
-        let should_start = game_orchestrator.should_start_game(lobby_id);
-    
-        if should_start {
-            game_orchestrator.start_game(lobby_id, thread_pool, socket_pool)
-        }
-    
-        (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
+// Only the author of the lobby or an admin may manage its game
+fn authorize_lobby_owner(
+    repo: &dyn Repository,
+    lobby_id: i32,
+    user_id: i32,
+) -> Result<(), &'static str> {
+    let author_id = repo
+        .get_lobby_author_id(lobby_id)
+        .map_err(|e| db_error_status(&e))?;
+    if author_id == user_id {
+        return Ok(());
+    }
+
+    match repo.is_admin(user_id) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            println!(
+                "user {} is not allowed to manage lobby {}",
+                user_id, lobby_id
+            );
+            Err("HTTP/1.1 403 Forbidden")
+        }
+        Err(e) => {
+            eprintln!("failed to check privileges of user {}: {}", user_id, e);
+            Err(db_error_status(&e))
+        }
+    }
 }
 
-// TODO: remove
-fn join_lobby_request_handler(
-    buf_reader: BufReader<&TcpStream>,
-    repo: Arc<PostgresDatabase>,
+fn join_lobby_request_socket_handler(
+    lobby_id: i32,
+    user_id: i32,
+    repo: Arc<dyn Repository>,
     game_orchestrator: Arc<GameOrchestrator>,
-    socket_pool: Arc<SocketPool>,
-    thread_pool: Arc<ThreadPool>,
-    // dealer_pool: Arc<DealerPool>,
-) -> (Box<dyn EncodableMessage>, &str) {
-    let decode_fn = |cursor: &mut Cursor<&[u8]>| JoinLobbyRequest::decode(cursor);
-
-    let result = parse_message(buf_reader, decode_fn);
-
-    let request = match result {
+) -> (Box<dyn EncodableMessage>, &'static str) {
+    let user = match repo.get_user_by_id(user_id) {
         Ok(v) => v,
-        _ => return (Box::new(EmptyMessage {}), "HTTP/1.1 400 Bad Request"),
+        Err(e) => {
+            eprintln!("user {} can't join lobby {}: {}", user_id, lobby_id, e);
+            return (Box::new(EmptyMessage {}), db_error_status(&e));
+        }
     };
 
-    let user = repo.get_user_by_id(request.player_id);
-
-    repo.add_user_to_lobby(request.lobby_id, user.id);
-
-    let game_created = if !game_orchestrator.is_game_exists(request.lobby_id) {
-        let created =
-            game_orchestrator.create_game(request.lobby_id, GameSettings { blind_size: 100 });
+    if let Err(e) = repo.add_user_to_lobby(lobby_id, user.id) {
+        eprintln!("user {} can't join lobby {}: {}", user_id, lobby_id, e);
+        return (Box::new(EmptyMessage {}), db_error_status(&e));
+    }
 
-        created
+    let game_created = if !game_orchestrator.is_game_exists(lobby_id) {
+        game_orchestrator.create_game(lobby_id)
     } else {
         true
     };
-    // TODO: think about sending messages to game_orchestrator...
-    if game_created {
-        game_orchestrator.join_game(request.lobby_id, user, &socket_pool);
-    } else {
-        return (Box::new(EmptyMessage {}), "HTTP/1.1 500 Internal Server Error");
+    if !game_created || !game_orchestrator.join_game(lobby_id, user) {
+        return (
+            Box::new(EmptyMessage {}),
+            "HTTP/1.1 500 Internal Server Error",
+        );
     }
 
-
-    let should_start = game_orchestrator.should_start_game(request.lobby_id);
+    let should_start = game_orchestrator.should_start_game(lobby_id);
 
     if should_start {
-        game_orchestrator.start_game(request.lobby_id, thread_pool, socket_pool)
+        // joining a running game only gives the player a seat
+        if let Err(e) = game_orchestrator.start_game(lobby_id) {
+            println!("game {} not started on join: {}", lobby_id, e);
+        }
     }
 
     (Box::new(EmptyMessage {}), "HTTP/1.1 200 OK")
 }
 
-fn handle_connection(
-    stream: TcpStream,
-    repo: Arc<PostgresDatabase>,
-    socket_pool: Arc<SocketPool>,
-    dealer_pool: Arc<DealerPool>,
-    pool: Arc<ThreadPool>,
-    game_orchestrator: Arc<GameOrchestrator>,
-) {
-    let reqest_type = determine_request_type(&stream);
+fn handle_connection(stream: TcpStream, context: &AppContext) {
+    // nobody may hold a worker forever by sending nothing
+    if let Err(e) = stream.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT)) {
+        println!("failed to set read timeout: {}", e);
+        return;
+    }
 
-    match reqest_type {
-        // TODO: refactor naming 
-        RequestType::WebSocket => handle_web_socket_connection_handshake(stream, socket_pool, pool, game_orchestrator, repo),
-        RequestType::Http => handle_http_request(
-            stream,
-            repo,
-            socket_pool,
-            dealer_pool,
-            pool,
-            game_orchestrator,
-        ),
+    let head = peek_request_head(&stream);
+    match head.as_deref().map(parse_head) {
+        Some(Ok(request)) if request.is_websocket_upgrade() => {
+            handle_web_socket_connection_handshake(stream, request, context)
+        }
+        // HTTP parser reads the request again and answers malformed ones itself
+        _ => handle_http_request(stream, context),
     };
 }
 
-fn construct_response(status_line: &str, message: Box<dyn EncodableMessage>) -> Vec<u8> {
-    let buf = message.encode_message();
+fn construct_response(reply: HttpReply, keep_alive: bool, is_head: bool) -> Vec<u8> {
+    let buf = reply.message.encode_message();
     let content_length = buf.len();
 
     let mut response = Vec::new();
-    response.extend_from_slice(status_line.as_bytes());
+    response.extend_from_slice(reply.status_line.as_bytes());
     response.extend_from_slice(b"\r\nContent-Length: ");
     response.extend_from_slice(content_length.to_string().as_bytes());
     response.extend_from_slice(b"\r\nContent-Type: application/octet-stream\r\n");
-    response.extend_from_slice(b"Access-Control-Allow-Origin: *\r\n\r\n");
+    response.extend_from_slice(b"Access-Control-Allow-Origin: *\r\n");
+    if keep_alive {
+        response.extend_from_slice(b"Connection: keep-alive\r\n");
+    } else {
+        response.extend_from_slice(b"Connection: close\r\n");
+    }
+    for (name, value) in reply.headers {
+        response.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    response.extend_from_slice(b"\r\n");
 
-    response.extend_from_slice(&buf);
+    // HEAD gets the headers of GET without the body
+    if !is_head {
+        response.extend_from_slice(&buf);
+    }
 
     response
 }
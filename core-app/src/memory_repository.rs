@@ -0,0 +1,196 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use crate::{
+    game_snapshot::SnapshotStore,
+    hand_history::HandHistoryStore,
+    protos::{
+        game_snapshot::GameSnapshot,
+        hand_history::HandHistory,
+        lobby::{Lobby, LobbyList},
+        user::User,
+    },
+    repository::{DbError, Repository},
+};
+
+// Same rules as the postgres schema (lobby author and members must exist),
+// but everything is lost on restart
+pub struct InMemoryRepository {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    users: BTreeMap<i32, User>,
+    admins: Vec<i32>,
+    lobbies: BTreeMap<i32, Lobby>,
+    // (lobby_id, user_id)
+    memberships: Vec<(i32, i32)>,
+    snapshots: HashMap<i32, GameSnapshot>,
+    hands: Vec<HandHistory>,
+}
+
+impl Default for InMemoryRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryRepository {
+    pub fn new() -> Self {
+        InMemoryRepository {
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+
+    // users of database/feed.sql, so tokens for ids 1..=6 work out of the box
+    pub fn with_demo_users() -> Self {
+        let repository = Self::new();
+        let users = [
+            ("Rudolf", "AF"),
+            ("Petya", "DZ"),
+            ("Vasya", "AS"),
+            ("Kolya", "AO"),
+            ("Sergey", "AQ"),
+            ("Andrey", "AM"),
+        ];
+        for (id, (name, country)) in (1..).zip(users) {
+            repository.add_user(User {
+                id,
+                name: String::from(name),
+                country: String::from(country),
+                email: format!("{}@gmail.com", name.to_lowercase()),
+            });
+        }
+        repository
+    }
+
+    pub fn add_user(&self, user: User) {
+        self.state.lock().unwrap().users.insert(user.id, user);
+    }
+
+    pub fn add_admin(&self, user: User) {
+        let mut state = self.state.lock().unwrap();
+        state.admins.push(user.id);
+        state.users.insert(user.id, user);
+    }
+}
+
+impl Repository for InMemoryRepository {
+    fn get_user_by_id(&self, user_id: i32) -> Result<User, DbError> {
+        let state = self.state.lock().unwrap();
+        state.users.get(&user_id).cloned().ok_or(DbError::NotFound)
+    }
+
+    fn get_users_by_lobby_id(&self, lobby_id: i32) -> Result<Vec<User>, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .memberships
+            .iter()
+            .filter(|(lobby, _)| *lobby == lobby_id)
+            .filter_map(|(_, user_id)| state.users.get(user_id).cloned())
+            .collect())
+    }
+
+    fn is_admin(&self, user_id: i32) -> Result<bool, DbError> {
+        Ok(self.state.lock().unwrap().admins.contains(&user_id))
+    }
+
+    fn create_lobby(&self, mut lobby: Lobby) -> Result<i32, DbError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.users.contains_key(&lobby.author_id) {
+            return Err(DbError::ConstraintViolation(format!(
+                "author {} doesn't exist",
+                lobby.author_id
+            )));
+        }
+
+        let lobby_id = state.lobbies.keys().next_back().map_or(1, |id| id + 1);
+        lobby.id = Some(lobby_id);
+        state.lobbies.insert(lobby_id, lobby);
+        Ok(lobby_id)
+    }
+
+    fn get_lobbies(&self) -> Result<LobbyList, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(LobbyList {
+            list: state.lobbies.values().cloned().collect(),
+        })
+    }
+
+    fn get_lobby_author_id(&self, lobby_id: i32) -> Result<i32, DbError> {
+        let state = self.state.lock().unwrap();
+        state
+            .lobbies
+            .get(&lobby_id)
+            .map(|lobby| lobby.author_id)
+            .ok_or(DbError::NotFound)
+    }
+
+    fn add_user_to_lobby(&self, lobby_id: i32, user_id: i32) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.lobbies.contains_key(&lobby_id) {
+            return Err(DbError::ConstraintViolation(format!(
+                "lobby {} doesn't exist",
+                lobby_id
+            )));
+        }
+        if !state.users.contains_key(&user_id) {
+            return Err(DbError::ConstraintViolation(format!(
+                "user {} doesn't exist",
+                user_id
+            )));
+        }
+
+        if !state.memberships.contains(&(lobby_id, user_id)) {
+            state.memberships.push((lobby_id, user_id));
+        }
+        Ok(())
+    }
+
+    fn get_hands_by_user_id(
+        &self,
+        user_id: i32,
+        limit: usize,
+    ) -> Result<Vec<HandHistory>, DbError> {
+        let state = self.state.lock().unwrap();
+        let mut hands: Vec<HandHistory> = state
+            .hands
+            .iter()
+            .rev()
+            .filter(|hand| hand.has_player(user_id))
+            .take(limit)
+            .cloned()
+            .collect();
+        hands.reverse();
+        Ok(hands)
+    }
+}
+
+impl SnapshotStore for InMemoryRepository {
+    fn save_snapshot(&self, snapshot: &GameSnapshot) {
+        let mut state = self.state.lock().unwrap();
+        state.snapshots.insert(snapshot.lobby_id, snapshot.clone());
+    }
+
+    fn load_snapshot(&self, lobby_id: i32) -> Option<GameSnapshot> {
+        self.state.lock().unwrap().snapshots.get(&lobby_id).cloned()
+    }
+
+    fn load_snapshots(&self) -> Vec<GameSnapshot> {
+        let state = self.state.lock().unwrap();
+        state.snapshots.values().cloned().collect()
+    }
+
+    fn remove_snapshot(&self, lobby_id: i32) {
+        self.state.lock().unwrap().snapshots.remove(&lobby_id);
+    }
+}
+
+impl HandHistoryStore for InMemoryRepository {
+    fn save_hand(&self, hand: &HandHistory) {
+        self.state.lock().unwrap().hands.push(hand.clone());
+    }
+}
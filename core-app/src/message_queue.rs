@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::protos::responses::ResponseMessageType;
+
+pub const DEFAULT_MESSAGE_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+// a user who never comes back must not grow the queue forever
+const MAX_MESSAGES_PER_USER: usize = 100;
+
+struct QueuedMessage {
+    queued_at: Instant,
+    // encoded ResponseMessage, ready to be written to the socket
+    message: Vec<u8>,
+}
+
+// Messages for users without a connection, delivered when they connect again.
+// Only messages which are not superseded by the next state are kept, see is_durable:
+// currently that is GameOver alone.
+pub struct MessageQueue {
+    ttl: Duration,
+    queues: Mutex<HashMap<i32, VecDeque<QueuedMessage>>>,
+}
+
+impl MessageQueue {
+    pub fn new(ttl: Duration) -> Self {
+        MessageQueue {
+            ttl,
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn push(&self, user_id: i32, message: Vec<u8>) {
+        let mut queues = self.queues.lock().unwrap();
+        self.remove_expired(&mut queues);
+
+        let queue = queues.entry(user_id).or_default();
+        if queue.len() == MAX_MESSAGES_PER_USER {
+            println!(
+                "offline queue of user {} is full, oldest message dropped",
+                user_id
+            );
+            queue.pop_front();
+        }
+        queue.push_back(QueuedMessage {
+            queued_at: Instant::now(),
+            message,
+        });
+    }
+
+    // Hands alive messages of the user to send, oldest first. Delivery stops at the first
+    // message send refuses, it and everything after it stay in the queue.
+    pub fn deliver<F: FnMut(&[u8]) -> bool>(&self, user_id: i32, mut send: F) -> usize {
+        let mut queues = self.queues.lock().unwrap();
+        self.remove_expired(&mut queues);
+
+        let queue = match queues.get_mut(&user_id) {
+            Some(q) => q,
+            None => return 0,
+        };
+
+        let mut delivered = 0;
+        while let Some(m) = queue.front() {
+            if !send(&m.message) {
+                break;
+            }
+            queue.pop_front();
+            delivered += 1;
+        }
+
+        if queue.is_empty() {
+            queues.remove(&user_id);
+        }
+        delivered
+    }
+
+    fn remove_expired(&self, queues: &mut HashMap<i32, VecDeque<QueuedMessage>>) {
+        let ttl = self.ttl;
+        queues.retain(|_, queue| {
+            queue.retain(|m| m.queued_at.elapsed() < ttl);
+            !queue.is_empty()
+        });
+    }
+}
+
+impl Default for MessageQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_MESSAGE_TTL)
+    }
+}
+
+// Which messages outlive the connection. Today GameOver is the only message type the server
+// sends that the user can't get back any other way; tournament results or cash-out
+// confirmations don't exist yet and have to be added here as durable once they do.
+pub fn is_durable(message_type: ResponseMessageType) -> bool {
+    match message_type {
+        ResponseMessageType::GameOver => true,
+        // replaced by the full state sent on reconnect
+        ResponseMessageType::ClientState | ResponseMessageType::StateDelta => false,
+        // the countdown is over by the time the user is back, the table state tells the rest
+        ResponseMessageType::StartGame => false,
+        // missed chat comes back as ChatHistory when the user joins the table again
+        ResponseMessageType::Chat | ResponseMessageType::ChatHistory => false,
+        // answers a message the user has just sent, stale once the connection is gone
+        ResponseMessageType::ChatRejected => false,
+        // meant for open connections only, the server is gone afterwards
+        ResponseMessageType::ServerShutdown => false,
+    }
+}
@@ -0,0 +1,436 @@
+use std::{error::Error, fmt};
+
+use postgres::Client;
+use sha2::{Digest, Sha256};
+
+use crate::repository::DbError;
+
+// Versioned schema changes compiled into the binary, applied in order at startup.
+// Applied migrations must never be edited: a changed checksum stops the server.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("database/migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "hand_history",
+        sql: include_str!("database/migrations/0002_hand_history.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "adopt_init_schema",
+        sql: include_str!("database/migrations/0003_adopt_init_schema.sql"),
+    },
+];
+
+// Columns the queries of PostgresDatabase rely on, checked after migrating
+const EXPECTED_COLUMNS: &[(&str, &[&str])] = &[
+    ("users", &["id", "name", "country", "email", "type"]),
+    (
+        "lobbies",
+        &[
+            "id",
+            "name",
+            "author_id",
+            "players_registered",
+            "game_name",
+            "game_type",
+        ],
+    ),
+    ("players_lobbies", &["player_id", "lobby_id"]),
+    ("game_snapshots", &["lobby_id", "payload", "updated_at"]),
+    ("hand_histories", &["id", "lobby_id", "payload"]),
+    ("hand_players", &["hand_id", "user_id"]),
+];
+
+// Unique keys ON CONFLICT of the queries infers, and the one hand_players relies on
+// against counting a hand twice. Columns are in alphabetical order.
+const EXPECTED_UNIQUE_KEYS: &[(&str, &[&str])] = &[
+    ("players_lobbies", &["lobby_id", "player_id"]),
+    ("game_snapshots", &["lobby_id"]),
+    ("hand_players", &["hand_id", "user_id"]),
+];
+
+// Labels FromSql/ToSql of GameName and GameType read and write
+const EXPECTED_ENUM_LABELS: &[(&str, &[&str])] = &[
+    ("game_name_enum", &["Holdem"]),
+    ("game_type_enum", &["Tournament", "Cash"]),
+];
+
+// any constant works, it only has to be the same for every instance
+const MIGRATION_LOCK_ID: i64 = 0x66756e5f706f6b;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Db(DbError),
+    // migration was edited after it had been applied
+    ChecksumMismatch { version: i32, name: String },
+    // database was migrated by a newer server
+    UnknownVersion(i32),
+    Failed { version: i32, error: DbError },
+    Drift(Vec<String>),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Db(e) => write!(f, "{}", e),
+            MigrationError::ChecksumMismatch { version, name } => write!(
+                f,
+                "migration {} {} was changed after it had been applied",
+                version, name
+            ),
+            MigrationError::UnknownVersion(version) => write!(
+                f,
+                "database has migration {} which this server doesn't know",
+                version
+            ),
+            MigrationError::Failed { version, error } => {
+                write!(f, "migration {} failed: {}", version, error)
+            }
+            MigrationError::Drift(problems) => {
+                write!(f, "schema drift: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl Error for MigrationError {}
+
+impl From<postgres::Error> for MigrationError {
+    fn from(e: postgres::Error) -> Self {
+        MigrationError::Db(e.into())
+    }
+}
+
+impl From<DbError> for MigrationError {
+    fn from(e: DbError) -> Self {
+        MigrationError::Db(e)
+    }
+}
+
+fn checksum(sql: &str) -> String {
+    format!("{:x}", Sha256::digest(sql.as_bytes()))
+}
+
+// row of schema_migrations
+struct AppliedMigration {
+    version: i32,
+    name: String,
+    checksum: String,
+}
+
+// Migrations still to apply, oldest first. Fails when an applied one was edited
+// or isn't known to this server.
+fn pending_migrations<'a>(
+    migrations: &'a [Migration],
+    applied: &[AppliedMigration],
+) -> Result<Vec<&'a Migration>, MigrationError> {
+    for row in applied {
+        match migrations.iter().find(|m| m.version == row.version) {
+            Some(migration) if checksum(migration.sql) == row.checksum => {}
+            Some(_) => {
+                return Err(MigrationError::ChecksumMismatch {
+                    version: row.version,
+                    name: row.name.clone(),
+                })
+            }
+            None => return Err(MigrationError::UnknownVersion(row.version)),
+        }
+    }
+
+    let mut pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|m| !applied.iter().any(|row| row.version == m.version))
+        .collect();
+    pending.sort_by_key(|m| m.version);
+    Ok(pending)
+}
+
+// Applies pending migrations and verifies the result. Returns how many were applied.
+// Several instances may start at once, the advisory lock lets one of them migrate.
+pub fn run_migrations(client: &mut Client) -> Result<usize, MigrationError> {
+    let mut transaction = client.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID])?;
+    transaction.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            checksum VARCHAR NOT NULL,
+            applied_at TIMESTAMP NOT NULL DEFAULT NOW()
+        )",
+    )?;
+
+    let applied = transaction.query(
+        "SELECT version, name, checksum FROM schema_migrations ORDER BY version",
+        &[],
+    )?;
+    let applied = applied
+        .iter()
+        .map(|row| {
+            Ok(AppliedMigration {
+                version: row.try_get("version")?,
+                name: row.try_get("name")?,
+                checksum: row.try_get("checksum")?,
+            })
+        })
+        .collect::<Result<Vec<_>, postgres::Error>>()?;
+
+    let mut count = 0;
+    for migration in pending_migrations(MIGRATIONS, &applied)? {
+        println!(
+            "applying migration {} {}",
+            migration.version, migration.name
+        );
+        transaction
+            .batch_execute(migration.sql)
+            .map_err(|e| MigrationError::Failed {
+                version: migration.version,
+                error: e.into(),
+            })?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+            &[
+                &migration.version,
+                &migration.name,
+                &checksum(migration.sql),
+            ],
+        )?;
+        count += 1;
+    }
+    transaction.commit()?;
+
+    verify_schema(client)?;
+    Ok(count)
+}
+
+// Fails fast when the tables differ from what the queries expect,
+// e.g. a database created from an old schema file
+pub fn verify_schema(client: &mut Client) -> Result<(), MigrationError> {
+    let mut problems = Vec::new();
+
+    for (table, columns) in EXPECTED_COLUMNS {
+        let rows = client.query(
+            "SELECT column_name::text FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = $1",
+            &[table],
+        )?;
+        let existing: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        problems.extend(missing_columns(table, columns, &existing));
+    }
+
+    for (table, columns) in EXPECTED_UNIQUE_KEYS {
+        // partial and expression indexes can't be used by ON CONFLICT without naming them
+        let rows = client.query(
+            "SELECT array_agg(a.attname::text ORDER BY a.attname) FROM pg_index i
+                JOIN pg_class c ON c.oid = i.indrelid
+                JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY (i.indkey)
+                WHERE c.relname = $1 AND c.relnamespace = current_schema()::regnamespace
+                    AND i.indisunique AND i.indpred IS NULL AND i.indexprs IS NULL
+                GROUP BY i.indexrelid",
+            &[table],
+        )?;
+        let keys: Vec<Vec<String>> = rows.iter().map(|row| row.get(0)).collect();
+        problems.extend(missing_unique_key(table, columns, &keys));
+    }
+
+    for (enum_name, labels) in EXPECTED_ENUM_LABELS {
+        let rows = client.query(
+            "SELECT e.enumlabel::text FROM pg_enum e
+                JOIN pg_type t ON t.oid = e.enumtypid WHERE t.typname = $1",
+            &[enum_name],
+        )?;
+        let existing: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        problems.extend(missing_enum_labels(enum_name, labels, &existing));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrationError::Drift(problems))
+    }
+}
+
+fn missing_columns(table: &str, expected: &[&str], existing: &[String]) -> Vec<String> {
+    if existing.is_empty() {
+        return vec![format!("table {} is missing", table)];
+    }
+    expected
+        .iter()
+        .filter(|column| !existing.iter().any(|c| c == *column))
+        .map(|column| format!("column {}.{} is missing", table, column))
+        .collect()
+}
+
+fn missing_unique_key(table: &str, columns: &[&str], keys: &[Vec<String>]) -> Option<String> {
+    if keys.iter().any(|key| key == columns) {
+        return None;
+    }
+    Some(format!(
+        "{} has no unique key on ({})",
+        table,
+        columns.join(", ")
+    ))
+}
+
+fn missing_enum_labels(enum_name: &str, labels: &[&str], existing: &[String]) -> Vec<String> {
+    labels
+        .iter()
+        .filter(|label| !existing.iter().any(|l| l == *label))
+        .map(|label| format!("{} has no label {}", enum_name, label))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 2,
+            name: "second",
+            sql: "CREATE TABLE b (id INTEGER);",
+        },
+        Migration {
+            version: 1,
+            name: "first",
+            sql: "CREATE TABLE a (id INTEGER);",
+        },
+        Migration {
+            version: 3,
+            name: "third",
+            sql: "CREATE TABLE c (id INTEGER);",
+        },
+    ];
+
+    fn applied(version: i32, sql: &str) -> AppliedMigration {
+        AppliedMigration {
+            version,
+            name: format!("migration {}", version),
+            checksum: checksum(sql),
+        }
+    }
+
+    fn versions(pending: Vec<&Migration>) -> Vec<i32> {
+        pending.iter().map(|m| m.version).collect()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn embedded_migrations_are_numbered_in_order() {
+        let versions: Vec<i32> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<i32> = (1..=MIGRATIONS.len() as i32).collect();
+        assert_eq!(versions, expected);
+        assert!(pending_migrations(MIGRATIONS, &[]).is_ok());
+    }
+
+    #[test]
+    fn pending_migrations_are_applied_oldest_first() {
+        let pending = pending_migrations(TEST_MIGRATIONS, &[]).unwrap();
+        assert_eq!(versions(pending), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn applied_migrations_are_skipped() {
+        let cases = [
+            (vec![applied(1, TEST_MIGRATIONS[1].sql)], vec![2, 3]),
+            (
+                vec![
+                    applied(1, TEST_MIGRATIONS[1].sql),
+                    applied(2, TEST_MIGRATIONS[0].sql),
+                ],
+                vec![3],
+            ),
+            (
+                vec![
+                    applied(1, TEST_MIGRATIONS[1].sql),
+                    applied(2, TEST_MIGRATIONS[0].sql),
+                    applied(3, TEST_MIGRATIONS[2].sql),
+                ],
+                vec![],
+            ),
+        ];
+
+        for (applied, expected) in cases {
+            let pending = pending_migrations(TEST_MIGRATIONS, &applied).unwrap();
+            assert_eq!(versions(pending), expected);
+        }
+    }
+
+    #[test]
+    fn edited_migration_stops_the_server() {
+        let applied = [
+            applied(1, TEST_MIGRATIONS[1].sql),
+            applied(2, "CREATE TABLE b (id BIGINT);"),
+        ];
+
+        match pending_migrations(TEST_MIGRATIONS, &applied) {
+            Err(MigrationError::ChecksumMismatch { version, name }) => {
+                assert_eq!((version, name.as_str()), (2, "migration 2"));
+            }
+            other => panic!("edited migration is accepted: {:?}", other.map(versions)),
+        }
+    }
+
+    #[test]
+    fn migration_of_a_newer_server_stops_the_server() {
+        let applied = [applied(4, "CREATE TABLE d (id INTEGER);")];
+
+        assert!(matches!(
+            pending_migrations(TEST_MIGRATIONS, &applied),
+            Err(MigrationError::UnknownVersion(4))
+        ));
+    }
+
+    #[test]
+    fn drift_is_reported() {
+        assert_eq!(
+            missing_columns("users", &["id", "type"], &strings(&["id", "name"])),
+            vec!["column users.type is missing"]
+        );
+        assert_eq!(
+            missing_columns("users", &["id"], &[]),
+            vec!["table users is missing"]
+        );
+        assert!(missing_columns("users", &["id"], &strings(&["id", "name"])).is_empty());
+
+        let columns = ["lobby_id", "player_id"];
+        let cases = [
+            (vec![strings(&["lobby_id", "player_id"])], None),
+            (
+                vec![strings(&["player_lobby_id"]), strings(&["lobby_id"])],
+                Some("players_lobbies has no unique key on (lobby_id, player_id)"),
+            ),
+            (
+                vec![],
+                Some("players_lobbies has no unique key on (lobby_id, player_id)"),
+            ),
+        ];
+        for (keys, expected) in cases {
+            assert_eq!(
+                missing_unique_key("players_lobbies", &columns, &keys).as_deref(),
+                expected
+            );
+        }
+
+        assert_eq!(
+            missing_enum_labels(
+                "game_type_enum",
+                &["Tournament", "Cash"],
+                &strings(&["Tournament"])
+            ),
+            vec!["game_type_enum has no label Cash"]
+        );
+    }
+}
@@ -26,10 +26,10 @@ impl Player {
             .collect()
     }
 
-    pub fn from_user(u: User) -> Player {
+    pub fn from_user(u: User, bank: i32) -> Player {
         Player {
             action: None,
-            bank: 10000,
+            bank,
             country: u.country,
             user_id: u.id,
             user_name: u.name,
@@ -0,0 +1,721 @@
+use std::collections::BTreeMap;
+
+use crate::protos::{
+    game_state::{Action, ActionType, StreetStatus, Winner},
+    hand_history::HandHistory,
+    player_stats::{PlayerStats, Position, PositionStats, StatCounter, StatLine},
+};
+
+// HUD statistics of one user computed from the recorded hands. Every hand is tallied on its own
+// and added to the overall line and to the line of the position the user had in it.
+
+// seats between the big blind and the button, in the order they act preflop
+const MIDDLE_POSITIONS: [Position; 6] = [
+    Position::Utg,
+    Position::Utg1,
+    Position::Utg2,
+    Position::Mp,
+    Position::Mp1,
+    Position::Mp2,
+];
+
+#[derive(Default, Clone, Copy)]
+struct Counter {
+    made: i32,
+    opportunities: i32,
+}
+
+impl Counter {
+    fn record(&mut self, made: bool) {
+        self.opportunities += 1;
+        if made {
+            self.made += 1;
+        }
+    }
+
+    fn add(&mut self, other: &Counter) {
+        self.made += other.made;
+        self.opportunities += other.opportunities;
+    }
+}
+
+impl From<Counter> for Option<StatCounter> {
+    fn from(counter: Counter) -> Self {
+        Some(StatCounter {
+            made: counter.made,
+            opportunities: counter.opportunities,
+        })
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Tally {
+    hands: i32,
+    vpip: Counter,
+    pfr: Counter,
+    three_bet: Counter,
+    fold_to_three_bet: Counter,
+    cbet: Counter,
+    wtsd: Counter,
+    wsd: Counter,
+    aggressive_actions: i32,
+    passive_actions: i32,
+}
+
+impl Tally {
+    fn add(&mut self, other: &Tally) {
+        self.hands += other.hands;
+        self.vpip.add(&other.vpip);
+        self.pfr.add(&other.pfr);
+        self.three_bet.add(&other.three_bet);
+        self.fold_to_three_bet.add(&other.fold_to_three_bet);
+        self.cbet.add(&other.cbet);
+        self.wtsd.add(&other.wtsd);
+        self.wsd.add(&other.wsd);
+        self.aggressive_actions += other.aggressive_actions;
+        self.passive_actions += other.passive_actions;
+    }
+
+    fn to_stat_line(self) -> StatLine {
+        StatLine {
+            hands: self.hands,
+            vpip: self.vpip.into(),
+            pfr: self.pfr.into(),
+            three_bet: self.three_bet.into(),
+            fold_to_three_bet: self.fold_to_three_bet.into(),
+            cbet: self.cbet.into(),
+            wtsd: self.wtsd.into(),
+            wsd: self.wsd.into(),
+            aggressive_actions: self.aggressive_actions,
+            passive_actions: self.passive_actions,
+        }
+    }
+}
+
+pub fn calculate_stats(user_id: i32, hands: &[HandHistory]) -> PlayerStats {
+    let mut overall = Tally::default();
+    let mut by_position: BTreeMap<i32, Tally> = BTreeMap::new();
+
+    for hand in hands {
+        let Some(tally) = tally_hand(user_id, hand) else {
+            continue;
+        };
+        overall.add(&tally);
+        if let Some(position) = position_of(user_id, hand) {
+            by_position.entry(position as i32).or_default().add(&tally);
+        }
+    }
+
+    PlayerStats {
+        user_id,
+        overall: Some(overall.to_stat_line()),
+        by_position: by_position
+            .into_iter()
+            .map(|(position, tally)| PositionStats {
+                position,
+                stats: Some(tally.to_stat_line()),
+            })
+            .collect(),
+    }
+}
+
+fn is_street(action: &Action, street: StreetStatus) -> bool {
+    action.street_status == Some(street as i32)
+}
+
+fn is_postflop(action: &Action) -> bool {
+    action
+        .street_status
+        .is_some_and(|s| s != StreetStatus::Preflop as i32)
+}
+
+// every dealt player has at least the blind or a fold in the history,
+// players who sat down during the hand have nothing
+fn is_dealt(user_id: i32, hand: &HandHistory) -> bool {
+    hand.actions.iter().any(|a| a.player_id == user_id)
+}
+
+fn has_folded(user_id: i32, hand: &HandHistory) -> bool {
+    hand.actions
+        .iter()
+        .any(|a| a.player_id == user_id && a.action_type() == ActionType::Fold)
+}
+
+fn tally_hand(user_id: i32, hand: &HandHistory) -> Option<Tally> {
+    if !is_dealt(user_id, hand) {
+        return None;
+    }
+
+    let mut tally = Tally {
+        hands: 1,
+        ..Tally::default()
+    };
+
+    // preflop: the first raise opens, the second one is a 3-bet
+    let mut raisers: Vec<i32> = Vec::new();
+    let mut voluntarily_put_in = false;
+    let mut raised = false;
+    let mut faced_open = false;
+    let mut faced_three_bet = false;
+
+    for action in hand
+        .actions
+        .iter()
+        .filter(|a| is_street(a, StreetStatus::Preflop) && a.action_type() != ActionType::Blind)
+    {
+        let action_type = action.action_type();
+        if action.player_id == user_id {
+            voluntarily_put_in |= matches!(action_type, ActionType::Call | ActionType::Raise);
+            raised |= action_type == ActionType::Raise;
+
+            if !faced_open && raisers.len() == 1 && raisers[0] != user_id {
+                faced_open = true;
+                tally.three_bet.record(action_type == ActionType::Raise);
+            }
+            if !faced_three_bet && raisers.len() == 2 && raisers[0] == user_id {
+                faced_three_bet = true;
+                tally
+                    .fold_to_three_bet
+                    .record(action_type == ActionType::Fold);
+            }
+        }
+        if action_type == ActionType::Raise {
+            raisers.push(action.player_id);
+        }
+    }
+
+    tally.vpip.record(voluntarily_put_in);
+    tally.pfr.record(raised);
+
+    let folded = has_folded(user_id, hand);
+    let folded_preflop = hand.actions.iter().any(|a| {
+        a.player_id == user_id
+            && a.action_type() == ActionType::Fold
+            && is_street(a, StreetStatus::Preflop)
+    });
+    let saw_flop = hand.board.len() >= 3 && !folded_preflop;
+
+    // c-bet: the last preflop raiser is first to bet the flop
+    if saw_flop && raisers.last() == Some(&user_id) {
+        for action in hand
+            .actions
+            .iter()
+            .filter(|a| is_street(a, StreetStatus::Flop))
+        {
+            if action.player_id == user_id {
+                tally.cbet.record(action.action_type() == ActionType::Raise);
+                break;
+            }
+            if action.action_type() == ActionType::Raise {
+                break;
+            }
+        }
+    }
+
+    if saw_flop {
+        let players_left = hand
+            .players
+            .iter()
+            .filter(|p| is_dealt(p.user_id, hand) && !has_folded(p.user_id, hand))
+            .count();
+        let went_to_showdown = !folded && players_left > 1;
+        tally.wtsd.record(went_to_showdown);
+
+        if went_to_showdown {
+            tally.wsd.record(won_at_showdown(user_id, hand));
+        }
+    }
+
+    for action in hand
+        .actions
+        .iter()
+        .filter(|a| a.player_id == user_id && is_postflop(a))
+    {
+        match action.action_type() {
+            ActionType::Raise => tally.aggressive_actions += 1,
+            ActionType::Call => tally.passive_actions += 1,
+            _ => {}
+        }
+    }
+
+    Some(tally)
+}
+
+// Any pot counts, a short stack all-in may lose the main pot and still take a side pot.
+// Hands recorded before the pots were kept have the main pot winners only.
+fn won_at_showdown(user_id: i32, hand: &HandHistory) -> bool {
+    let Some(outcome) = hand.showdown_outcome.as_ref() else {
+        return false;
+    };
+    let won = |winners: &[Winner]| {
+        winners
+            .iter()
+            .any(|w| w.player_id == user_id && w.win_amout > 0)
+    };
+
+    if outcome.pots.is_empty() {
+        won(&outcome.winners)
+    } else {
+        outcome.pots.iter().any(|pot| won(&pot.winners))
+    }
+}
+
+// Heads-up the button is the small blind, so it is reported as Sb
+fn position_of(user_id: i32, hand: &HandHistory) -> Option<Position> {
+    let seat = hand.players.iter().position(|p| p.user_id == user_id)? as i32;
+    let big_blind = hand.big_blind_index?;
+    let small_blind = hand.small_blind_index?;
+    let button = hand.button_index?;
+
+    if seat == big_blind {
+        return Some(Position::Bb);
+    }
+    if seat == small_blind {
+        return Some(Position::Sb);
+    }
+    if seat == button {
+        return Some(Position::Btn);
+    }
+
+    let seats = hand.players.len() as i32;
+    let middle: Vec<i32> = (1..seats)
+        .map(|offset| (big_blind + offset) % seats)
+        .take_while(|&s| s != button)
+        .filter(|&s| is_dealt(hand.players[s as usize].user_id, hand))
+        .collect();
+
+    let index = middle.iter().position(|&s| s == seat)?;
+    if index == middle.len() - 1 {
+        return Some(Position::Co);
+    }
+    MIDDLE_POSITIONS.get(index).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{apply, Command, EngineState, Event},
+        protos::{
+            card::{Card, CardPair, CardSuit, CardValue},
+            game_state::{PotResult, ShowdownOutcome},
+            hand_history::HandPlayer,
+            player::Player,
+            requests::PlayerActionRequest,
+        },
+    };
+
+    const HERO: i32 = 1;
+    const VILLAIN: i32 = 3;
+
+    use ActionType::{Blind, Call, Check, Fold, Raise};
+    use StreetStatus::{Flop, Preflop, River, Turn};
+
+    fn act(player_id: i32, action_type: ActionType, street: StreetStatus) -> Action {
+        Action {
+            action_type: action_type.into(),
+            bet: 0,
+            player_id,
+            street_status: Some(street.into()),
+        }
+    }
+
+    fn winner(player_id: i32, win_amout: i32) -> Winner {
+        Winner {
+            player_id,
+            win_amout,
+        }
+    }
+
+    // seats 1, 2, 3: hero on the button, 2 posts the small blind, 3 the big one
+    fn hand(actions: Vec<Action>, board: usize, outcome: Option<ShowdownOutcome>) -> HandHistory {
+        let mut all = vec![act(2, Blind, Preflop), act(3, Blind, Preflop)];
+        all.extend(actions);
+        HandHistory {
+            players: [1, 2, 3]
+                .into_iter()
+                .map(|user_id| HandPlayer {
+                    user_id,
+                    ..Default::default()
+                })
+                .collect(),
+            button_index: Some(0),
+            small_blind_index: Some(1),
+            big_blind_index: Some(2),
+            actions: all,
+            board: vec![Card::default(); board],
+            showdown_outcome: outcome,
+            ..Default::default()
+        }
+    }
+
+    // hero opens, only the big blind calls and checks down after hero's c-bet
+    fn showdown(outcome: ShowdownOutcome) -> HandHistory {
+        hand(
+            vec![
+                act(HERO, Raise, Preflop),
+                act(2, Fold, Preflop),
+                act(VILLAIN, Call, Preflop),
+                act(VILLAIN, Check, Flop),
+                act(HERO, Raise, Flop),
+                act(VILLAIN, Call, Flop),
+                act(VILLAIN, Check, Turn),
+                act(HERO, Check, Turn),
+                act(VILLAIN, Check, River),
+                act(HERO, Check, River),
+            ],
+            5,
+            Some(outcome),
+        )
+    }
+
+    // made and opportunities of every counter, then aggressive and passive actions
+    #[derive(Debug, PartialEq)]
+    struct Counts {
+        vpip: (i32, i32),
+        pfr: (i32, i32),
+        three_bet: (i32, i32),
+        fold_to_three_bet: (i32, i32),
+        cbet: (i32, i32),
+        wtsd: (i32, i32),
+        wsd: (i32, i32),
+        af: (i32, i32),
+    }
+
+    fn counts(hand: &HandHistory) -> Counts {
+        let line = calculate_stats(HERO, std::slice::from_ref(hand))
+            .overall
+            .unwrap();
+        let pair = |c: Option<StatCounter>| c.map_or((0, 0), |c| (c.made, c.opportunities));
+        Counts {
+            vpip: pair(line.vpip),
+            pfr: pair(line.pfr),
+            three_bet: pair(line.three_bet),
+            fold_to_three_bet: pair(line.fold_to_three_bet),
+            cbet: pair(line.cbet),
+            wtsd: pair(line.wtsd),
+            wsd: pair(line.wsd),
+            af: (line.aggressive_actions, line.passive_actions),
+        }
+    }
+
+    const NONE: (i32, i32) = (0, 0);
+
+    #[test]
+    fn stats_are_counted_per_hand() {
+        let cases = [
+            (
+                "folds preflop",
+                hand(vec![act(HERO, Fold, Preflop)], 0, None),
+                Counts {
+                    vpip: (0, 1),
+                    pfr: (0, 1),
+                    three_bet: NONE,
+                    fold_to_three_bet: NONE,
+                    cbet: NONE,
+                    wtsd: NONE,
+                    wsd: NONE,
+                    af: NONE,
+                },
+            ),
+            (
+                "opens and folds to a 3-bet",
+                hand(
+                    vec![
+                        act(HERO, Raise, Preflop),
+                        act(2, Fold, Preflop),
+                        act(VILLAIN, Raise, Preflop),
+                        act(HERO, Fold, Preflop),
+                    ],
+                    0,
+                    None,
+                ),
+                Counts {
+                    vpip: (1, 1),
+                    pfr: (1, 1),
+                    three_bet: NONE,
+                    fold_to_three_bet: (1, 1),
+                    cbet: NONE,
+                    wtsd: NONE,
+                    wsd: NONE,
+                    af: NONE,
+                },
+            ),
+            (
+                "limps and 3-bets the raise behind",
+                hand(
+                    vec![
+                        act(HERO, Call, Preflop),
+                        act(2, Raise, Preflop),
+                        act(VILLAIN, Fold, Preflop),
+                        act(HERO, Raise, Preflop),
+                        act(2, Fold, Preflop),
+                    ],
+                    0,
+                    None,
+                ),
+                Counts {
+                    vpip: (1, 1),
+                    pfr: (1, 1),
+                    three_bet: (1, 1),
+                    fold_to_three_bet: NONE,
+                    cbet: NONE,
+                    wtsd: NONE,
+                    wsd: NONE,
+                    af: NONE,
+                },
+            ),
+            (
+                "c-bets the flop and folds the turn",
+                hand(
+                    vec![
+                        act(HERO, Raise, Preflop),
+                        act(2, Fold, Preflop),
+                        act(VILLAIN, Call, Preflop),
+                        act(VILLAIN, Check, Flop),
+                        act(HERO, Raise, Flop),
+                        act(VILLAIN, Raise, Flop),
+                        act(HERO, Call, Flop),
+                        act(VILLAIN, Raise, Turn),
+                        act(HERO, Fold, Turn),
+                    ],
+                    4,
+                    None,
+                ),
+                Counts {
+                    vpip: (1, 1),
+                    pfr: (1, 1),
+                    three_bet: NONE,
+                    fold_to_three_bet: NONE,
+                    cbet: (1, 1),
+                    wtsd: (0, 1),
+                    wsd: NONE,
+                    af: (1, 1),
+                },
+            ),
+            (
+                "loses the showdown",
+                showdown(ShowdownOutcome {
+                    winners: vec![winner(VILLAIN, 450)],
+                    pots: vec![PotResult {
+                        amount: 450,
+                        winners: vec![winner(VILLAIN, 450)],
+                    }],
+                    ..Default::default()
+                }),
+                Counts {
+                    vpip: (1, 1),
+                    pfr: (1, 1),
+                    three_bet: NONE,
+                    fold_to_three_bet: NONE,
+                    cbet: (1, 1),
+                    wtsd: (1, 1),
+                    wsd: (0, 1),
+                    af: (1, 0),
+                },
+            ),
+            (
+                "loses the main pot and wins the side pot",
+                showdown(ShowdownOutcome {
+                    winners: vec![winner(VILLAIN, 300)],
+                    pots: vec![
+                        PotResult {
+                            amount: 300,
+                            winners: vec![winner(VILLAIN, 300)],
+                        },
+                        PotResult {
+                            amount: 150,
+                            winners: vec![winner(HERO, 150)],
+                        },
+                    ],
+                    ..Default::default()
+                }),
+                Counts {
+                    vpip: (1, 1),
+                    pfr: (1, 1),
+                    three_bet: NONE,
+                    fold_to_three_bet: NONE,
+                    cbet: (1, 1),
+                    wtsd: (1, 1),
+                    wsd: (1, 1),
+                    af: (1, 0),
+                },
+            ),
+            (
+                "wins a hand recorded before the pots were kept",
+                showdown(ShowdownOutcome {
+                    winners: vec![winner(HERO, 450)],
+                    ..Default::default()
+                }),
+                Counts {
+                    vpip: (1, 1),
+                    pfr: (1, 1),
+                    three_bet: NONE,
+                    fold_to_three_bet: NONE,
+                    cbet: (1, 1),
+                    wtsd: (1, 1),
+                    wsd: (1, 1),
+                    af: (1, 0),
+                },
+            ),
+        ];
+
+        for (name, hand, expected) in cases {
+            assert_eq!(counts(&hand), expected, "{}", name);
+        }
+    }
+
+    fn awaited(events: &[Event]) -> i32 {
+        events
+            .iter()
+            .rev()
+            .find_map(|e| match e {
+                Event::AwaitingAction { player_id, .. } => Some(*player_id),
+                _ => None,
+            })
+            .expect("nobody is awaited")
+    }
+
+    // both players go all-in preflop, aces hold against kings on a board of blanks
+    fn engine_showdown(aces: i32, kings: i32) -> HandHistory {
+        let mut state = EngineState::with_seed(1, 20, 7);
+        for user_id in [aces, kings] {
+            let player = Player {
+                user_id,
+                bank: 1000,
+                ..Default::default()
+            };
+            (state, _) = apply(state, Command::Join(player));
+        }
+        let (mut state, events) = apply(state, Command::StartGame);
+
+        let pair = |suits: [CardSuit; 2], value: CardValue| CardPair {
+            card1: Some(Card::new(suits[0], value)),
+            card2: Some(Card::new(suits[1], value)),
+        };
+        for player in state.player_state.players.iter_mut() {
+            let value = if player.user_id == aces {
+                CardValue::Ace
+            } else {
+                CardValue::King
+            };
+            player.cards = Some(pair([CardSuit::Spades, CardSuit::Hearts], value));
+        }
+        state.deck_state.deck.cards = [
+            (CardSuit::Clubs, CardValue::Two),
+            (CardSuit::Diamonds, CardValue::Seven),
+            (CardSuit::Clubs, CardValue::Nine),
+            (CardSuit::Diamonds, CardValue::Three),
+            (CardSuit::Clubs, CardValue::Four),
+        ]
+        .into_iter()
+        .map(|(suit, value)| Card::new(suit, value))
+        .collect();
+
+        let mut events = events;
+        for action_type in [Raise, Call] {
+            let player_id = awaited(&events);
+            let bet = match action_type {
+                Raise => state
+                    .player_state
+                    .players
+                    .iter()
+                    .find(|p| p.user_id == player_id)
+                    .map_or(0, |p| p.bank),
+                _ => state
+                    .client_state(player_id)
+                    .amount_to_call
+                    .map_or(0, |v| v.value),
+            };
+            let request = PlayerActionRequest {
+                player_id,
+                lobby_id: 1,
+                action: Some(Action {
+                    action_type: action_type.into(),
+                    bet,
+                    player_id,
+                    street_status: None,
+                }),
+            };
+            (state, events) = apply(state, Command::Action(request));
+        }
+        assert!(events.iter().any(|e| matches!(e, Event::HandFinished)));
+
+        HandHistory::new(1, &state.game_state, &state.player_state)
+    }
+
+    #[test]
+    fn engine_showdown_is_won_by_the_stronger_hand() {
+        for (aces, kings) in [(1, 2), (2, 1)] {
+            let hand = engine_showdown(aces, kings);
+            assert_eq!(hand.board.len(), 5);
+
+            let wsd = |user_id: i32| {
+                calculate_stats(user_id, std::slice::from_ref(&hand))
+                    .overall
+                    .and_then(|line| line.wsd)
+                    .map(|c| (c.made, c.opportunities))
+            };
+            assert_eq!(wsd(aces), Some((1, 1)), "aces of user {}", aces);
+            assert_eq!(wsd(kings), Some((0, 1)), "kings of user {}", kings);
+        }
+    }
+
+    // every seat has acted, the button sits at `button` and the blinds follow it
+    fn table(seats: i32, button: i32, small_blind: i32, big_blind: i32) -> HandHistory {
+        HandHistory {
+            players: (0..seats)
+                .map(|user_id| HandPlayer {
+                    user_id,
+                    ..Default::default()
+                })
+                .collect(),
+            button_index: Some(button),
+            small_blind_index: Some(small_blind),
+            big_blind_index: Some(big_blind),
+            actions: (0..seats)
+                .map(|user_id| act(user_id, Fold, Preflop))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn positions_follow_the_button() {
+        let full_ring = table(9, 0, 1, 2);
+        let six_max = table(6, 2, 3, 4);
+        let heads_up = table(2, 0, 0, 1);
+        let mut sat_down_during_hand = table(6, 2, 3, 4);
+        sat_down_during_hand.actions.retain(|a| a.player_id != 0);
+
+        let cases = [
+            (&full_ring, 0, Some(Position::Btn)),
+            (&full_ring, 1, Some(Position::Sb)),
+            (&full_ring, 2, Some(Position::Bb)),
+            (&full_ring, 3, Some(Position::Utg)),
+            (&full_ring, 4, Some(Position::Utg1)),
+            (&full_ring, 5, Some(Position::Utg2)),
+            (&full_ring, 6, Some(Position::Mp)),
+            (&full_ring, 7, Some(Position::Mp1)),
+            (&full_ring, 8, Some(Position::Co)),
+            (&six_max, 5, Some(Position::Utg)),
+            (&six_max, 0, Some(Position::Utg1)),
+            (&six_max, 1, Some(Position::Co)),
+            (&heads_up, 0, Some(Position::Sb)),
+            (&heads_up, 1, Some(Position::Bb)),
+            (&sat_down_during_hand, 5, Some(Position::Utg)),
+            (&sat_down_during_hand, 1, Some(Position::Co)),
+            (&sat_down_during_hand, 0, None),
+        ];
+
+        for (hand, user_id, expected) in cases {
+            assert_eq!(
+                position_of(user_id, hand),
+                expected,
+                "user {} of {} seats",
+                user_id,
+                hand.players.len()
+            );
+        }
+    }
+}
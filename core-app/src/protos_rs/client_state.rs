@@ -45,4 +45,19 @@ pub struct ClientState {
     /// Might be empty if the game hasn't started
     #[prost(message, repeated, tag = "15")]
     pub action_history: ::prost::alloc::vec::Vec<super::game_state::Action>,
+    /// Hands tabled during an all-in runout
+    #[prost(message, repeated, tag = "16")]
+    pub revealed_cards: ::prost::alloc::vec::Vec<super::game_state::PlayerCards>,
+    /// Equity of tabled hands on the current street
+    #[prost(message, repeated, tag = "17")]
+    pub equities: ::prost::alloc::vec::Vec<super::equity::PlayerEquity>,
+    /// Per-table sequence, deltas continue from it
+    #[prost(uint64, tag = "18")]
+    pub sequence: u64,
+    /// FNV-1a 64 of this message encoded with sequence and state_hash unset
+    #[prost(uint64, tag = "19")]
+    pub state_hash: u64,
+    /// Chips in the middle, including the current street bets
+    #[prost(int32, tag = "20")]
+    pub pot: i32,
 }
@@ -0,0 +1,37 @@
+// This file is @generated by prost-build.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EquityRequest {
+    /// 2-10 players with known hole cards
+    #[prost(message, repeated, tag = "1")]
+    pub players: ::prost::alloc::vec::Vec<super::game_state::PlayerCards>,
+    /// 0, 3, 4 or 5 cards
+    #[prost(message, repeated, tag = "2")]
+    pub board: ::prost::alloc::vec::Vec<super::card::Card>,
+    /// cards known to be out of the deck
+    #[prost(message, repeated, tag = "3")]
+    pub dead_cards: ::prost::alloc::vec::Vec<super::card::Card>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PlayerEquity {
+    #[prost(int32, tag = "1")]
+    pub player_id: i32,
+    /// share of runouts won alone, from 0 to 1
+    #[prost(double, tag = "2")]
+    pub win: f64,
+    /// share of runouts where the pot is split
+    #[prost(double, tag = "3")]
+    pub tie: f64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EquityResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub equities: ::prost::alloc::vec::Vec<PlayerEquity>,
+    /// false when equity is estimated by Monte Carlo
+    #[prost(bool, tag = "2")]
+    pub is_exact: bool,
+    #[prost(int32, tag = "3")]
+    pub runouts: i32,
+}
@@ -0,0 +1,47 @@
+// This file is @generated by prost-build.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyPositionsSnapshot {
+    #[prost(int32, optional, tag = "1")]
+    pub small_blind_index: ::core::option::Option<i32>,
+    #[prost(int32, optional, tag = "2")]
+    pub big_blind_index: ::core::option::Option<i32>,
+    #[prost(int32, optional, tag = "3")]
+    pub curr_player_index: ::core::option::Option<i32>,
+    #[prost(int32, optional, tag = "4")]
+    pub button_index: ::core::option::Option<i32>,
+}
+/// Server side only: full state of a table persisted after every action
+/// so that it can be restored (or refunded) after a restart
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GameSnapshot {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(enumeration = "super::game_state::GameStatus", tag = "2")]
+    pub status: i32,
+    #[prost(message, optional, tag = "3")]
+    pub street: ::core::option::Option<super::game_state::Street>,
+    #[prost(int32, tag = "4")]
+    pub game_bank: i32,
+    #[prost(int32, tag = "5")]
+    pub big_blind: i32,
+    #[prost(int32, tag = "6")]
+    pub raise_amount: i32,
+    #[prost(int32, optional, tag = "7")]
+    pub raiser_index: ::core::option::Option<i32>,
+    #[prost(message, optional, tag = "8")]
+    pub positions: ::core::option::Option<KeyPositionsSnapshot>,
+    #[prost(int32, tag = "9")]
+    pub biggest_bet_on_curr_street: i32,
+    #[prost(message, repeated, tag = "10")]
+    pub action_history: ::prost::alloc::vec::Vec<super::game_state::Action>,
+    #[prost(message, optional, tag = "11")]
+    pub showdown_outcome: ::core::option::Option<super::game_state::ShowdownOutcome>,
+    #[prost(message, repeated, tag = "12")]
+    pub players: ::prost::alloc::vec::Vec<super::player::Player>,
+    #[prost(map = "int32, int32", tag = "13")]
+    pub bank_map: ::std::collections::HashMap<i32, i32>,
+    #[prost(message, repeated, tag = "14")]
+    pub deck: ::prost::alloc::vec::Vec<super::card::Card>,
+}
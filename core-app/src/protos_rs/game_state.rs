@@ -23,17 +23,30 @@ pub struct PlayerCards {
     #[prost(message, optional, tag = "2")]
     pub cards: ::core::option::Option<super::card::CardPair>,
 }
+/// one pot of the showdown and who took it
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PotResult {
+    #[prost(int32, tag = "1")]
+    pub amount: i32,
+    #[prost(message, repeated, tag = "2")]
+    pub winners: ::prost::alloc::vec::Vec<Winner>,
+}
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ShowdownOutcome {
     #[prost(message, optional, tag = "1")]
     pub street_history: ::core::option::Option<Street>,
+    /// winners of the main pot only, pots has all of them
     #[prost(message, repeated, tag = "2")]
     pub winners: ::prost::alloc::vec::Vec<Winner>,
     #[prost(message, repeated, tag = "3")]
     pub players_cards: ::prost::alloc::vec::Vec<PlayerCards>,
     #[prost(bool, tag = "4")]
     pub process_flop_automatically: bool,
+    /// main pot first, then the side pots
+    #[prost(message, repeated, tag = "5")]
+    pub pots: ::prost::alloc::vec::Vec<PotResult>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -0,0 +1,39 @@
+// This file is @generated by prost-build.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HandPlayer {
+    #[prost(int32, tag = "1")]
+    pub user_id: i32,
+    #[prost(bool, tag = "2")]
+    pub is_bot: bool,
+    /// stack after the hand
+    #[prost(int32, tag = "3")]
+    pub bank: i32,
+}
+/// Server side only: a finished hand as it was played, player statistics are computed from it
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HandHistory {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(int32, tag = "2")]
+    pub big_blind: i32,
+    /// seats in table order, indexes below point into it
+    #[prost(message, repeated, tag = "3")]
+    pub players: ::prost::alloc::vec::Vec<HandPlayer>,
+    #[prost(int32, optional, tag = "4")]
+    pub button_index: ::core::option::Option<i32>,
+    #[prost(int32, optional, tag = "5")]
+    pub small_blind_index: ::core::option::Option<i32>,
+    #[prost(int32, optional, tag = "6")]
+    pub big_blind_index: ::core::option::Option<i32>,
+    #[prost(message, repeated, tag = "7")]
+    pub actions: ::prost::alloc::vec::Vec<super::game_state::Action>,
+    #[prost(message, repeated, tag = "8")]
+    pub board: ::prost::alloc::vec::Vec<super::card::Card>,
+    #[prost(message, optional, tag = "9")]
+    pub showdown_outcome: ::core::option::Option<super::game_state::ShowdownOutcome>,
+    /// unix seconds
+    #[prost(int64, tag = "10")]
+    pub finished_at: i64,
+}
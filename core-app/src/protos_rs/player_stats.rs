@@ -0,0 +1,113 @@
+// This file is @generated by prost-build.
+/// Raw counts so the client can show the sample size next to the percentage
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StatCounter {
+    #[prost(int32, tag = "1")]
+    pub made: i32,
+    #[prost(int32, tag = "2")]
+    pub opportunities: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StatLine {
+    #[prost(int32, tag = "1")]
+    pub hands: i32,
+    /// called or raised preflop
+    #[prost(message, optional, tag = "2")]
+    pub vpip: ::core::option::Option<StatCounter>,
+    /// raised preflop
+    #[prost(message, optional, tag = "3")]
+    pub pfr: ::core::option::Option<StatCounter>,
+    /// re-raised the first preflop raise
+    #[prost(message, optional, tag = "4")]
+    pub three_bet: ::core::option::Option<StatCounter>,
+    /// opened, got re-raised and folded
+    #[prost(message, optional, tag = "5")]
+    pub fold_to_three_bet: ::core::option::Option<StatCounter>,
+    /// preflop aggressor bet the flop when checked to
+    #[prost(message, optional, tag = "6")]
+    pub cbet: ::core::option::Option<StatCounter>,
+    /// saw the flop and went to showdown
+    #[prost(message, optional, tag = "7")]
+    pub wtsd: ::core::option::Option<StatCounter>,
+    /// won money at showdown
+    #[prost(message, optional, tag = "8")]
+    pub wsd: ::core::option::Option<StatCounter>,
+    /// aggression factor is aggressive_actions / passive_actions, postflop only
+    #[prost(int32, tag = "9")]
+    pub aggressive_actions: i32,
+    #[prost(int32, tag = "10")]
+    pub passive_actions: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PositionStats {
+    #[prost(enumeration = "Position", tag = "1")]
+    pub position: i32,
+    #[prost(message, optional, tag = "2")]
+    pub stats: ::core::option::Option<StatLine>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PlayerStats {
+    #[prost(int32, tag = "1")]
+    pub user_id: i32,
+    #[prost(message, optional, tag = "2")]
+    pub overall: ::core::option::Option<StatLine>,
+    /// positions without hands are left out
+    #[prost(message, repeated, tag = "3")]
+    pub by_position: ::prost::alloc::vec::Vec<PositionStats>,
+}
+/// Seat relative to the button, only seats dealt into the hand are counted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Position {
+    Utg = 0,
+    Utg1 = 1,
+    Utg2 = 2,
+    Mp = 3,
+    Mp1 = 4,
+    Mp2 = 5,
+    Co = 6,
+    /// heads-up the button posts the small blind and is counted as Sb
+    Btn = 7,
+    Sb = 8,
+    Bb = 9,
+}
+impl Position {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Position::Utg => "Utg",
+            Position::Utg1 => "Utg1",
+            Position::Utg2 => "Utg2",
+            Position::Mp => "Mp",
+            Position::Mp1 => "Mp1",
+            Position::Mp2 => "Mp2",
+            Position::Co => "Co",
+            Position::Btn => "Btn",
+            Position::Sb => "Sb",
+            Position::Bb => "Bb",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "Utg" => Some(Self::Utg),
+            "Utg1" => Some(Self::Utg1),
+            "Utg2" => Some(Self::Utg2),
+            "Mp" => Some(Self::Mp),
+            "Mp1" => Some(Self::Mp1),
+            "Mp2" => Some(Self::Mp2),
+            "Co" => Some(Self::Co),
+            "Btn" => Some(Self::Btn),
+            "Sb" => Some(Self::Sb),
+            "Bb" => Some(Self::Bb),
+            _ => None,
+        }
+    }
+}
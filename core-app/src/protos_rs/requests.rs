@@ -39,6 +39,28 @@ pub struct SpawnBotRequest {
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ResyncRequest {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(int32, tag = "2")]
+    pub player_id: i32,
+    /// last sequence the client applied successfully
+    #[prost(uint64, tag = "3")]
+    pub last_sequence: u64,
+}
+/// sent over the table or spectator websocket, the sender and the table come from the connection
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatMessageRequest {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(int32, tag = "2")]
+    pub player_id: i32,
+    #[prost(string, tag = "3")]
+    pub text: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct PlayerActionRequest {
     #[prost(int32, tag = "1")]
     pub player_id: i32,
@@ -47,3 +69,22 @@ pub struct PlayerActionRequest {
     #[prost(message, optional, tag = "3")]
     pub action: ::core::option::Option<super::game_state::Action>,
 }
+/// Frame of a table websocket. Field numbers don't overlap with PlayerActionRequest,
+/// so a bare PlayerActionRequest of older clients decodes without a request and is read as an action.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TableRequest {
+    #[prost(oneof = "table_request::Request", tags = "4, 5")]
+    pub request: ::core::option::Option<table_request::Request>,
+}
+/// Nested message and enum types in `TableRequest`.
+pub mod table_request {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Request {
+        #[prost(message, tag = "4")]
+        Action(super::PlayerActionRequest),
+        #[prost(message, tag = "5")]
+        Chat(super::ChatMessageRequest),
+    }
+}
@@ -23,12 +23,57 @@ pub struct GameOverMessage {
     #[prost(int32, tag = "2")]
     pub user_id: i32,
 }
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatMessage {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(int32, tag = "2")]
+    pub user_id: i32,
+    #[prost(string, tag = "3")]
+    pub text: ::prost::alloc::string::String,
+    /// unix time in milliseconds
+    #[prost(int64, tag = "4")]
+    pub sent_at: i64,
+    #[prost(bool, tag = "5")]
+    pub is_spectator: bool,
+}
+/// last messages of the table, sent to everyone who sits down or starts watching
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatHistoryMessage {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: ::prost::alloc::vec::Vec<ChatMessage>,
+}
+/// chat message was not posted, sent to its author only
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatRejectedMessage {
+    #[prost(int32, tag = "1")]
+    pub lobby_id: i32,
+    #[prost(string, tag = "2")]
+    pub reason: ::prost::alloc::string::String,
+}
+/// sent to every connection right before the server closes it
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ServerShutdownMessage {
+    #[prost(string, tag = "1")]
+    pub reason: ::prost::alloc::string::String,
+}
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
 pub enum ResponseMessageType {
     StartGame = 0,
     ClientState = 1,
     GameOver = 2,
+    StateDelta = 3,
+    Chat = 4,
+    ChatHistory = 5,
+    ServerShutdown = 6,
+    ChatRejected = 7,
 }
 impl ResponseMessageType {
     /// String value of the enum field names used in the ProtoBuf definition.
@@ -40,6 +85,11 @@ impl ResponseMessageType {
             ResponseMessageType::StartGame => "StartGame",
             ResponseMessageType::ClientState => "ClientState",
             ResponseMessageType::GameOver => "GameOver",
+            ResponseMessageType::StateDelta => "StateDelta",
+            ResponseMessageType::Chat => "Chat",
+            ResponseMessageType::ChatHistory => "ChatHistory",
+            ResponseMessageType::ServerShutdown => "ServerShutdown",
+            ResponseMessageType::ChatRejected => "ChatRejected",
         }
     }
     /// Creates an enum from field names used in the ProtoBuf definition.
@@ -48,6 +98,11 @@ impl ResponseMessageType {
             "StartGame" => Some(Self::StartGame),
             "ClientState" => Some(Self::ClientState),
             "GameOver" => Some(Self::GameOver),
+            "StateDelta" => Some(Self::StateDelta),
+            "Chat" => Some(Self::Chat),
+            "ChatHistory" => Some(Self::ChatHistory),
+            "ServerShutdown" => Some(Self::ServerShutdown),
+            "ChatRejected" => Some(Self::ChatRejected),
             _ => None,
         }
     }
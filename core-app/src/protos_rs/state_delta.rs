@@ -0,0 +1,113 @@
+// This file is @generated by prost-build.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CardsDealt {
+    /// empty when cards are taken away
+    #[prost(message, optional, tag = "1")]
+    pub cards: ::core::option::Option<super::card::CardPair>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PotUpdated {
+    #[prost(int32, tag = "1")]
+    pub total: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PositionsChanged {
+    #[prost(message, optional, tag = "1")]
+    pub button_id: ::core::option::Option<super::google::protobuf::Int32Value>,
+    #[prost(message, optional, tag = "2")]
+    pub small_blind_id: ::core::option::Option<super::google::protobuf::Int32Value>,
+    #[prost(message, optional, tag = "3")]
+    pub big_blind_id: ::core::option::Option<super::google::protobuf::Int32Value>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TurnChanged {
+    #[prost(message, optional, tag = "1")]
+    pub curr_player_id: ::core::option::Option<super::google::protobuf::Int32Value>,
+    #[prost(message, optional, tag = "2")]
+    pub amount_to_call: ::core::option::Option<super::google::protobuf::Int32Value>,
+    #[prost(message, optional, tag = "3")]
+    pub min_amount_to_raise: ::core::option::Option<super::google::protobuf::Int32Value>,
+    #[prost(message, optional, tag = "4")]
+    pub can_raise: ::core::option::Option<super::google::protobuf::BoolValue>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShowdownUpdated {
+    /// empty when showdown is cleared
+    #[prost(message, optional, tag = "1")]
+    pub showdown_outcome: ::core::option::Option<super::game_state::ShowdownOutcome>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RunoutUpdated {
+    #[prost(message, repeated, tag = "1")]
+    pub revealed_cards: ::prost::alloc::vec::Vec<super::game_state::PlayerCards>,
+    #[prost(message, repeated, tag = "2")]
+    pub equities: ::prost::alloc::vec::Vec<super::equity::PlayerEquity>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeltaEvent {
+    #[prost(
+        oneof = "delta_event::Event",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13"
+    )]
+    pub event: ::core::option::Option<delta_event::Event>,
+}
+/// Nested message and enum types in `DeltaEvent`.
+pub mod delta_event {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Event {
+        /// appended to action history
+        #[prost(message, tag = "1")]
+        PlayerActed(super::super::game_state::Action),
+        #[prost(bool, tag = "2")]
+        ActionHistoryCleared(bool),
+        #[prost(message, tag = "3")]
+        CardsDealt(super::CardsDealt),
+        #[prost(message, tag = "4")]
+        StreetUpdated(super::super::game_state::Street),
+        #[prost(message, tag = "5")]
+        PotUpdated(super::PotUpdated),
+        /// joined or changed, replaces player with the same id or is appended
+        #[prost(message, tag = "6")]
+        PlayerUpdated(super::super::player::Player),
+        #[prost(int32, tag = "7")]
+        PlayerLeft(i32),
+        #[prost(message, tag = "8")]
+        PositionsChanged(super::PositionsChanged),
+        #[prost(message, tag = "9")]
+        TurnChanged(super::TurnChanged),
+        #[prost(enumeration = "super::super::game_state::GameStatus", tag = "10")]
+        StatusChanged(i32),
+        #[prost(message, tag = "11")]
+        ShowdownUpdated(super::ShowdownUpdated),
+        #[prost(message, tag = "12")]
+        RunoutUpdated(super::RunoutUpdated),
+        /// street is unset again, e.g. back to waiting for players
+        #[prost(bool, tag = "13")]
+        StreetCleared(bool),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StateDelta {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(int32, tag = "2")]
+    pub lobby_id: i32,
+    #[prost(uint64, tag = "3")]
+    pub sequence: u64,
+    #[prost(uint64, tag = "4")]
+    pub base_sequence: u64,
+    #[prost(message, repeated, tag = "5")]
+    pub events: ::prost::alloc::vec::Vec<DeltaEvent>,
+    /// state_hash of the ClientState after applying the events
+    #[prost(uint64, tag = "6")]
+    pub state_hash: u64,
+}
@@ -0,0 +1,52 @@
+use std::{error::Error, fmt};
+
+use crate::{
+    game_snapshot::SnapshotStore,
+    hand_history::HandHistoryStore,
+    protos::{
+        hand_history::HandHistory,
+        lobby::{Lobby, LobbyList},
+        user::User,
+    },
+};
+
+// Everything the server keeps between restarts. PostgresDatabase is the production backend,
+// InMemoryRepository runs the server without a database for local development and demos
+pub trait Repository: SnapshotStore + HandHistoryStore {
+    fn get_user_by_id(&self, user_id: i32) -> Result<User, DbError>;
+    fn get_users_by_lobby_id(&self, lobby_id: i32) -> Result<Vec<User>, DbError>;
+    fn is_admin(&self, user_id: i32) -> Result<bool, DbError>;
+
+    fn create_lobby(&self, lobby: Lobby) -> Result<i32, DbError>;
+    fn get_lobbies(&self) -> Result<LobbyList, DbError>;
+    fn get_lobby_author_id(&self, lobby_id: i32) -> Result<i32, DbError>;
+
+    fn add_user_to_lobby(&self, lobby_id: i32, user_id: i32) -> Result<(), DbError>;
+
+    // the last `limit` hands of the user, oldest first
+    fn get_hands_by_user_id(&self, user_id: i32, limit: usize)
+        -> Result<Vec<HandHistory>, DbError>;
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    NotFound,
+    // unique, foreign key or check constraint rejected the change
+    ConstraintViolation(String),
+    // no connection could be made or taken from the pool
+    Unavailable(String),
+    Query(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::NotFound => write!(f, "record not found"),
+            DbError::ConstraintViolation(e) => write!(f, "constraint violation: {}", e),
+            DbError::Unavailable(e) => write!(f, "database unavailable: {}", e),
+            DbError::Query(e) => write!(f, "query failed: {}", e),
+        }
+    }
+}
+
+impl Error for DbError {}
@@ -1,13 +1,16 @@
-use std::error::Error;
+use std::{error::Error, sync::mpsc::Sender, time::Instant};
 
 use prost::Message;
 
 use crate::{
-    game_orchestrator::JoinGameMessage,
+    game_orchestrator::{JoinGameMessage, StartGameError},
     protos::{
-        client_state::ClientState, requests::PlayerActionRequest, responses::{ResponseMessageType, StartGameResponse}, user::User
+        client_state::ClientState,
+        requests::{PlayerActionRequest, ResyncRequest},
+        responses::{ResponseMessageType, StartGameResponse},
+        user::User,
     },
-    socket_pool::{ConnectionClosedEvent, ReadMessageError},
+    socket_pool::{ChatEvent, ConnectionClosedEvent, ReadMessageError},
 };
 
 pub struct TMessageResponse {
@@ -34,7 +37,10 @@ impl<M: Message> EncodableMessage for M {
 
 pub enum SocketSourceMessage {
     ConnectionClosed(ConnectionClosedEvent),
-    PlayerActionRequest(Result<PlayerActionRequest, ReadMessageError>),
+    PlayerActionRequest {
+        user_id: i32,
+        result: Result<PlayerActionRequest, ReadMessageError>,
+    },
 }
 #[derive(Debug)]
 pub enum PlayerActionRequestError {
@@ -45,7 +51,21 @@ pub enum PlayerActionRequestError {
 pub enum GameChannelMessage {
     HttpRequestSource(JoinGameMessage),
     SocketSource(SocketSourceMessage),
-    InnerSource(PlayerActionRequest)
+    InnerSource(PlayerActionRequest),
+    Resync(ResyncRequest),
+    Chat(ChatEvent),
+    // admin command, answered right away: the session starts or is already running
+    Start(Sender<Result<(), StartGameError>>),
+    // state is queried by message, nobody outside of the actor reads the game
+    IsReadyToStart(Sender<bool>),
+    // server is going down: finish the hand or refund it at the deadline, persist the game
+    // and answer the sender, the actor stops afterwards
+    Shutdown {
+        deadline: Instant,
+        reply: Sender<()>,
+    },
+    // timer of the actor: a grace period or the shutdown deadline may be over
+    Tick,
 }
 
 pub fn create_message_response<T>(
@@ -56,30 +76,30 @@ pub fn create_message_response<T>(
 where
     T: Message + 'static,
 {
-    return TMessageResponse {
+    TMessageResponse {
         message: Box::new(message),
         receiver_id,
         message_type: t,
-    };
+    }
 }
 
 pub fn generate_client_state_responses(states: Vec<ClientState>) -> Vec<TMessageResponse> {
-    return states
+    states
         .into_iter()
         .map(|state| {
             let receiver_id = state.player_id;
             create_message_response(state, ResponseMessageType::ClientState, receiver_id)
         })
-        .collect();
+        .collect()
 }
 
 pub fn generate_game_started_responses(
     lobby_id: i32,
-    users: &Vec<User>,
+    users: &[User],
     delay: i32,
 ) -> Vec<TMessageResponse> {
-    return users
-        .into_iter()
+    users
+        .iter()
         .map(|user| {
             let receiver_id = user.id;
             create_message_response(
@@ -91,5 +111,5 @@ pub fn generate_game_started_responses(
                 receiver_id,
             )
         })
-        .collect();
+        .collect()
 }
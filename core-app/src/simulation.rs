@@ -0,0 +1,740 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use pokereval_cactus::card::Card as PCard;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    engine::{self, Command, EngineState, Event},
+    equity::evaluator,
+    game::DeckState,
+    game_snapshot::RecoveryPolicy,
+    protos::{
+        client_state::ClientState,
+        game_snapshot::GameSnapshot,
+        game_state::{Action, ActionType},
+        player::Player,
+        requests::PlayerActionRequest,
+    },
+};
+
+// Headless table for offline stress testing of rules and bot strategies.
+// Drives the engine directly, so no sockets, threads or database are involved.
+
+const SIMULATION_LOBBY_ID: i32 = 0;
+// guard against hands which never finish
+const MAX_ACTIONS_PER_HAND: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    // picks any valid action
+    Random,
+    // checks or calls, never raises or folds
+    Passive,
+    // raises whenever allowed, otherwise calls
+    Aggressive,
+    // plays given actions in a loop, invalid ones are replaced with check/call
+    Scripted(Vec<ActionType>),
+}
+
+impl Strategy {
+    pub fn parse(value: &str) -> Option<Strategy> {
+        match value {
+            "random" => Some(Strategy::Random),
+            "passive" => Some(Strategy::Passive),
+            "aggressive" => Some(Strategy::Aggressive),
+            _ => None,
+        }
+    }
+}
+
+pub struct SimulatedPlayer {
+    pub name: String,
+    pub strategy: Strategy,
+}
+
+pub struct SimulationConfig {
+    pub players: Vec<SimulatedPlayer>,
+    pub hands: usize,
+    pub seed: u64,
+    pub blind_size: i32,
+    pub starting_bank: i32,
+}
+
+impl SimulationConfig {
+    pub fn new(players_amount: usize, strategy: Strategy) -> Self {
+        let players = (1..=players_amount)
+            .map(|i| SimulatedPlayer {
+                name: format!("player {}", i),
+                strategy: strategy.clone(),
+            })
+            .collect();
+
+        SimulationConfig {
+            players,
+            hands: 1000,
+            seed: 0,
+            blind_size: 10,
+            starting_bank: 1000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PlayerResult {
+    pub user_id: i32,
+    pub name: String,
+    pub starting_bank: i32,
+    pub final_bank: i32,
+    pub hands_won: usize,
+    pub eliminated_at_hand: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct InvariantViolation {
+    pub hand: usize,
+    pub description: String,
+}
+
+#[derive(Debug)]
+pub struct SimulationReport {
+    pub seed: u64,
+    pub hands_played: usize,
+    pub initial_chips: i32,
+    pub final_chips: i32,
+    pub players: Vec<PlayerResult>,
+    pub violations: Vec<InvariantViolation>,
+}
+
+impl SimulationReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seed: {}", self.seed)?;
+        writeln!(f, "hands played: {}", self.hands_played)?;
+        writeln!(
+            f,
+            "chips: {} at start, {} at the end",
+            self.initial_chips, self.final_chips
+        )?;
+
+        for p in &self.players {
+            write!(
+                f,
+                "  {} (id {}): {} -> {} ({:+}), won {} hands",
+                p.name,
+                p.user_id,
+                p.starting_bank,
+                p.final_bank,
+                p.final_bank - p.starting_bank,
+                p.hands_won
+            )?;
+            match p.eliminated_at_hand {
+                Some(hand) => writeln!(f, ", eliminated at hand {}", hand)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        writeln!(f, "invariant violations: {}", self.violations.len())?;
+        for v in &self.violations {
+            writeln!(f, "  hand {}: {}", v.hand, v.description)?;
+        }
+        Ok(())
+    }
+}
+
+struct Simulation {
+    state: Option<EngineState>,
+    // state before the last command, used to refund a hand the engine panicked in
+    last_snapshot: Option<GameSnapshot>,
+    strategies: HashMap<i32, Strategy>,
+    script_positions: HashMap<i32, usize>,
+    rng: StdRng,
+    total_chips: i32,
+    hand: usize,
+    results: Vec<PlayerResult>,
+    violations: Vec<InvariantViolation>,
+}
+
+pub fn run_simulation(config: SimulationConfig) -> SimulationReport {
+    let mut simulation = Simulation::new(&config);
+    simulation.run(config.hands);
+    simulation.into_report(config.seed)
+}
+
+impl Simulation {
+    fn new(config: &SimulationConfig) -> Self {
+        let mut state = EngineState::with_seed(SIMULATION_LOBBY_ID, config.blind_size, config.seed);
+        let mut strategies = HashMap::new();
+        let mut results = Vec::new();
+
+        for (i, p) in config.players.iter().enumerate() {
+            let user_id = i as i32 + 1;
+            let player = Player {
+                user_name: p.name.clone(),
+                user_id,
+                bank: config.starting_bank,
+                ..Default::default()
+            };
+            (state, _) = engine::apply(state, Command::Join(player));
+
+            strategies.insert(user_id, p.strategy.clone());
+            results.push(PlayerResult {
+                user_id,
+                name: p.name.clone(),
+                starting_bank: config.starting_bank,
+                final_bank: config.starting_bank,
+                hands_won: 0,
+                eliminated_at_hand: None,
+            });
+        }
+
+        Simulation {
+            state: Some(state),
+            last_snapshot: None,
+            strategies,
+            script_positions: HashMap::new(),
+            // strategies use their own stream, so changing a strategy does not change the cards
+            rng: StdRng::seed_from_u64(config.seed.wrapping_add(1)),
+            total_chips: config.starting_bank * config.players.len() as i32,
+            hand: 0,
+            results,
+            violations: Vec::new(),
+        }
+    }
+
+    fn run(&mut self, hands: usize) {
+        let mut events = match self.apply(Command::StartGame) {
+            Some(events) => events,
+            None => return,
+        };
+
+        while self.hand < hands {
+            self.hand += 1;
+
+            let finished = self.play_hand(events);
+
+            if self.hand == hands {
+                return;
+            }
+
+            events = match self.deal_next_hand(finished) {
+                Some(events) => events,
+                None => return,
+            };
+            if events
+                .iter()
+                .any(|e| matches!(e, Event::GameStopped { .. }))
+            {
+                return;
+            }
+        }
+    }
+
+    // A broken hand is refunded and a fresh one is dealt, so one engine bug
+    // doesn't hide the ones which would show up later
+    fn deal_next_hand(&mut self, finished: bool) -> Option<Vec<Event>> {
+        if finished {
+            if let Some(events) = self.apply(Command::NextHand) {
+                return Some(events);
+            }
+        }
+
+        self.refund_hand();
+        let events = self.apply(Command::StartGame)?;
+        if events
+            .iter()
+            .any(|e| matches!(e, Event::CommandRejected { .. }))
+        {
+            return None;
+        }
+        Some(events)
+    }
+
+    // Same recovery as the server after a panic: every player gets back the chips
+    // put in the interrupted hand
+    fn refund_hand(&mut self) {
+        let snapshot = match &self.state {
+            Some(state) => GameSnapshot::new(
+                SIMULATION_LOBBY_ID,
+                &state.game_state,
+                &state.player_state,
+                &state.deck_state,
+            ),
+            None => match self.last_snapshot.take() {
+                Some(snapshot) => snapshot,
+                None => return,
+            },
+        };
+
+        let (game_state, player_state, _) = snapshot.into_states(RecoveryPolicy::Refund);
+        self.state = Some(EngineState {
+            lobby_id: SIMULATION_LOBBY_ID,
+            game_state,
+            player_state,
+            // refunded deck is random, the simulation must stay reproducible
+            deck_state: DeckState::with_seed(self.rng.gen()),
+        });
+    }
+
+    // Returns false when the hand was broken by an invariant violation
+    fn play_hand(&mut self, mut events: Vec<Event>) -> bool {
+        for _ in 0..MAX_ACTIONS_PER_HAND {
+            if events.iter().any(|e| matches!(e, Event::HandFinished)) {
+                self.record_hand_result();
+                // a wrong payout is reported, but the hand is over and its chips stay where they went
+                self.check_showdown();
+                return self.check_chips(true);
+            }
+
+            if let Some(Event::CommandRejected { reason }) = events
+                .iter()
+                .find(|e| matches!(e, Event::CommandRejected { .. }))
+            {
+                self.violation(format!("command rejected: {}", reason));
+                return false;
+            }
+
+            if !self.check_chips(false) {
+                return false;
+            }
+
+            let player_id = match events.iter().rev().find_map(|e| match e {
+                Event::AwaitingAction { player_id, .. } => Some(*player_id),
+                _ => None,
+            }) {
+                Some(id) => id,
+                None => {
+                    self.violation(String::from("next player not found"));
+                    return false;
+                }
+            };
+
+            let state = self.state.as_ref().unwrap();
+            let client_state = state.client_state(player_id);
+            let street = state.game_state.street.street_status;
+            let action = self.decide(player_id, &client_state);
+
+            events = match self.apply(Command::Action(PlayerActionRequest {
+                player_id,
+                lobby_id: SIMULATION_LOBBY_ID,
+                action: Some(action),
+            })) {
+                Some(events) => events,
+                None => return false,
+            };
+
+            if !self.check_turn_order(player_id, street, &events) {
+                return false;
+            }
+        }
+
+        self.violation(format!(
+            "hand is not finished after {} actions",
+            MAX_ACTIONS_PER_HAND
+        ));
+        false
+    }
+
+    // Engine panics are reported as violations, the state before the command is kept for the refund
+    fn apply(&mut self, command: Command) -> Option<Vec<Event>> {
+        let state = self.state.take().unwrap();
+        self.last_snapshot = Some(GameSnapshot::new(
+            SIMULATION_LOBBY_ID,
+            &state.game_state,
+            &state.player_state,
+            &state.deck_state,
+        ));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| engine::apply(state, command)));
+
+        match result {
+            Ok((state, events)) => {
+                self.state = Some(state);
+                self.track_eliminations(&events);
+                Some(events)
+            }
+            Err(e) => {
+                let message = e
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("unknown panic"));
+                self.violation(format!("engine panicked: {}", message));
+                None
+            }
+        }
+    }
+
+    fn decide(&mut self, player_id: i32, client_state: &ClientState) -> Action {
+        let amount_to_call = client_state.amount_to_call.as_ref().map_or(0, |v| v.value);
+        let min_raise = client_state
+            .min_amount_to_raise
+            .as_ref()
+            .map_or(0, |v| v.value);
+        let can_raise = client_state.can_raise.as_ref().is_some_and(|v| v.value);
+        let bank = client_state
+            .players
+            .iter()
+            .find(|p| p.user_id == player_id)
+            .map_or(0, |p| p.bank);
+        let can_raise = can_raise && bank > amount_to_call;
+
+        let passive = if amount_to_call == 0 {
+            ActionType::Check
+        } else {
+            ActionType::Call
+        };
+
+        let action_type = match self.strategies.get(&player_id).unwrap() {
+            Strategy::Passive => passive,
+            Strategy::Aggressive if can_raise => ActionType::Raise,
+            Strategy::Aggressive => passive,
+            Strategy::Random => {
+                let mut options = vec![passive];
+                if amount_to_call > 0 {
+                    options.push(ActionType::Fold);
+                }
+                if can_raise {
+                    options.push(ActionType::Raise);
+                }
+                options[self.rng.gen_range(0..options.len())]
+            }
+            Strategy::Scripted(script) => {
+                let position = self.script_positions.entry(player_id).or_insert(0);
+                let action_type = script
+                    .get(*position % script.len().max(1))
+                    .copied()
+                    .unwrap_or(passive);
+                *position += 1;
+                match action_type {
+                    ActionType::Raise if can_raise => ActionType::Raise,
+                    ActionType::Fold if amount_to_call > 0 => ActionType::Fold,
+                    _ => passive,
+                }
+            }
+        };
+
+        let bet = match action_type {
+            ActionType::Call => amount_to_call,
+            // a short stack raises all in
+            ActionType::Raise => min_raise.min(bank),
+            _ => 0,
+        };
+
+        Action {
+            action_type: action_type.into(),
+            bet,
+            player_id,
+            street_status: None,
+        }
+    }
+
+    // Within a betting round nobody who can still act may be passed over
+    fn check_turn_order(&mut self, actor: i32, street: i32, events: &[Event]) -> bool {
+        let next = match events.iter().rev().find_map(|e| match e {
+            Event::AwaitingAction { player_id, .. } => Some(*player_id),
+            _ => None,
+        }) {
+            Some(id) => id,
+            None => return true,
+        };
+
+        let state = self.state.as_ref().unwrap();
+        if state.game_state.street.street_status != street {
+            return true;
+        }
+
+        let players = &state.player_state.players;
+        let seat = |user_id: i32| players.iter().position(|p| p.user_id == user_id);
+        let (from, to) = match (seat(actor), seat(next)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return true,
+        };
+
+        let skipped: Vec<i32> = (1..players.len())
+            .map(|offset| (from + offset) % players.len())
+            .take_while(|&index| index != to)
+            .map(|index| &players[index])
+            .filter(|p| {
+                let folded = p
+                    .action
+                    .as_ref()
+                    .is_some_and(|a| a.action_type() == ActionType::Fold);
+                !folded && p.bank > 0
+            })
+            .map(|p| p.user_id)
+            .collect();
+
+        if skipped.is_empty() {
+            return true;
+        }
+
+        self.violation(format!(
+            "turn skipped players {:?}: {} acted and {} is awaited",
+            skipped, actor, next
+        ));
+        false
+    }
+
+    fn check_chips(&mut self, hand_finished: bool) -> bool {
+        let state = self.state.as_ref().unwrap();
+        let players = &state.player_state.players;
+
+        if let Some(p) = players.iter().find(|p| p.bank < 0) {
+            let description = format!("player {} has negative bank {}", p.user_id, p.bank);
+            self.violation(description);
+            return false;
+        }
+
+        let banks: i32 = players.iter().map(|p| p.bank).sum();
+        let bets: i32 = players.iter().map(|p| p.bet_in_current_seed).sum();
+
+        // after the showdown the pot is already paid out
+        let pot = if hand_finished {
+            0
+        } else {
+            state.game_state.game_bank
+        };
+
+        if banks + pot != self.total_chips {
+            let description = format!(
+                "chips are not conserved: {} in banks + {} in pot, expected {}",
+                banks, pot, self.total_chips
+            );
+            // keep going with the new total, so one leak is reported once
+            self.total_chips = banks + pot;
+            self.violation(description);
+        }
+
+        if !hand_finished && bets != pot {
+            let description = format!("pot {} doesn't match sum of player bets {}", pot, bets);
+            self.violation(description);
+        }
+
+        true
+    }
+
+    // Every player who hasn't folded has at least the main pot in, so its winners must hold
+    // the best hand among them
+    fn check_showdown(&mut self) {
+        let state = self.state.as_ref().unwrap();
+        let outcome = match &state.game_state.showdown_outcome {
+            Some(outcome) => outcome,
+            None => return,
+        };
+
+        let contenders: Vec<_> = state
+            .player_state
+            .players
+            .iter()
+            .filter(|p| {
+                p.action
+                    .as_ref()
+                    .is_some_and(|a| a.action_type() != ActionType::Fold)
+            })
+            .filter_map(|p| {
+                let cards = p.cards.as_ref()?;
+                Some((p.user_id, [cards.card1.as_ref()?, cards.card2.as_ref()?]))
+            })
+            .collect();
+        if contenders.len() < 2 {
+            return;
+        }
+
+        let board = &state.game_state.street.cards;
+        if board.len() != 5 {
+            let description = format!("showdown with {} board cards", board.len());
+            self.violation(description);
+            return;
+        }
+        let board: Vec<i32> = board.iter().map(|c| PCard::new(c.to_string())).collect();
+
+        // cactus kev ranks: the lower the rank, the stronger the hand
+        let ranks: Vec<(i32, i32)> = contenders
+            .into_iter()
+            .map(|(user_id, cards)| {
+                let hand = cards.iter().map(|c| PCard::new(c.to_string())).collect();
+                (user_id, evaluator().evaluate(hand, board.clone()))
+            })
+            .collect();
+        let best = ranks.iter().map(|(_, rank)| *rank).min().unwrap();
+
+        let mut expected: Vec<i32> = ranks
+            .iter()
+            .filter(|(_, rank)| *rank == best)
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        let mut paid: Vec<i32> = outcome.winners.iter().map(|w| w.player_id).collect();
+        expected.sort();
+        paid.sort();
+
+        if paid != expected {
+            let description = format!(
+                "main pot paid to {:?}, best hand is held by {:?}",
+                paid, expected
+            );
+            self.violation(description);
+        }
+    }
+
+    fn record_hand_result(&mut self) {
+        let state = self.state.as_ref().unwrap();
+
+        let winners: Vec<i32> = state
+            .game_state
+            .showdown_outcome
+            .as_ref()
+            .map(|o| o.winners.iter().map(|w| w.player_id).collect())
+            .unwrap_or_default();
+
+        for result in self.results.iter_mut() {
+            if winners.contains(&result.user_id) {
+                result.hands_won += 1;
+            }
+            if let Some(p) = state
+                .player_state
+                .players
+                .iter()
+                .find(|p| p.user_id == result.user_id)
+            {
+                result.final_bank = p.bank;
+            }
+        }
+    }
+
+    fn track_eliminations(&mut self, events: &[Event]) {
+        for event in events {
+            if let Event::PlayerEliminated { player_id } = event {
+                if let Some(result) = self.results.iter_mut().find(|r| r.user_id == *player_id) {
+                    result.final_bank = 0;
+                    // reported by NextHand, so self.hand is still the hand the player busted in
+                    result.eliminated_at_hand = Some(self.hand);
+                }
+            }
+        }
+    }
+
+    fn violation(&mut self, description: String) {
+        self.violations.push(InvariantViolation {
+            hand: self.hand,
+            description,
+        });
+    }
+
+    fn into_report(self, seed: u64) -> SimulationReport {
+        let final_chips = self.results.iter().map(|r| r.final_bank).sum();
+        SimulationReport {
+            seed,
+            hands_played: self.hand,
+            initial_chips: self.results.iter().map(|r| r.starting_bank).sum(),
+            final_chips,
+            players: self.results,
+            violations: self.violations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::{
+        card::{Card, CardPair, CardSuit, CardValue},
+        game_state::{ShowdownOutcome, Winner},
+    };
+
+    fn simulate(players: usize, strategy: Strategy, seed: u64) -> SimulationReport {
+        let mut config = SimulationConfig::new(players, strategy);
+        config.hands = 200;
+        config.seed = seed;
+        run_simulation(config)
+    }
+
+    #[test]
+    fn six_players_play_without_violations() {
+        for strategy in [Strategy::Random, Strategy::Passive, Strategy::Aggressive] {
+            for seed in 0..3 {
+                let report = simulate(6, strategy.clone(), seed);
+                assert!(report.is_ok(), "{:?} seed {}:\n{}", strategy, seed, report);
+                assert_eq!(report.final_chips, report.initial_chips);
+            }
+        }
+    }
+
+    #[test]
+    fn full_ring_plays_requested_hands() {
+        let report = simulate(10, Strategy::Passive, 7);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.hands_played, 200);
+    }
+
+    #[test]
+    fn showdown_paid_to_a_weaker_hand_is_reported() {
+        let mut simulation = Simulation::new(&SimulationConfig::new(2, Strategy::Passive));
+        simulation.apply(Command::StartGame).unwrap();
+
+        let card = |suit: CardSuit, value: CardValue| Some(Card::new(suit, value));
+        let state = simulation.state.as_mut().unwrap();
+        for player in state.player_state.players.iter_mut() {
+            let value = if player.user_id == 1 {
+                CardValue::Ace
+            } else {
+                CardValue::King
+            };
+            player.cards = Some(CardPair {
+                card1: card(CardSuit::Spades, value),
+                card2: card(CardSuit::Hearts, value),
+            });
+            player.action = Some(Action {
+                action_type: ActionType::Call.into(),
+                player_id: player.user_id,
+                ..Default::default()
+            });
+        }
+        state.game_state.street.cards = [
+            (CardSuit::Clubs, CardValue::Two),
+            (CardSuit::Diamonds, CardValue::Seven),
+            (CardSuit::Clubs, CardValue::Nine),
+            (CardSuit::Diamonds, CardValue::Three),
+            (CardSuit::Clubs, CardValue::Four),
+        ]
+        .into_iter()
+        .map(|(suit, value)| Card::new(suit, value))
+        .collect();
+
+        let paid_to = |player_id: i32| ShowdownOutcome {
+            winners: vec![Winner {
+                player_id,
+                win_amout: 100,
+            }],
+            ..Default::default()
+        };
+
+        let state = simulation.state.as_mut().unwrap();
+        state.game_state.showdown_outcome = Some(paid_to(1));
+        simulation.check_showdown();
+        assert!(simulation.violations.is_empty());
+
+        let state = simulation.state.as_mut().unwrap();
+        state.game_state.showdown_outcome = Some(paid_to(2));
+        simulation.check_showdown();
+        assert_eq!(simulation.violations.len(), 1);
+    }
+
+    #[test]
+    fn same_seed_gives_same_result() {
+        let first = simulate(6, Strategy::Random, 11);
+        let second = simulate(6, Strategy::Random, 11);
+        let banks =
+            |r: &SimulationReport| r.players.iter().map(|p| p.final_bank).collect::<Vec<_>>();
+        assert_eq!(first.hands_played, second.hands_played);
+        assert_eq!(banks(&first), banks(&second));
+    }
+}
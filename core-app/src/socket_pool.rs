@@ -1,332 +1,1071 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
     io::ErrorKind,
+    mem,
     net::TcpStream,
-    sync::{Arc, Mutex, MutexGuard, RwLock},
-    thread::{self},
-    time::{Duration, SystemTime},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use tungstenite::{protocol::CloseFrame, Error as TError, Message as TMessage, WebSocket};
+use mio::{unix::SourceFd, Events, Interest, Poll, Registry, Token, Waker};
+use tungstenite::{Error as TError, Message as TMessage, WebSocket};
+
+use prost::Message;
 
 use crate::{
-    protos::responses::ResponseMessage,
+    message_queue::{is_durable, MessageQueue},
+    protos::{
+        requests::{table_request::Request, TableRequest},
+        responses::{ResponseMessage, ResponseMessageType},
+    },
     responses::{EncodableMessage, TMessageResponse},
 };
 
+// every connection is pinged this often, a connection silent for IDLE_TIMEOUT is closed
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+// messages received while nobody reads them, the oldest are dropped
+const MAX_INBOX_SIZE: usize = 16;
+// messages waiting for a slow client, see Connection::enqueue for what happens beyond it
+const MAX_OUTBOX_SIZE: usize = 64;
+// how often close_all checks whether everything is closed
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const WAKER: Token = Token(0);
+
+// A user has one connection per table plus the lobby feed without a table,
+// so several tables can be played at once. Watching a table is a connection of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionKey {
+    pub user_id: i32,
+    pub lobby_id: Option<i32>,
+    pub spectator: bool,
+}
+
+impl ConnectionKey {
+    pub fn table(user_id: i32, lobby_id: i32) -> Self {
+        ConnectionKey {
+            user_id,
+            lobby_id: Some(lobby_id),
+            spectator: false,
+        }
+    }
+
+    pub fn feed(user_id: i32) -> Self {
+        ConnectionKey {
+            user_id,
+            lobby_id: None,
+            spectator: false,
+        }
+    }
+
+    pub fn spectator(user_id: i32, lobby_id: i32) -> Self {
+        ConnectionKey {
+            user_id,
+            lobby_id: Some(lobby_id),
+            spectator: true,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.lobby_id, self.spectator) {
+            (Some(lobby_id), true) => write!(f, "spectator {} of lobby {}", self.user_id, lobby_id),
+            (Some(lobby_id), false) => write!(f, "client {} at lobby {}", self.user_id, lobby_id),
+            (None, _) => write!(f, "client {}", self.user_id),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ConnectionClosedEvent {
     pub user_id: i32,
+    // None for the lobby feed
+    pub lobby_id: Option<i32>,
+}
+
+// chat message sent over a table or spectator connection, the key tells who sent it and where
+#[derive(Clone)]
+pub struct ChatEvent {
+    pub key: ConnectionKey,
+    pub text: String,
+}
+
+pub type ConnectionClosedListeners =
+    Arc<Mutex<Vec<Box<dyn Fn(ConnectionClosedEvent) + Send + Sync>>>>;
+pub type SendEventListeners = Arc<Mutex<Vec<Box<dyn Fn(SendEvent) + Send + Sync>>>>;
+pub type ChatListeners = Arc<Mutex<Vec<Box<dyn Fn(ChatEvent) + Send + Sync>>>>;
+
+#[derive(Debug, Clone)]
+pub enum SendError {
+    NotConnected,
+    QueueFull,
+    Io(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NotConnected => write!(f, "client is not connected"),
+            SendError::QueueFull => write!(
+                f,
+                "client doesn't keep up, {} messages are waiting",
+                MAX_OUTBOX_SIZE
+            ),
+            SendError::Io(e) => write!(f, "write failed: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SendEvent {
+    // client fell behind and its queued table states were dropped, it needs a full state
+    Coalesced {
+        key: ConnectionKey,
+    },
+    // message is not delivered, a connected client is disconnected
+    Failed {
+        key: ConnectionKey,
+        reason: SendError,
+    },
 }
 
 pub struct PlayerChannelClient {
     pub client_id: i32,
+    // table the socket was opened for, None for the lobby feed
+    pub lobby_id: Option<i32>,
     pub socket: WebSocket<TcpStream>,
 }
-pub struct SocketPool {
-    pool: Arc<RwLock<HashMap<i32, Arc<Mutex<WebSocket<TcpStream>>>>>>,
-}
 
 #[derive(Debug)]
 pub enum ReadMessageError {
     Iddle,
     Disconnected,
+    Malformed,
+}
+
+type ReadCallback = Box<dyn FnOnce(Result<Vec<u8>, ReadMessageError>) + Send>;
+
+// pending read of one connection, completed by the reactor
+struct Waiter {
+    deadline: Instant,
+    callback: ReadCallback,
+}
+
+struct Outgoing {
+    message_type: ResponseMessageType,
+    // encoded ResponseMessage
+    bytes: Vec<u8>,
+    // spectators may see the table with a delay, everybody else gets it right away
+    send_at: Instant,
+}
+
+// what a table connection sent, see TableRequest
+enum TableFrame {
+    // encoded PlayerActionRequest
+    Action(Vec<u8>),
+    Chat(String),
+}
+
+enum Enqueued {
+    Queued,
+    Coalesced,
+    Overflow,
+}
+
+struct Connection {
+    token: Token,
+    socket: WebSocket<TcpStream>,
+    // any frame, pongs included, counts as a sign of life
+    last_seen: Instant,
+    inbox: VecDeque<Vec<u8>>,
+    // drained by the reactor as fast as the client reads
+    outbox: VecDeque<Outgoing>,
+    // a write failed or the client is too slow, the reactor closes the connection on its next turn
+    broken: bool,
+    // server is shutting down, closed as soon as the outbox is written out
+    closing: bool,
+}
+
+impl Connection {
+    // Slow consumer policy: when the outbox is full the queued table states are dropped,
+    // the next full state supersedes them. A client whose outbox is still full after that
+    // doesn't read at all and gets disconnected.
+    fn enqueue(&mut self, message: Outgoing) -> Enqueued {
+        if self.outbox.len() < MAX_OUTBOX_SIZE {
+            self.outbox.push_back(message);
+            return Enqueued::Queued;
+        }
+
+        self.outbox.retain(|m| !is_table_state(m.message_type));
+        // a delta can't be applied without the dropped ones, the full state is sent instead
+        if is_table_state(message.message_type) {
+            return Enqueued::Coalesced;
+        }
+        if self.outbox.len() < MAX_OUTBOX_SIZE {
+            self.outbox.push_back(message);
+            return Enqueued::Coalesced;
+        }
+        Enqueued::Overflow
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    by_key: HashMap<ConnectionKey, Connection>,
+    tokens: HashMap<Token, ConnectionKey>,
+    waiters: HashMap<ConnectionKey, Waiter>,
+    // connections with new messages in the outbox
+    pending_writes: HashSet<ConnectionKey>,
+    // connections whose next message is not due yet
+    delayed_writes: HashSet<ConnectionKey>,
+    // reported by the reactor once the lock is released
+    send_events: Vec<SendEvent>,
+    next_token: usize,
+}
+
+// Websockets of players. All of them are non blocking and registered in a single
+// epoll reactor thread: reads complete callbacks instead of occupying a thread per
+// waiting player, writes go through a bounded queue per connection drained by the
+// reactor, and liveness is tracked by pings from the same thread.
+pub struct SocketPool {
+    connections: Arc<Mutex<Connections>>,
+    registry: Registry,
+    waker: Arc<Waker>,
+    listeners: ConnectionClosedListeners,
+    send_listeners: SendEventListeners,
+    chat_listeners: ChatListeners,
+    // messages for users who are not connected right now
+    offline_queue: Arc<MessageQueue>,
+}
+
+impl Default for SocketPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SocketPool {
     pub fn new() -> Self {
+        Self::with_offline_queue(MessageQueue::default())
+    }
+
+    pub fn with_offline_queue(offline_queue: MessageQueue) -> Self {
+        let poll = Poll::new().expect("failed to create socket reactor");
+        let registry = poll
+            .registry()
+            .try_clone()
+            .expect("failed to clone reactor registry");
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).expect("failed to create waker"));
+
+        let connections = Arc::new(Mutex::new(Connections {
+            next_token: WAKER.0 + 1,
+            ..Default::default()
+        }));
+        let listeners: ConnectionClosedListeners = Arc::new(Mutex::new(Vec::new()));
+        let send_listeners: SendEventListeners = Arc::new(Mutex::new(Vec::new()));
+        let chat_listeners: ChatListeners = Arc::new(Mutex::new(Vec::new()));
+        let offline_queue = Arc::new(offline_queue);
+
+        let reactor = Reactor {
+            poll,
+            connections: Arc::clone(&connections),
+            listeners: Arc::clone(&listeners),
+            send_listeners: Arc::clone(&send_listeners),
+            chat_listeners: Arc::clone(&chat_listeners),
+            offline_queue: Arc::clone(&offline_queue),
+        };
+        thread::Builder::new()
+            .name(String::from("socket-reactor"))
+            .spawn(move || reactor.run())
+            .expect("failed to spawn socket reactor");
+
         Self {
-            pool: Arc::new(RwLock::new(HashMap::new())),
+            connections,
+            registry,
+            waker,
+            listeners,
+            send_listeners,
+            chat_listeners,
+            offline_queue,
         }
     }
 
-    pub fn add(&self, v: PlayerChannelClient) {
-        let mut pool = self.pool.try_write().unwrap();
+    pub fn add(&self, mut v: PlayerChannelClient) {
+        let key = ConnectionKey {
+            user_id: v.client_id,
+            lobby_id: v.lobby_id,
+            spectator: false,
+        };
+        if let Err(e) = v.socket.get_ref().set_nonblocking(true) {
+            println!("failed to make socket of {} non blocking: {}", key, e);
+            return;
+        }
 
-        pool.insert(v.client_id, Arc::new(Mutex::new(v.socket)));
+        let delivered = self.offline_queue.deliver(v.client_id, |message| {
+            match v.socket.send(TMessage::Binary(message.to_vec())) {
+                Ok(_) => true,
+                // message is buffered and goes out when the socket is writable
+                Err(TError::Io(e)) if e.kind() == ErrorKind::WouldBlock => true,
+                Err(_) => false,
+            }
+        });
+        if delivered > 0 {
+            println!(
+                "delivered {} queued messages to client {}",
+                delivered, v.client_id
+            );
+        }
 
-        println!("LENGTH: {}", pool.len())
+        self.register(key, v.socket, Vec::new());
     }
 
-    pub fn read_client_message<T: prost::Message + Default + 'static>(
+    // Spectators only get what their table broadcasts, see update_spectators. The first
+    // messages (send at, message) go out before anything broadcast afterwards.
+    pub fn add_spectator(
         &self,
-        client_id: i32,
-    ) -> Result<T, ReadMessageError> {
-        let socket = match self.get_channel(&client_id) {
-            Some(s) => s,
-            None => {
-                println!(
-                    "User disconnected and removed from pool before health check event: {}",
-                    &client_id
-                );
-                return Err(ReadMessageError::Disconnected);
-            }
-        };
+        user_id: i32,
+        lobby_id: i32,
+        socket: WebSocket<TcpStream>,
+        first_messages: Vec<(Instant, ResponseMessage)>,
+    ) {
+        let key = ConnectionKey::spectator(user_id, lobby_id);
+        if let Err(e) = socket.get_ref().set_nonblocking(true) {
+            println!("failed to make socket of {} non blocking: {}", key, e);
+            return;
+        }
 
-        let result = self.read_non_blocking(socket);
+        self.register(key, socket, first_messages);
+    }
 
-        let message = match result {
-            Err(e) => match e {
-                TError::ConnectionClosed => {
-                    self.remove_connection(&client_id);
-                    return Err(ReadMessageError::Disconnected);
-                }
-                TError::AlreadyClosed => {
-                    self.remove_connection(&client_id);
-                    return Err(ReadMessageError::Disconnected);
+    fn register(
+        &self,
+        key: ConnectionKey,
+        socket: WebSocket<TcpStream>,
+        first_messages: Vec<(Instant, ResponseMessage)>,
+    ) {
+        let mut connections = self.connections.lock().unwrap();
+        let token = Token(connections.next_token);
+        connections.next_token += 1;
+
+        // edge triggered: the reactor reads and flushes until WouldBlock
+        let fd = socket.get_ref().as_raw_fd();
+        if let Err(e) = self.registry.register(
+            &mut SourceFd(&fd),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            println!("failed to register socket of {}: {}", key, e);
+            return;
+        }
+
+        // reconnect to the same table replaces the old socket, a pending read keeps waiting for the new one
+        if let Some(old) = connections.by_key.remove(&key) {
+            connections.tokens.remove(&old.token);
+            deregister(&self.registry, &old.socket);
+        }
+
+        let outbox: VecDeque<Outgoing> = first_messages
+            .into_iter()
+            .map(|(send_at, message)| Outgoing {
+                message_type: message.payload_type(),
+                bytes: message.encode_message(),
+                send_at,
+            })
+            .collect();
+        if !outbox.is_empty() {
+            connections.pending_writes.insert(key);
+        }
+
+        connections.tokens.insert(token, key);
+        connections.by_key.insert(
+            key,
+            Connection {
+                token,
+                socket,
+                last_seen: Instant::now(),
+                inbox: VecDeque::new(),
+                outbox,
+                broken: false,
+                closing: false,
+            },
+        );
+
+        drop(connections);
+        self.wake();
+    }
+
+    pub fn has_connection(&self, key: ConnectionKey) -> bool {
+        self.connections.lock().unwrap().by_key.contains_key(&key)
+    }
+
+    // Calls on_message with the next message of the client at the table, or with an error
+    // when the client disconnects or doesn't answer within timeout. Never blocks: the callback
+    // runs on the reactor thread, or right away if a message is already waiting.
+    pub fn read_client_message<T, F>(
+        &self,
+        client_id: i32,
+        lobby_id: i32,
+        timeout: Duration,
+        on_message: F,
+    ) where
+        T: prost::Message + Default + 'static,
+        F: FnOnce(Result<T, ReadMessageError>) + Send + 'static,
+    {
+        let key = ConnectionKey::table(client_id, lobby_id);
+        let callback: ReadCallback = Box::new(move |result| {
+            on_message(result.and_then(|bytes| {
+                T::decode(bytes.as_slice()).map_err(|e| {
+                    println!("{} sent malformed message: {}", key, e);
+                    ReadMessageError::Malformed
+                })
+            }))
+        });
+
+        let mut connections = self.connections.lock().unwrap();
+        let ready = match connections.by_key.get_mut(&key) {
+            None => Err(ReadMessageError::Disconnected),
+            Some(connection) => match connection.inbox.pop_front() {
+                Some(message) => Ok(message),
+                None => {
+                    let waiter = Waiter {
+                        deadline: Instant::now() + timeout,
+                        callback,
+                    };
+                    if connections.waiters.insert(key, waiter).is_some() {
+                        println!("pending read of {} is replaced", key);
+                    }
+                    drop(connections);
+                    // the reactor may be sleeping past the new deadline
+                    self.wake();
+                    return;
                 }
-                TError::Io(_) => return Err(ReadMessageError::Iddle),
-                TError::Tls(_) => todo!(),
-                TError::Capacity(_) => todo!(),
-                TError::Protocol(_) => todo!(),
-                TError::WriteBufferFull(_) => todo!(),
-                TError::Utf8 => todo!(),
-                TError::AttackAttempt => todo!(),
-                TError::Url(_) => todo!(),
-                TError::Http(_) => todo!(),
-                TError::HttpFormat(_) => todo!(),
             },
-            Ok(r) => r,
         };
+        drop(connections);
 
-        let bytes = match message {
-            TMessage::Binary(bytes) => bytes,
-            TMessage::Close(frame) => {
-                let connection = self.remove_connection(&client_id);
-                self.close_connection(connection, frame);
-                return Err(ReadMessageError::Disconnected);
-            }
-            _ => panic!("Expected binary message"),
+        if ready.is_err() {
+            println!("{} disconnected and removed from pool before read", key);
+        }
+        callback(ready);
+    }
+
+    // Messages outside of any table go through the lobby feed
+    pub fn update_clients(&self, responses: Vec<TMessageResponse>) -> Vec<i32> {
+        self.queue_messages(None, responses)
+    }
+
+    pub fn update_table_clients(
+        &self,
+        lobby_id: i32,
+        responses: Vec<TMessageResponse>,
+    ) -> Vec<i32> {
+        self.queue_messages(Some(lobby_id), responses)
+    }
+
+    // Same message to every spectator of the table, written out by the reactor at send_at
+    pub fn update_spectators(&self, lobby_id: i32, message: ResponseMessage, send_at: Instant) {
+        self.queue_spectator_messages(lobby_id, None, message, send_at);
+    }
+
+    // Message for one spectator only, it goes out after what the spectator has queued already
+    pub fn update_spectator(&self, user_id: i32, lobby_id: i32, message: ResponseMessage) {
+        self.queue_spectator_messages(lobby_id, Some(user_id), message, Instant::now());
+    }
+
+    // all spectators of the table when user_id is None
+    fn queue_spectator_messages(
+        &self,
+        lobby_id: i32,
+        user_id: Option<i32>,
+        message: ResponseMessage,
+        send_at: Instant,
+    ) {
+        let message_type = message.payload_type();
+        let bytes = message.encode_message();
+        let outgoing = || Outgoing {
+            message_type,
+            bytes: bytes.clone(),
+            send_at,
         };
 
-        let mut reader = std::io::Cursor::new(bytes);
+        let mut guard = self.connections.lock().unwrap();
+        let connections = &mut *guard;
+        for (key, connection) in connections.by_key.iter_mut().filter(|(key, _)| {
+            key.spectator
+                && key.lobby_id == Some(lobby_id)
+                && user_id.is_none_or(|id| id == key.user_id)
+        }) {
+            let mut enqueued = connection.enqueue(outgoing());
+            // spectators get full states only, after the queued ones are dropped the new one is all they need
+            if let (Enqueued::Coalesced, true) = (&enqueued, is_table_state(message_type)) {
+                enqueued = connection.enqueue(outgoing());
+            }
+            if let Enqueued::Overflow = enqueued {
+                println!("{} doesn't read, disconnecting", key);
+                connection.broken = true;
+                continue;
+            }
+            connections.pending_writes.insert(*key);
+        }
+        drop(guard);
 
-        let request = T::decode(&mut reader).unwrap();
-        Ok(request)
+        self.wake();
     }
 
-    // TODO: return Array of results
-    pub fn update_clients(&self, responses: Vec<TMessageResponse>) -> Vec<i32> {
+    // Only queues the messages, the reactor writes them as fast as each client reads.
+    // Returns clients the messages were not queued for, failures are also reported as SendEvent.
+    fn queue_messages(&self, lobby_id: Option<i32>, responses: Vec<TMessageResponse>) -> Vec<i32> {
         let mut unsuccessful_clients = Vec::new();
-
-        let client_channels = self.pool.try_read().unwrap();
+        let mut guard = self.connections.lock().unwrap();
+        let connections = &mut *guard;
 
         for response in responses {
+            let user_id = response.receiver_id;
+            let key = ConnectionKey {
+                user_id,
+                lobby_id,
+                spectator: false,
+            };
             let response_message = ResponseMessage {
                 payload: response.message.encode_message(),
                 payload_type: response.message_type.into(),
             };
+            let message = Outgoing {
+                message_type: response.message_type,
+                bytes: response_message.encode_message(),
+                send_at: Instant::now(),
+            };
 
-            match client_channels.get(&response.receiver_id) {
-                Some(s) => {
-                    match s.lock() {
-                        Ok(mut guard) => {
-                            match guard.send(TMessage::Binary(response_message.encode_message())) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    println!("Error occurred during sending message to client {}: {}", response.receiver_id, e);
-                                    unsuccessful_clients.push(response.receiver_id);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            println!("Failed to lock mutex for client {}: {}", response.receiver_id, e);
-                            unsuccessful_clients.push(response.receiver_id);
-                        }
-                    }
-                },
-                None => {
-                    println!(
-                        "no such user connection with provided id: {}",
-                        &response.receiver_id
-                    );
-                    unsuccessful_clients.push(response.receiver_id);
-
+            let connection = match connections.by_key.get_mut(&key) {
+                Some(connection) if !connection.broken => connection,
+                _ => {
+                    println!("no connection of {}", key);
+                    connections.send_events.push(SendEvent::Failed {
+                        key,
+                        reason: SendError::NotConnected,
+                    });
+                    self.keep_if_durable(user_id, message);
+                    unsuccessful_clients.push(user_id);
+                    continue;
                 }
             };
-        }
 
-        drop(client_channels);
-
-        for c in unsuccessful_clients.iter() {
-            self.remove_connection(c);
+            let durable = is_durable(message.message_type).then(|| message.bytes.clone());
+            match connection.enqueue(message) {
+                Enqueued::Queued => {}
+                Enqueued::Coalesced => {
+                    println!("{} is slow, queued table states dropped", key);
+                    connections.send_events.push(SendEvent::Coalesced { key });
+                }
+                Enqueued::Overflow => {
+                    println!("{} doesn't read, disconnecting", key);
+                    connection.broken = true;
+                    connections.send_events.push(SendEvent::Failed {
+                        key,
+                        reason: SendError::QueueFull,
+                    });
+                    if let Some(bytes) = durable {
+                        self.offline_queue.push(user_id, bytes);
+                    }
+                    unsuccessful_clients.push(user_id);
+                    continue;
+                }
+            }
+            connections.pending_writes.insert(key);
         }
+        drop(guard);
+
+        self.wake();
 
         unsuccessful_clients
     }
 
-    // TODO: return Result instead
-
-    pub fn check_connection_health(&self, connection_id: i32) -> bool {
-        let pool = self.pool.read().unwrap();
-        let connected = match pool.get(&connection_id) {
-            Some(socket) => {
-                let mut socket_guard = socket.lock().unwrap();
-                let mut connected = false;
-
-                SocketPool::ping(&mut socket_guard);
-                let time = SystemTime::now();
-
-                loop {
-                    match socket_guard.read() {
-                        Ok(r) => match r {
-                            TMessage::Pong(_) => {
-                                connected = true;
-                                break;
-                            }
-                            TMessage::Close(_) => {
-                                connected = false;
-                                break;
-                            }
-                            _ => {
-                                if time.elapsed().unwrap() > Duration::from_millis(3000) {
-                                    break;
-                                }
-                                thread::sleep(Duration::from_millis(200));
-                            }
-                        },
-                        Err(_) => {
-                            if time.elapsed().unwrap() > Duration::from_millis(3000) {
-                                break;
-                            }
-                            thread::sleep(Duration::from_millis(200));
-                        }
-                    }
-                }
-                connected
-            }
+    // Doesn't touch the socket: the reactor pings every connection and drops
+    // the ones which stay silent, so a registered connection is a live one
+    pub fn check_connection_health(&self, connection_id: i32, lobby_id: i32) -> bool {
+        let key = ConnectionKey::table(connection_id, lobby_id);
+        let connections = self.connections.lock().unwrap();
+        match connections.by_key.get(&key) {
+            Some(connection) => !connection.broken && connection.last_seen.elapsed() < IDLE_TIMEOUT,
             None => {
-                println!("connection with id {} is already removed", &connection_id);
+                println!("connection of {} is already removed", key);
                 false
             }
-        };
+        }
+    }
+
+    // Sends the notice to every connection and closes each one once its outbox is written out.
+    // Waits until all connections are closed or the timeout passes.
+    pub fn close_all(&self, notice: ResponseMessage, timeout: Duration) {
+        let message_type = notice.payload_type();
+        let bytes = notice.encode_message();
 
-        if !connected {
-            drop(pool);
-            self.pool.write().unwrap().remove(&connection_id);
+        let mut guard = self.connections.lock().unwrap();
+        let connections = &mut *guard;
+        for (key, connection) in connections.by_key.iter_mut() {
+            let message = Outgoing {
+                message_type,
+                bytes: bytes.clone(),
+                send_at: Instant::now(),
+            };
+            if let Enqueued::Overflow = connection.enqueue(message) {
+                connection.broken = true;
+            }
+            connection.closing = true;
+            connections.pending_writes.insert(*key);
         }
+        drop(guard);
+        self.wake();
 
-        connected
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.connections.lock().unwrap().by_key.is_empty() {
+                return;
+            }
+            thread::sleep(CLOSE_POLL_INTERVAL);
+        }
+        println!("some connections were not closed before shutdown");
+    }
+
+    pub fn add_send_listener(&self, listener: Box<dyn Fn(SendEvent) + Send + Sync>) {
+        self.send_listeners.lock().unwrap().push(listener);
+    }
+
+    fn keep_if_durable(&self, user_id: i32, message: Outgoing) {
+        if is_durable(message.message_type) {
+            self.offline_queue.push(user_id, message.bytes);
+        }
+    }
+
+    pub fn add_chat_listener(&self, listener: Box<dyn Fn(ChatEvent) + Send + Sync>) {
+        self.chat_listeners.lock().unwrap().push(listener);
     }
 
-    pub fn spawn_health_checker(
+    pub fn add_connection_closed_listener(
         &self,
-    ) -> Arc<Mutex<Vec<Box<dyn Fn(ConnectionClosedEvent) + Send + Sync>>>> {
-        let pool_clone = Arc::clone(&self.pool);
+        listener: Box<dyn Fn(ConnectionClosedEvent) + Send + Sync>,
+    ) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+
+    fn wake(&self) {
+        if let Err(e) = self.waker.wake() {
+            println!("failed to wake socket reactor: {}", e);
+        }
+    }
+}
+
+struct Reactor {
+    poll: Poll,
+    connections: Arc<Mutex<Connections>>,
+    listeners: ConnectionClosedListeners,
+    send_listeners: SendEventListeners,
+    chat_listeners: ChatListeners,
+    offline_queue: Arc<MessageQueue>,
+}
 
-        let jobs: Arc<Mutex<Vec<Box<dyn Fn(ConnectionClosedEvent) + Send + Sync>>>> =
-            Arc::new(Mutex::new(Vec::new()));
+impl Reactor {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        let mut next_ping = Instant::now() + PING_INTERVAL;
 
-        let jobs_clone = Arc::clone(&jobs);
+        loop {
+            let guard = self.connections.lock().unwrap();
+            let delayed = guard.delayed_writes.iter().filter_map(|key| {
+                let connection = guard.by_key.get(key)?;
+                connection.outbox.front().map(|m| m.send_at)
+            });
+            let next_deadline = guard
+                .waiters
+                .values()
+                .map(|w| w.deadline)
+                .chain(delayed)
+                .min()
+                .map_or(next_ping, |deadline| deadline.min(next_ping));
+            drop(guard);
 
+            let timeout = next_deadline.saturating_duration_since(Instant::now());
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                eprintln!("socket reactor stopped: {}", e);
+                return;
+            }
 
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_millis(5000));
+            // callbacks and listeners run after the lock is released, they may call back into the pool
+            let mut completed: Vec<(ReadCallback, Result<Vec<u8>, ReadMessageError>)> = Vec::new();
+            let mut closed: Vec<ConnectionKey> = Vec::new();
+            let mut chats: Vec<ChatEvent> = Vec::new();
 
-            let closed_connections: Vec<i32> = {
-                let pool = pool_clone.read().unwrap();
-                pool.iter()
-                    .filter_map(|(user_id, socket)| {
-                        let mut socket_guard = socket.lock().unwrap();
-                        // TODO: rethink
-                        if !SocketPool::ping(&mut socket_guard) {
-                            Some(*user_id)
-                        } else {
-                            None
-                        }
+            let mut guard = self.connections.lock().unwrap();
+            let connections = &mut *guard;
+            let now = Instant::now();
+
+            for event in events.iter() {
+                let key = match connections.tokens.get(&event.token()) {
+                    Some(key) => *key,
+                    // waker, or a socket replaced by reconnect
+                    None => continue,
+                };
+                let connection = match connections.by_key.get_mut(&key) {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+
+                let mut alive = true;
+                if event.is_readable() || event.is_read_closed() {
+                    alive = read_frames(
+                        key,
+                        connection,
+                        &mut connections.waiters,
+                        &mut completed,
+                        &mut chats,
+                    );
+                }
+                // also sends pongs queued by tungstenite while reading
+                if alive {
+                    alive = write_queued(
+                        key,
+                        connection,
+                        &mut connections.send_events,
+                        &mut connections.delayed_writes,
+                    );
+                }
+                if !alive {
+                    closed.push(key);
+                }
+            }
+
+            let mut writes = mem::take(&mut connections.pending_writes);
+            writes.extend(mem::take(&mut connections.delayed_writes));
+            for key in writes {
+                if let Some(connection) = connections.by_key.get_mut(&key) {
+                    if !write_queued(
+                        key,
+                        connection,
+                        &mut connections.send_events,
+                        &mut connections.delayed_writes,
+                    ) {
+                        closed.push(key);
+                    }
+                }
+            }
+
+            if now >= next_ping {
+                for (key, connection) in connections.by_key.iter_mut() {
+                    if connection.last_seen.elapsed() >= IDLE_TIMEOUT
+                        || !ping(
+                            *key,
+                            connection,
+                            &mut connections.send_events,
+                            &mut connections.delayed_writes,
+                        )
+                    {
+                        closed.push(*key);
+                    }
+                }
+                next_ping = now + PING_INTERVAL;
+            }
+
+            closed.extend(
+                connections
+                    .by_key
+                    .iter()
+                    .filter(|(_, connection)| {
+                        connection.broken || (connection.closing && connection.outbox.is_empty())
                     })
-                    .collect::<Vec<_>>()
-            };
+                    .map(|(key, _)| *key),
+            );
+            closed.sort_unstable();
+            closed.dedup();
 
-            for connection_id in closed_connections {
-                pool_clone.write().unwrap().remove(&connection_id);
+            for key in closed.iter() {
+                if let Some(mut connection) = connections.by_key.remove(key) {
+                    connections.tokens.remove(&connection.token);
+                    deregister(self.poll.registry(), &connection.socket);
+                    // best effort, the peer may be gone already
+                    let _ = connection.socket.close(None);
+                    let _ = connection.socket.flush();
 
-                let jobs = jobs_clone.lock().unwrap();
+                    for message in connection.outbox {
+                        if is_durable(message.message_type) {
+                            self.offline_queue.push(key.user_id, message.bytes);
+                        }
+                    }
+                }
+                if let Some(waiter) = connections.waiters.remove(key) {
+                    completed.push((waiter.callback, Err(ReadMessageError::Disconnected)));
+                }
+                println!("connection of {} is closed", key);
+            }
 
-                for job in jobs.iter() {
-                    job(ConnectionClosedEvent {
-                        user_id: connection_id,
-                    });
+            let expired: Vec<ConnectionKey> = connections
+                .waiters
+                .iter()
+                .filter(|(_, waiter)| waiter.deadline <= now)
+                .map(|(key, _)| *key)
+                .collect();
+            for key in expired {
+                if let Some(waiter) = connections.waiters.remove(&key) {
+                    completed.push((waiter.callback, Err(ReadMessageError::Iddle)));
                 }
             }
-        });
-        Arc::clone(&jobs)
+            let send_events = mem::take(&mut connections.send_events);
+            drop(guard);
+
+            if !send_events.is_empty() {
+                let send_listeners = self.send_listeners.lock().unwrap();
+                for event in send_events {
+                    for listener in send_listeners.iter() {
+                        listener(event.clone());
+                    }
+                }
+            }
+
+            for (callback, result) in completed {
+                callback(result);
+            }
+
+            if !chats.is_empty() {
+                let chat_listeners = self.chat_listeners.lock().unwrap();
+                for chat in chats {
+                    for listener in chat_listeners.iter() {
+                        listener(chat.clone());
+                    }
+                }
+            }
+
+            if !closed.is_empty() {
+                let listeners = self.listeners.lock().unwrap();
+                // nobody holds a seat behind a spectator connection
+                for key in closed.into_iter().filter(|key| !key.spectator) {
+                    for listener in listeners.iter() {
+                        listener(ConnectionClosedEvent {
+                            user_id: key.user_id,
+                            lobby_id: key.lobby_id,
+                        });
+                    }
+                }
+            }
+        }
     }
+}
 
-    fn ping(socket_guard: &mut MutexGuard<WebSocket<TcpStream>>) -> bool {
-        if let Err(_) = socket_guard.write(TMessage::Ping(Vec::new())) {
-            return false;
+// Reads until the socket would block. Returns false when the connection is closed.
+fn read_frames(
+    key: ConnectionKey,
+    connection: &mut Connection,
+    waiters: &mut HashMap<ConnectionKey, Waiter>,
+    completed: &mut Vec<(ReadCallback, Result<Vec<u8>, ReadMessageError>)>,
+    chats: &mut Vec<ChatEvent>,
+) -> bool {
+    loop {
+        match connection.socket.read() {
+            Ok(message) => {
+                connection.last_seen = Instant::now();
+                match message {
+                    // the lobby feed has no table requests
+                    TMessage::Binary(bytes) if key.lobby_id.is_none() => {
+                        receive(key, connection, waiters, completed, bytes)
+                    }
+                    TMessage::Binary(bytes) => match table_frame(bytes) {
+                        TableFrame::Chat(text) => chats.push(ChatEvent { key, text }),
+                        TableFrame::Action(_) if key.spectator => {
+                            println!("{} sent an action, ignored", key)
+                        }
+                        TableFrame::Action(bytes) => {
+                            receive(key, connection, waiters, completed, bytes)
+                        }
+                    },
+                    TMessage::Close(_) => return false,
+                    // pings are answered by tungstenite, pongs only refresh last_seen
+                    TMessage::Ping(_) | TMessage::Pong(_) | TMessage::Frame(_) => {}
+                    TMessage::Text(_) => println!("{} sent text message, ignored", key),
+                }
+            }
+            Err(TError::Io(e)) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(TError::ConnectionClosed) | Err(TError::AlreadyClosed) => return false,
+            Err(e) => {
+                println!("failed to read from {}: {}", key, e);
+                return false;
+            }
         }
-        if let Err(_) = socket_guard.flush() {
-            return false;
+    }
+}
+
+// Completes the pending read of the connection or keeps the message for the next one
+fn receive(
+    key: ConnectionKey,
+    connection: &mut Connection,
+    waiters: &mut HashMap<ConnectionKey, Waiter>,
+    completed: &mut Vec<(ReadCallback, Result<Vec<u8>, ReadMessageError>)>,
+    bytes: Vec<u8>,
+) {
+    match waiters.remove(&key) {
+        Some(waiter) => completed.push((waiter.callback, Ok(bytes))),
+        None => {
+            if connection.inbox.len() == MAX_INBOX_SIZE {
+                println!("inbox of {} is full, dropping oldest message", key);
+                connection.inbox.pop_front();
+            }
+            connection.inbox.push_back(bytes);
         }
-        true
     }
-    fn remove_connection(&self, client_id: &i32) -> Arc<Mutex<WebSocket<TcpStream>>> {
-        let mut client_channels = self.pool.write().unwrap();
-        client_channels.remove(client_id).unwrap()
+}
+
+fn table_frame(bytes: Vec<u8>) -> TableFrame {
+    match TableRequest::decode(bytes.as_slice()) {
+        Ok(TableRequest {
+            request: Some(Request::Action(action)),
+        }) => TableFrame::Action(action.encode_message()),
+        Ok(TableRequest {
+            request: Some(Request::Chat(chat)),
+        }) => TableFrame::Chat(chat.text),
+        // bare PlayerActionRequest, a malformed one is reported to the reader
+        _ => TableFrame::Action(bytes),
     }
+}
 
-    fn close_connection(
-        &self,
-        connection: Arc<Mutex<WebSocket<TcpStream>>>,
-        frame: Option<CloseFrame<'static>>,
-    ) {
-        let mut guard = connection.lock().unwrap();
-
-        match guard.close(frame) {
-            Ok(_) => match guard.flush() {
-                Ok(_) => {},
-                Err(e) => match e {
-                    tungstenite::Error::ConnectionClosed => {
-                        println!("Connection closed abruptly by client!")
-                    },
-                    _ => {
-                        println!("Unprocessed error while flushing connection close: {}", e);
-                    } ,
-                },
-            },
-            Err(e) => match e {
-                tungstenite::Error::ConnectionClosed => {
-                    println!("Connection closed abruptly by client!")
-                },
-                _ => {
-                    println!("Unprocessed error while closing connection: {}", e);
-                }
-            },
+// Writer of one connection: moves queued messages into the socket while the client keeps up.
+// Stops at WouldBlock, the next writable event continues, or at a message which is not due yet.
+// Returns false when the connection is broken.
+fn write_queued(
+    key: ConnectionKey,
+    connection: &mut Connection,
+    send_events: &mut Vec<SendEvent>,
+    delayed_writes: &mut HashSet<ConnectionKey>,
+) -> bool {
+    loop {
+        match connection.socket.flush() {
+            Ok(_) => {}
+            Err(TError::Io(e)) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(e) => {
+                send_events.push(SendEvent::Failed {
+                    key,
+                    reason: SendError::Io(e.to_string()),
+                });
+                return false;
+            }
+        }
+
+        match connection.outbox.front() {
+            None => return true,
+            Some(message) if message.send_at > Instant::now() => {
+                delayed_writes.insert(key);
+                return true;
+            }
+            Some(_) => {}
+        }
+        let message = match connection.outbox.pop_front() {
+            Some(message) => message,
+            None => return true,
+        };
+        match connection
+            .socket
+            .write(TMessage::Binary(message.bytes.clone()))
+        {
+            Ok(_) => {}
+            // the frame is buffered, it goes out with the next flush
+            Err(TError::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => {
+                // kept for the offline queue
+                connection.outbox.push_front(message);
+                send_events.push(SendEvent::Failed {
+                    key,
+                    reason: SendError::Io(e.to_string()),
+                });
+                return false;
+            }
         }
     }
+}
+
+fn ping(
+    key: ConnectionKey,
+    connection: &mut Connection,
+    send_events: &mut Vec<SendEvent>,
+    delayed_writes: &mut HashSet<ConnectionKey>,
+) -> bool {
+    match connection.socket.write(TMessage::Ping(Vec::new())) {
+        Ok(_) => write_queued(key, connection, send_events, delayed_writes),
+        Err(TError::Io(e)) if e.kind() == ErrorKind::WouldBlock => true,
+        Err(_) => false,
+    }
+}
 
-    fn get_channel(&self, client_id: &i32) -> Option<Arc<Mutex<WebSocket<TcpStream>>>> {
-        let client_channels = self.pool.read().unwrap();
+// full states and deltas, every one of them is superseded by the next full state
+fn is_table_state(message_type: ResponseMessageType) -> bool {
+    matches!(
+        message_type,
+        ResponseMessageType::ClientState | ResponseMessageType::StateDelta
+    )
+}
 
-        client_channels.get(client_id).cloned()
+fn deregister(registry: &Registry, socket: &WebSocket<TcpStream>) {
+    let fd = socket.get_ref().as_raw_fd();
+    if let Err(e) = registry.deregister(&mut SourceFd(&fd)) {
+        println!("failed to deregister socket: {}", e);
     }
+}
 
-    fn read_non_blocking(&self, socket: Arc<Mutex<WebSocket<TcpStream>>>) -> Result<TMessage, TError> {
-        let start_time = SystemTime::now();
-        loop {
-            let message: Result<TMessage, TError> = socket.lock().unwrap().read();
-            match message {
-                Ok(msg) => {
-                    if !msg.is_pong() {
-                        return Ok(msg);
-                    }
-                }
-                Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => {
-                    if start_time.elapsed().unwrap() >= Duration::from_secs(300) {
-                        return Err(tungstenite::Error::Io(std::io::Error::new(
-                            ErrorKind::TimedOut,
-                            "Read operation timed out",
-                        )));
-                    }
-                    // think about optimizing sleeping time to balance between cpu usage and responsiveness
-                    std::thread::sleep(Duration::from_millis(200));
-                }
-                Err(err) => {
-                    return Err(err);
-                }
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::{
+        game_state::{Action, ActionType},
+        requests::{ChatMessageRequest, PlayerActionRequest},
+    };
+
+    fn action() -> PlayerActionRequest {
+        PlayerActionRequest {
+            player_id: 1,
+            lobby_id: 2,
+            action: Some(Action {
+                player_id: 1,
+                action_type: ActionType::Raise.into(),
+                bet: 40,
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn table_frames_are_told_apart() {
+        let chat = TableRequest {
+            request: Some(Request::Chat(ChatMessageRequest {
+                text: String::from("gl"),
+                ..Default::default()
+            })),
+        };
+        assert!(
+            matches!(table_frame(chat.encode_message()), TableFrame::Chat(text) if text == "gl")
+        );
+
+        let wrapped = TableRequest {
+            request: Some(Request::Action(action())),
+        };
+        match table_frame(wrapped.encode_message()) {
+            TableFrame::Action(bytes) => {
+                assert_eq!(
+                    PlayerActionRequest::decode(bytes.as_slice()).unwrap(),
+                    action()
+                )
+            }
+            TableFrame::Chat(_) => panic!("action is read as chat"),
+        }
+    }
+
+    #[test]
+    fn bare_action_of_older_clients_is_still_an_action() {
+        let bytes = action().encode_message();
+        match table_frame(bytes.clone()) {
+            TableFrame::Action(read) => assert_eq!(read, bytes),
+            TableFrame::Chat(_) => panic!("action is read as chat"),
         }
     }
 }
@@ -0,0 +1,121 @@
+use std::{
+    collections::VecDeque,
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tungstenite::WebSocket;
+
+use crate::{
+    protos::{
+        client_state::ClientState,
+        responses::{ResponseMessage, ResponseMessageType},
+    },
+    responses::EncodableMessage,
+    socket_pool::{ConnectionKey, SocketPool},
+};
+
+#[derive(Default)]
+struct BroadcastStates {
+    // send at, public state which is still on its way to the spectators
+    pending: VecDeque<(Instant, ClientState)>,
+    // last state spectators have actually received, sent to everyone who starts watching
+    delivered: Option<ClientState>,
+}
+
+impl BroadcastStates {
+    fn settle(&mut self, now: Instant) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|(send_at, _)| *send_at <= now)
+        {
+            self.delivered = self.pending.pop_front().map(|(_, state)| state);
+        }
+    }
+}
+
+// Users watching a table without a seat. Their sockets live in SocketPool next to the
+// players' ones, but they get only the public view of the table and, if configured,
+// with a delay.
+pub struct SpectatorPool {
+    lobby_id: i32,
+    delay: Duration,
+    socket_pool: Arc<SocketPool>,
+    states: Mutex<BroadcastStates>,
+}
+
+impl SpectatorPool {
+    pub fn new(lobby_id: i32, delay: Duration, socket_pool: Arc<SocketPool>) -> Self {
+        SpectatorPool {
+            lobby_id,
+            delay,
+            socket_pool,
+            states: Mutex::new(BroadcastStates::default()),
+        }
+    }
+
+    // first_messages go out before the state, e.g. chat history
+    pub fn add(
+        &self,
+        user_id: i32,
+        socket: WebSocket<TcpStream>,
+        first_messages: Vec<ResponseMessage>,
+    ) {
+        let now = Instant::now();
+        let mut messages: Vec<(Instant, ResponseMessage)> =
+            first_messages.into_iter().map(|m| (now, m)).collect();
+
+        // held until the socket is registered, so no broadcast slips in between
+        let mut states = self.states.lock().unwrap();
+        states.settle(now);
+        messages.extend(
+            states
+                .delivered
+                .iter()
+                .map(|s| (now, state_message(s.clone()))),
+        );
+        messages.extend(
+            states
+                .pending
+                .iter()
+                .map(|(send_at, s)| (*send_at, state_message(s.clone()))),
+        );
+
+        println!("user {} is watching lobby {}", user_id, self.lobby_id);
+        self.socket_pool
+            .add_spectator(user_id, self.lobby_id, socket, messages);
+    }
+
+    pub fn contains(&self, user_id: i32) -> bool {
+        self.socket_pool
+            .has_connection(ConnectionKey::spectator(user_id, self.lobby_id))
+    }
+
+    // state must already be the public view, see dealer::public_view
+    pub fn broadcast(&self, state: ClientState) {
+        let now = Instant::now();
+        let send_at = now + self.delay;
+
+        let mut states = self.states.lock().unwrap();
+        states.settle(now);
+        states.pending.push_back((send_at, state.clone()));
+        drop(states);
+
+        self.socket_pool
+            .update_spectators(self.lobby_id, state_message(state), send_at);
+    }
+
+    pub fn broadcast_message(&self, message: ResponseMessage) {
+        self.socket_pool
+            .update_spectators(self.lobby_id, message, Instant::now() + self.delay);
+    }
+}
+
+fn state_message(state: ClientState) -> ResponseMessage {
+    ResponseMessage {
+        payload: state.encode_message(),
+        payload_type: ResponseMessageType::ClientState.into(),
+    }
+}
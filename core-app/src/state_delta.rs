@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+
+use prost::Message;
+
+use crate::{
+    protos::{
+        client_state::ClientState,
+        responses::ResponseMessageType,
+        state_delta::{
+            delta_event::Event, CardsDealt, DeltaEvent, PositionsChanged, PotUpdated,
+            RunoutUpdated, ShowdownUpdated, StateDelta, TurnChanged,
+        },
+    },
+    responses::{create_message_response, TMessageResponse},
+};
+
+pub const STATE_DELTA_VERSION: u32 = 1;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// Remembers the last state every player has received, so the next one can be sent as a delta.
+// Sequence is shared by the whole table and grows by one for every state change.
+pub struct StateSync {
+    lobby_id: i32,
+    sequence: u64,
+    last_sent: HashMap<i32, ClientState>,
+}
+
+impl StateSync {
+    pub fn new(lobby_id: i32) -> Self {
+        StateSync {
+            lobby_id,
+            sequence: 0,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // next state for this player goes out as a full ClientState (join, reconnect)
+    pub fn forget(&mut self, player_id: i32) {
+        self.last_sent.remove(&player_id);
+    }
+
+    pub fn forget_all(&mut self) {
+        self.last_sent.clear();
+    }
+
+    pub fn create_responses(&mut self, states: Vec<ClientState>) -> Vec<TMessageResponse> {
+        self.sequence += 1;
+
+        let mut responses = Vec::new();
+
+        for mut state in states {
+            state.sequence = self.sequence;
+            state.state_hash = state_hash(&state);
+            let receiver_id = state.player_id;
+
+            match self.last_sent.get(&receiver_id) {
+                Some(last) => {
+                    let events = diff_client_states(last, &state);
+                    // nothing changed for this player, the next delta will continue from the last one
+                    if events.is_empty() {
+                        continue;
+                    }
+                    let delta = StateDelta {
+                        version: STATE_DELTA_VERSION,
+                        lobby_id: self.lobby_id,
+                        sequence: self.sequence,
+                        base_sequence: last.sequence,
+                        events,
+                        state_hash: state.state_hash,
+                    };
+                    responses.push(create_message_response(
+                        delta,
+                        ResponseMessageType::StateDelta,
+                        receiver_id,
+                    ));
+                }
+                None => responses.push(create_message_response(
+                    state.clone(),
+                    ResponseMessageType::ClientState,
+                    receiver_id,
+                )),
+            }
+
+            self.last_sent.insert(receiver_id, state);
+        }
+
+        responses
+    }
+
+    // Answer to ResyncRequest: the current state again, without moving the sequence
+    pub fn create_full_state_response(&mut self, mut state: ClientState) -> TMessageResponse {
+        state.sequence = self.sequence;
+        state.state_hash = state_hash(&state);
+        let receiver_id = state.player_id;
+
+        self.last_sent.insert(receiver_id, state.clone());
+
+        create_message_response(state, ResponseMessageType::ClientState, receiver_id)
+    }
+}
+
+// FNV-1a over the protobuf encoding: cheap, and trivial to reproduce on the client
+pub fn state_hash(state: &ClientState) -> u64 {
+    let mut state = state.clone();
+    state.sequence = 0;
+    state.state_hash = 0;
+
+    state
+        .encode_to_vec()
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+pub fn diff_client_states(old: &ClientState, new: &ClientState) -> Vec<DeltaEvent> {
+    let mut events = Vec::new();
+
+    if old.game_status != new.game_status {
+        events.push(Event::StatusChanged(new.game_status));
+    }
+
+    for player in &new.players {
+        if !old.players.contains(player) {
+            events.push(Event::PlayerUpdated(player.clone()));
+        }
+    }
+    for player in &old.players {
+        if !new.players.iter().any(|p| p.user_id == player.user_id) {
+            events.push(Event::PlayerLeft(player.user_id));
+        }
+    }
+
+    if old.pot != new.pot {
+        events.push(Event::PotUpdated(PotUpdated { total: new.pot }));
+    }
+
+    if old.cards != new.cards {
+        events.push(Event::CardsDealt(CardsDealt {
+            cards: new.cards.clone(),
+        }));
+    }
+
+    if old.street != new.street {
+        match &new.street {
+            Some(street) => events.push(Event::StreetUpdated(street.clone())),
+            None => events.push(Event::StreetCleared(true)),
+        }
+    }
+
+    if new.action_history.starts_with(&old.action_history) {
+        new.action_history[old.action_history.len()..]
+            .iter()
+            .for_each(|a| events.push(Event::PlayerActed(a.clone())));
+    } else {
+        events.push(Event::ActionHistoryCleared(true));
+        new.action_history
+            .iter()
+            .for_each(|a| events.push(Event::PlayerActed(a.clone())));
+    }
+
+    if (
+        &old.curr_button_id,
+        &old.curr_small_blind_id,
+        &old.curr_big_blind_id,
+    ) != (
+        &new.curr_button_id,
+        &new.curr_small_blind_id,
+        &new.curr_big_blind_id,
+    ) {
+        events.push(Event::PositionsChanged(PositionsChanged {
+            button_id: new.curr_button_id.clone(),
+            small_blind_id: new.curr_small_blind_id.clone(),
+            big_blind_id: new.curr_big_blind_id.clone(),
+        }));
+    }
+
+    if (
+        &old.curr_player_id,
+        &old.amount_to_call,
+        &old.min_amount_to_raise,
+        &old.can_raise,
+    ) != (
+        &new.curr_player_id,
+        &new.amount_to_call,
+        &new.min_amount_to_raise,
+        &new.can_raise,
+    ) {
+        events.push(Event::TurnChanged(TurnChanged {
+            curr_player_id: new.curr_player_id.clone(),
+            amount_to_call: new.amount_to_call.clone(),
+            min_amount_to_raise: new.min_amount_to_raise.clone(),
+            can_raise: new.can_raise.clone(),
+        }));
+    }
+
+    if old.showdown_outcome != new.showdown_outcome {
+        events.push(Event::ShowdownUpdated(ShowdownUpdated {
+            showdown_outcome: new.showdown_outcome.clone(),
+        }));
+    }
+
+    if old.revealed_cards != new.revealed_cards || old.equities != new.equities {
+        events.push(Event::RunoutUpdated(RunoutUpdated {
+            revealed_cards: new.revealed_cards.clone(),
+            equities: new.equities.clone(),
+        }));
+    }
+
+    events
+        .into_iter()
+        .map(|event| DeltaEvent { event: Some(event) })
+        .collect()
+}
+
+// Client side of diff_client_states: rebuilds the state the delta was made from.
+// The result hashes to delta.state_hash when base is the state with delta.base_sequence.
+pub fn apply_delta(base: &ClientState, delta: &StateDelta) -> ClientState {
+    let mut state = base.clone();
+
+    for event in delta.events.iter().filter_map(|e| e.event.as_ref()) {
+        match event {
+            Event::PlayerActed(action) => state.action_history.push(action.clone()),
+            Event::ActionHistoryCleared(_) => state.action_history.clear(),
+            Event::CardsDealt(dealt) => state.cards = dealt.cards.clone(),
+            Event::StreetUpdated(street) => state.street = Some(street.clone()),
+            Event::StreetCleared(_) => state.street = None,
+            Event::PotUpdated(pot) => state.pot = pot.total,
+            Event::PlayerUpdated(player) => {
+                match state
+                    .players
+                    .iter_mut()
+                    .find(|p| p.user_id == player.user_id)
+                {
+                    Some(existing) => *existing = player.clone(),
+                    None => state.players.push(player.clone()),
+                }
+            }
+            Event::PlayerLeft(user_id) => state.players.retain(|p| p.user_id != *user_id),
+            Event::PositionsChanged(positions) => {
+                state.curr_button_id = positions.button_id.clone();
+                state.curr_small_blind_id = positions.small_blind_id.clone();
+                state.curr_big_blind_id = positions.big_blind_id.clone();
+            }
+            Event::TurnChanged(turn) => {
+                state.curr_player_id = turn.curr_player_id.clone();
+                state.amount_to_call = turn.amount_to_call.clone();
+                state.min_amount_to_raise = turn.min_amount_to_raise.clone();
+                state.can_raise = turn.can_raise.clone();
+            }
+            Event::StatusChanged(status) => state.game_status = *status,
+            Event::ShowdownUpdated(showdown) => {
+                state.showdown_outcome = showdown.showdown_outcome.clone()
+            }
+            Event::RunoutUpdated(runout) => {
+                state.revealed_cards = runout.revealed_cards.clone();
+                state.equities = runout.equities.clone();
+            }
+        }
+    }
+
+    state.sequence = delta.sequence;
+    state.state_hash = delta.state_hash;
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{self, Command, EngineState},
+        protos::{
+            game_state::{Action, ActionType, GameStatus, Street},
+            player::Player,
+            requests::PlayerActionRequest,
+        },
+    };
+
+    const LOBBY_ID: i32 = 1;
+
+    fn stamp(mut state: ClientState, sequence: u64) -> ClientState {
+        state.sequence = sequence;
+        state.state_hash = state_hash(&state);
+        state
+    }
+
+    fn assert_round_trip(old: &ClientState, new: &ClientState) {
+        let old = stamp(old.clone(), 1);
+        let new = stamp(new.clone(), 2);
+        let delta = StateDelta {
+            version: STATE_DELTA_VERSION,
+            lobby_id: LOBBY_ID,
+            sequence: new.sequence,
+            base_sequence: old.sequence,
+            events: diff_client_states(&old, &new),
+            state_hash: new.state_hash,
+        };
+
+        let applied = apply_delta(&old, &delta);
+
+        assert_eq!(applied, new);
+        assert_eq!(state_hash(&applied), delta.state_hash);
+    }
+
+    fn fold(player_id: i32) -> Command {
+        Command::Action(PlayerActionRequest {
+            player_id,
+            lobby_id: LOBBY_ID,
+            action: Some(Action {
+                action_type: ActionType::Fold.into(),
+                bet: 0,
+                player_id,
+                street_status: None,
+            }),
+        })
+    }
+
+    #[test]
+    fn street_can_be_cleared() {
+        let old = ClientState {
+            street: Some(Street::default()),
+            game_status: GameStatus::Active.into(),
+            ..Default::default()
+        };
+        let new = ClientState {
+            street: None,
+            game_status: GameStatus::WaitingForPlayers.into(),
+            ..Default::default()
+        };
+
+        let events = diff_client_states(&old, &new);
+
+        assert!(events
+            .iter()
+            .any(|e| e.event == Some(Event::StreetCleared(true))));
+        assert_round_trip(&old, &new);
+    }
+
+    #[test]
+    fn pot_follows_the_client_state_field() {
+        let old = ClientState {
+            pot: 30,
+            ..Default::default()
+        };
+        let new = ClientState {
+            pot: 130,
+            ..Default::default()
+        };
+
+        let events = diff_client_states(&old, &new);
+
+        assert_eq!(
+            events,
+            vec![DeltaEvent {
+                event: Some(Event::PotUpdated(PotUpdated { total: 130 }))
+            }]
+        );
+        assert_round_trip(&old, &new);
+    }
+
+    #[test]
+    fn deltas_rebuild_every_state_of_a_played_hand() {
+        let mut state = EngineState::with_seed(LOBBY_ID, 20, 7);
+        let mut history: Vec<Vec<ClientState>> = Vec::new();
+        let mut run = |state: EngineState, command: Command| -> (EngineState, Option<i32>) {
+            let (state, events) = engine::apply(state, command);
+            history.push(state.client_states());
+            let awaited = events.iter().rev().find_map(|e| match e {
+                engine::Event::AwaitingAction { player_id, .. } => Some(*player_id),
+                _ => None,
+            });
+            (state, awaited)
+        };
+
+        for user_id in 1..=3 {
+            let player = Player {
+                user_id,
+                bank: 1000,
+                ..Default::default()
+            };
+            (state, _) = run(state, Command::Join(player));
+        }
+        // whoever is on turn folds until the hand is over
+        let (mut state, mut awaited) = run(state, Command::StartGame);
+        while let Some(player_id) = awaited {
+            (state, awaited) = run(state, fold(player_id));
+        }
+        (state, _) = run(state, Command::NextHand);
+        // two players leave, the table goes back to waiting and the street is cleared
+        (state, _) = run(state, Command::Disconnect { player_id: 2 });
+        (state, _) = run(state, Command::Disconnect { player_id: 3 });
+        run(state, Command::NextHand);
+
+        for pair in history.windows(2) {
+            for new in &pair[1] {
+                if let Some(old) = pair[0].iter().find(|s| s.player_id == new.player_id) {
+                    assert_round_trip(old, new);
+                }
+            }
+        }
+        let last = &history[history.len() - 1][0];
+        assert_eq!(last.game_status(), GameStatus::WaitingForPlayers);
+        assert_eq!(last.street, None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::protos::responses::{ChatHistoryMessage, ChatMessage};
+
+pub const MAX_MESSAGE_LENGTH: usize = 200;
+pub const HISTORY_SIZE: usize = 50;
+// at most RATE_LIMIT_MESSAGES per user during RATE_LIMIT_WINDOW
+const RATE_LIMIT_MESSAGES: usize = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChatError {
+    NotAtTable,
+    EmptyMessage,
+    MessageTooLong(usize),
+    RateLimited,
+    Muted,
+    Rejected(String),
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::NotAtTable => write!(f, "only players and spectators of the table can chat"),
+            ChatError::EmptyMessage => write!(f, "message is empty"),
+            ChatError::MessageTooLong(length) => write!(
+                f,
+                "message has {} characters, at most {} are allowed",
+                length, MAX_MESSAGE_LENGTH
+            ),
+            ChatError::RateLimited => write!(f, "too many messages, slow down"),
+            ChatError::Muted => write!(f, "user is muted"),
+            ChatError::Rejected(reason) => write!(f, "message rejected: {}", reason),
+        }
+    }
+}
+
+impl Error for ChatError {}
+
+// Hook for profanity filters and mutes. Returns the text to publish, which may differ
+// from the original (e.g. masked words), or the reason the message is not published.
+pub trait ChatModerator: Send + Sync {
+    fn moderate(&self, lobby_id: i32, user_id: i32, text: String) -> Result<String, ChatError>;
+}
+
+pub struct AllowAll;
+
+impl ChatModerator for AllowAll {
+    fn moderate(&self, _lobby_id: i32, _user_id: i32, text: String) -> Result<String, ChatError> {
+        Ok(text)
+    }
+}
+
+struct ChatState {
+    history: VecDeque<ChatMessage>,
+    sent_at: HashMap<i32, VecDeque<Instant>>,
+}
+
+impl ChatState {
+    fn is_rate_limited(&mut self, user_id: i32, now: Instant) -> bool {
+        let sent_at = self.sent_at.entry(user_id).or_default();
+        while sent_at
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RATE_LIMIT_WINDOW)
+        {
+            sent_at.pop_front();
+        }
+        sent_at.len() >= RATE_LIMIT_MESSAGES
+    }
+}
+
+// Chat of one table: validates messages and keeps the last HISTORY_SIZE of them.
+// Delivery is up to the game, it knows who sits at the table.
+pub struct TableChat {
+    lobby_id: i32,
+    moderator: Arc<dyn ChatModerator>,
+    state: Mutex<ChatState>,
+}
+
+impl TableChat {
+    pub fn new(lobby_id: i32, moderator: Arc<dyn ChatModerator>) -> Self {
+        TableChat {
+            lobby_id,
+            moderator,
+            state: Mutex::new(ChatState {
+                history: VecDeque::new(),
+                sent_at: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn post(
+        &self,
+        user_id: i32,
+        text: &str,
+        is_spectator: bool,
+    ) -> Result<ChatMessage, ChatError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(ChatError::EmptyMessage);
+        }
+        let length = text.chars().count();
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(ChatError::MessageTooLong(length));
+        }
+
+        // checked before moderation too, so flooding doesn't keep the moderator busy
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .is_rate_limited(user_id, Instant::now())
+        {
+            return Err(ChatError::RateLimited);
+        }
+
+        // moderator may be slow, e.g. a remote filter, the rest of the table keeps chatting meanwhile
+        let text = self
+            .moderator
+            .moderate(self.lobby_id, user_id, text.to_string())?;
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        // other messages of the user may have got through while this one was moderated
+        if state.is_rate_limited(user_id, now) {
+            return Err(ChatError::RateLimited);
+        }
+        state.sent_at.entry(user_id).or_default().push_back(now);
+
+        let message = ChatMessage {
+            lobby_id: self.lobby_id,
+            user_id,
+            text,
+            sent_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default(),
+            is_spectator,
+        };
+
+        if state.history.len() == HISTORY_SIZE {
+            state.history.pop_front();
+        }
+        state.history.push_back(message.clone());
+
+        Ok(message)
+    }
+
+    pub fn history(&self) -> ChatHistoryMessage {
+        ChatHistoryMessage {
+            lobby_id: self.lobby_id,
+            messages: self.state.lock().unwrap().history.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::mpsc::{self, Receiver, Sender},
+        thread,
+    };
+
+    const LOBBY_ID: i32 = 1;
+
+    fn chat(moderator: impl ChatModerator + 'static) -> Arc<TableChat> {
+        Arc::new(TableChat::new(LOBBY_ID, Arc::new(moderator)))
+    }
+
+    // mutes one user and masks one word for everyone else
+    struct Strict {
+        muted: i32,
+    }
+
+    impl ChatModerator for Strict {
+        fn moderate(
+            &self,
+            _lobby_id: i32,
+            user_id: i32,
+            text: String,
+        ) -> Result<String, ChatError> {
+            if user_id == self.muted {
+                return Err(ChatError::Muted);
+            }
+            Ok(text.replace("fold", "****"))
+        }
+    }
+
+    // holds the messages of one user until it is told to go on
+    struct Slow {
+        user_id: i32,
+        entered: Mutex<Sender<()>>,
+        proceed: Mutex<Receiver<()>>,
+    }
+
+    impl ChatModerator for Slow {
+        fn moderate(
+            &self,
+            _lobby_id: i32,
+            user_id: i32,
+            text: String,
+        ) -> Result<String, ChatError> {
+            if user_id == self.user_id {
+                self.entered.lock().unwrap().send(()).unwrap();
+                self.proceed.lock().unwrap().recv().unwrap();
+            }
+            Ok(text)
+        }
+    }
+
+    #[test]
+    fn length_of_the_message_is_checked() {
+        let chat = chat(AllowAll);
+        let longest = "ы".repeat(MAX_MESSAGE_LENGTH);
+
+        assert_eq!(chat.post(1, "   ", false), Err(ChatError::EmptyMessage));
+        assert_eq!(
+            chat.post(1, &format!("{}!", longest), false),
+            Err(ChatError::MessageTooLong(MAX_MESSAGE_LENGTH + 1))
+        );
+        assert_eq!(
+            chat.post(1, &format!(" {} ", longest), false).unwrap().text,
+            longest
+        );
+    }
+
+    #[test]
+    fn sixth_message_inside_the_window_is_rejected() {
+        let chat = chat(AllowAll);
+        for i in 0..RATE_LIMIT_MESSAGES {
+            assert!(chat.post(1, &format!("message {}", i), false).is_ok());
+        }
+
+        assert_eq!(chat.post(1, "one more", false), Err(ChatError::RateLimited));
+        // the limit is per user
+        assert!(chat.post(2, "hi", true).is_ok());
+        assert_eq!(chat.history().messages.len(), RATE_LIMIT_MESSAGES + 1);
+    }
+
+    #[test]
+    fn history_keeps_the_last_messages() {
+        let chat = chat(AllowAll);
+        for user_id in 0..=HISTORY_SIZE as i32 {
+            chat.post(user_id, &format!("from {}", user_id), false)
+                .unwrap();
+        }
+
+        let history = chat.history();
+        assert_eq!(history.lobby_id, LOBBY_ID);
+        assert_eq!(history.messages.len(), HISTORY_SIZE);
+        assert_eq!(history.messages[0].user_id, 1);
+        assert_eq!(
+            history.messages.last().unwrap().user_id,
+            HISTORY_SIZE as i32
+        );
+    }
+
+    #[test]
+    fn moderator_rejects_and_rewrites_messages() {
+        let chat = chat(Strict { muted: 7 });
+
+        assert_eq!(chat.post(7, "hello", false), Err(ChatError::Muted));
+        assert_eq!(chat.post(1, "just fold", false).unwrap().text, "just ****");
+
+        let history = chat.history();
+        assert_eq!(history.messages.len(), 1);
+        assert_eq!(history.messages[0].user_id, 1);
+    }
+
+    #[test]
+    fn slow_moderation_doesnt_block_the_table() {
+        let (entered, moderating) = mpsc::channel();
+        let (proceed, wait) = mpsc::channel();
+        let chat = chat(Slow {
+            user_id: 1,
+            entered: Mutex::new(entered),
+            proceed: Mutex::new(wait),
+        });
+
+        let slow_chat = Arc::clone(&chat);
+        let slow = thread::spawn(move || slow_chat.post(1, "thinking...", false));
+        moderating.recv().unwrap();
+
+        let (posted, result) = mpsc::channel();
+        let other_chat = Arc::clone(&chat);
+        thread::spawn(move || posted.send(other_chat.post(2, "nice hand", false)));
+        let other = result.recv_timeout(Duration::from_secs(5));
+        assert!(
+            matches!(other, Ok(Ok(_))),
+            "chat is blocked by the moderator"
+        );
+
+        proceed.send(()).unwrap();
+        assert!(slow.join().unwrap().is_ok());
+        assert_eq!(chat.history().messages.len(), 2);
+    }
+}
@@ -27,11 +27,11 @@ impl Worker {
             }
         });
 
-        let worker = Worker {
+        
+        Worker {
             id,
             handle: Some(handle),
-        };
-        worker
+        }
     }
 }
 type Job = Box<dyn FnOnce() + Send + 'static>;